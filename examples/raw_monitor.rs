@@ -0,0 +1,41 @@
+//! Sends a hand-built monitor request to a device and prints the raw response packets.
+//!
+//! Run with the Bluetooth ID of a device (see `longshot list`), or a simulator name like `sim[on]`.
+
+use std::time::Duration;
+
+use futures::StreamExt;
+use longshot::ecam::{get_ecam_simulator, Ecam, EcamBT, EcamDriver, EcamOutput};
+use longshot::protocol::{hexdump, packetize, EcamRequestId};
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let device_name = std::env::args()
+        .nth(1)
+        .ok_or("Usage: raw_monitor <device-id>")?;
+    let driver: Box<dyn EcamDriver> = if device_name.starts_with("sim") {
+        Box::new(get_ecam_simulator(&device_name).await?)
+    } else {
+        Box::new(EcamBT::get(device_name).await?)
+    };
+    let ecam = Ecam::new(driver, false).await;
+    ecam.wait_for_connection().await?;
+
+    // A monitor request is the request ID followed by 0x0f
+    let payload = [EcamRequestId::MonitorV2.into(), 0x0f];
+    println!("Sending {}", hexdump(&packetize(&payload)));
+
+    let mut tap = ecam.packet_tap().await?;
+    ecam.write_raw(&payload).await?;
+    let responses = async {
+        while let Some(output) = tap.next().await {
+            match output {
+                EcamOutput::Packet(packet) => println!("Received {:?}", packet.bytes),
+                EcamOutput::Done => break,
+                _ => {}
+            }
+        }
+    };
+    let _ = tokio::time::timeout(Duration::from_secs(2), responses).await;
+    Ok(())
+}
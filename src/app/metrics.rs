@@ -0,0 +1,162 @@
+//! Prometheus metrics for the HTTP server, written in the text exposition format. These are only served when the
+//! server is given a metrics address, but are cheap enough to always collect.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use longshot::ecam::EcamStatus;
+
+/// The upper bounds of the brew duration histogram buckets, in seconds.
+const BREW_DURATION_BUCKETS: [u64; 7] = [30, 60, 90, 120, 180, 300, 600];
+
+/// Declares the states reported by the `longshot_machine_state` gauge along with the [`EcamStatus`] each one covers,
+/// so that the match (which must cover every status) and the list of states can't disagree.
+macro_rules! machine_states {
+    ($($status:pat => $name:literal,)*) => {
+        /// Every state reported by the `longshot_machine_state` gauge, other than [`UNKNOWN_STATE`].
+        const STATES: &[&str] = &[$($name),*];
+
+        fn state_name(status: EcamStatus) -> &'static str {
+            match status {
+                $($status => $name,)*
+            }
+        }
+    };
+}
+
+machine_states! {
+    EcamStatus::StandBy => "standby",
+    EcamStatus::TurningOn(_) => "turning_on",
+    EcamStatus::ShuttingDown(_) => "shutting_down",
+    EcamStatus::Ready => "ready",
+    EcamStatus::Busy(_) => "busy",
+    EcamStatus::Rinsing(_) => "rinsing",
+    EcamStatus::MilkCleaning(_) => "milk_cleaning",
+    EcamStatus::HotWater(_) => "hot_water",
+    EcamStatus::Descaling => "descaling",
+    EcamStatus::Alarm(_) => "alarm",
+    EcamStatus::Fault => "fault",
+    EcamStatus::Fetching(_) => "fetching",
+}
+
+/// The state reported when the machine's status isn't known yet (or can't be read without waiting).
+const UNKNOWN_STATE: &str = "unknown";
+
+#[derive(Default)]
+pub struct Metrics {
+    brews: AtomicU64,
+    brew_errors: AtomicU64,
+    /// Successful brews by the first bucket they fit in (the last is `+Inf`).
+    brew_duration_buckets: [AtomicU64; BREW_DURATION_BUCKETS.len() + 1],
+    brew_duration_millis: AtomicU64,
+}
+
+impl Metrics {
+    /// Records a brew request that made it past parsing, and how it turned out.
+    pub fn record_brew(&self, duration: Duration, success: bool) {
+        self.brews.fetch_add(1, Ordering::Relaxed);
+        if !success {
+            self.brew_errors.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let bucket = BREW_DURATION_BUCKETS
+            .iter()
+            .position(|&le| duration <= Duration::from_secs(le))
+            .unwrap_or(BREW_DURATION_BUCKETS.len());
+        self.brew_duration_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.brew_duration_millis
+            .fetch_add(duration.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Renders the metrics, along with the machine's current status (reported as [`UNKNOWN_STATE`] if it isn't known).
+    pub fn render(&self, status: Option<EcamStatus>) -> String {
+        let mut s = String::new();
+        let brews = self.brews.load(Ordering::Relaxed);
+        let brew_errors = self.brew_errors.load(Ordering::Relaxed);
+        let _ = writeln!(s, "# HELP longshot_brews_total Brews requested.");
+        let _ = writeln!(s, "# TYPE longshot_brews_total counter");
+        let _ = writeln!(s, "longshot_brews_total {}", brews);
+        let _ = writeln!(s, "# HELP longshot_brew_errors_total Brews that failed.");
+        let _ = writeln!(s, "# TYPE longshot_brew_errors_total counter");
+        let _ = writeln!(s, "longshot_brew_errors_total {}", brew_errors);
+
+        let _ = writeln!(
+            s,
+            "# HELP longshot_brew_duration_seconds Time taken by successful brews."
+        );
+        let _ = writeln!(s, "# TYPE longshot_brew_duration_seconds histogram");
+        let mut count = 0;
+        for (i, bucket) in self.brew_duration_buckets.iter().enumerate() {
+            count += bucket.load(Ordering::Relaxed);
+            let le = match BREW_DURATION_BUCKETS.get(i) {
+                Some(le) => le.to_string(),
+                None => "+Inf".to_owned(),
+            };
+            let _ = writeln!(
+                s,
+                "longshot_brew_duration_seconds_bucket{{le=\"{}\"}} {}",
+                le, count
+            );
+        }
+        let sum = self.brew_duration_millis.load(Ordering::Relaxed) as f64 / 1000.0;
+        let _ = writeln!(s, "longshot_brew_duration_seconds_sum {}", sum);
+        let _ = writeln!(s, "longshot_brew_duration_seconds_count {}", count);
+
+        let _ = writeln!(
+            s,
+            "# HELP longshot_machine_state The machine's current state (1 for the current state, 0 otherwise)."
+        );
+        let _ = writeln!(s, "# TYPE longshot_machine_state gauge");
+        let current = status.map(state_name).unwrap_or(UNKNOWN_STATE);
+        for state in STATES.iter().chain([&UNKNOWN_STATE]) {
+            let _ = writeln!(
+                s,
+                "longshot_machine_state{{state=\"{}\"}} {}",
+                state,
+                (*state == current) as u8
+            );
+        }
+        s
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn render() {
+        let metrics = Metrics::default();
+        metrics.record_brew(Duration::from_secs(45), true);
+        metrics.record_brew(Duration::from_millis(1500), false);
+        metrics.record_brew(Duration::from_secs(1200), true);
+        let rendered = metrics.render(Some(EcamStatus::Busy(10)));
+        for line in [
+            "longshot_brews_total 3",
+            "longshot_brew_errors_total 1",
+            "longshot_brew_duration_seconds_bucket{le=\"30\"} 0",
+            "longshot_brew_duration_seconds_bucket{le=\"60\"} 1",
+            "longshot_brew_duration_seconds_bucket{le=\"600\"} 1",
+            "longshot_brew_duration_seconds_bucket{le=\"+Inf\"} 2",
+            "longshot_brew_duration_seconds_sum 1245",
+            "longshot_brew_duration_seconds_count 2",
+            "longshot_machine_state{state=\"busy\"} 1",
+            "longshot_machine_state{state=\"ready\"} 0",
+        ] {
+            assert!(
+                rendered.lines().any(|x| x == line),
+                "Missing {}:\n{}",
+                line,
+                rendered
+            );
+        }
+        // Only the unknown state is current if the status isn't known
+        let rendered = metrics.render(None);
+        let current: Vec<_> = rendered
+            .lines()
+            .filter(|x| x.starts_with("longshot_machine_state{") && x.ends_with(" 1"))
+            .collect();
+        assert_eq!(current, vec!["longshot_machine_state{state=\"unknown\"} 1"]);
+    }
+}
@@ -1 +1,4 @@
+mod metrics;
 mod web;
+
+pub use web::serve;
@@ -1 +1,295 @@
+//! A small HTTP API for a single machine, for webhooks and home automation that doesn't use the command line:
+//!
+//!  * `GET /status`: the machine's [`EcamStatusDetail`].
+//!  * `GET /status/ws`: a WebSocket that sends the machine's [`EcamStatusDetail`] as a JSON text frame when connected,
+//!    and again whenever the status (or the progress of the current operation) changes.
+//!  * `GET /recipes`: the machine's recipes, as printed by `list-recipes --json`.
+//!  * `POST /brew`: brews a beverage described like a recipe file (see [`SavedRecipe`]), ie:
+//!    `{"beverage": "cappuccino", "ingredients": {"coffee": 80}}`. Unspecified ingredients use the machine's defaults.
+//!
+//! Failures are reported with the same JSON object as `--json-errors`.
+//!
+//! If given a metrics address, the server also serves Prometheus metrics at `GET /metrics` on that address.
 
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use futures::{Stream, StreamExt};
+use longshot::ecam::{Ecam, EcamError, EcamStatusDetail};
+use longshot::operations::*;
+use tokio::sync::Mutex;
+
+use super::metrics::Metrics;
+use crate::ErrorReport;
+
+struct AppState {
+    ecam: Ecam,
+    /// Held while talking to the machine, so that brews (and recipe fetches) run one at a time.
+    busy: Mutex<()>,
+    metrics: Metrics,
+}
+
+/// An [`EcamError`] along with the HTTP status it's reported with.
+#[derive(Debug)]
+struct ApiError(EcamError);
+
+impl From<EcamError> for ApiError {
+    fn from(e: EcamError) -> Self {
+        ApiError(e)
+    }
+}
+
+impl ApiError {
+    fn status(&self) -> StatusCode {
+        match &self.0 {
+            EcamError::NotFound => StatusCode::NOT_FOUND,
+            EcamError::IOError(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                StatusCode::BAD_REQUEST
+            }
+            EcamError::UnsupportedBeverage(_) | EcamError::MissingRecipeVariant(..) => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            EcamError::Busy(_)
+            | EcamError::NotReady(_)
+            | EcamError::DeviceInUse
+            | EcamError::GroundsContainerFull
+            | EcamError::AccessoryDetached(_) => StatusCode::CONFLICT,
+            EcamError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status(), Json(ErrorReport::new(&self.0))).into_response()
+    }
+}
+
+async fn status(State(state): State<Arc<AppState>>) -> Result<Json<EcamStatusDetail>, ApiError> {
+    Ok(Json(state.ecam.current_state_detailed().await?))
+}
+
+/// The JSON frames sent to status WebSocket clients, starting with the current status.
+async fn status_frames(ecam: Ecam) -> Result<impl Stream<Item = String>, EcamError> {
+    let details = ecam.status_detail_stream().await?;
+    Ok(details.filter_map(|detail| async move { serde_json::to_string(&detail).ok() }))
+}
+
+async fn status_ws(State(state): State<Arc<AppState>>, ws: WebSocketUpgrade) -> Response {
+    ws.on_upgrade(move |socket| forward_status(state.ecam.clone(), socket))
+}
+
+async fn forward_status(ecam: Ecam, mut socket: WebSocket) {
+    let frames = match status_frames(ecam).await {
+        Ok(frames) => frames,
+        Err(e) => {
+            longshot::warning!("Failed to stream status: {}", e);
+            let _ = socket.close().await;
+            return;
+        }
+    };
+    futures::pin_mut!(frames);
+    loop {
+        tokio::select! {
+            frame = frames.next() => match frame {
+                Some(frame) => if socket.send(Message::Text(frame)).await.is_err() {
+                    break;
+                },
+                None => {
+                    let _ = socket.close().await;
+                    break;
+                }
+            },
+            // Clients don't send anything, but we need to notice when they leave
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+async fn recipes(State(state): State<Arc<AppState>>) -> Result<Json<Vec<RecipeDetails>>, ApiError> {
+    let _busy = state.busy.lock().await;
+    Ok(Json(
+        list_recipies_for(state.ecam.clone(), None).await?.recipes,
+    ))
+}
+
+/// Brews the requested beverage, responding with the recipe that was brewed once it's complete.
+async fn brew_beverage(
+    State(state): State<Arc<AppState>>,
+    Json(body): Json<serde_json::Value>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let requested = SavedRecipe::from_json(body)?;
+    let _busy = state.busy.lock().await;
+    let start = tokio::time::Instant::now();
+    let result = async {
+        let recipe = validate_brew(
+            state.ecam.clone(),
+            requested.beverage,
+            DEFAULT_RECIPE_VARIANT,
+            requested.ingredients,
+            IngredientCheckMode::AllowDefaults,
+        )
+        .await?;
+        brew(
+            state.ecam.clone(),
+            false,
+            requested.beverage,
+            recipe.clone(),
+        )
+        .await?;
+        Ok::<_, EcamError>(recipe)
+    }
+    .await;
+    state.metrics.record_brew(start.elapsed(), result.is_ok());
+    let recipe = result?;
+    Ok(Json(
+        SavedRecipe::from_recipe(requested.beverage, &recipe).to_json(),
+    ))
+}
+
+async fn metrics(State(state): State<Arc<AppState>>) -> String {
+    // Scrapes shouldn't wait on the machine, so report the state as unknown if it can't be read right away
+    state.metrics.render(state.ecam.try_current_state())
+}
+
+fn router(state: Arc<AppState>) -> Router {
+    Router::new()
+        .route("/status", get(status))
+        .route("/status/ws", get(status_ws))
+        .route("/recipes", get(recipes))
+        .route("/brew", post(brew_beverage))
+        .with_state(state)
+}
+
+fn metrics_router(state: Arc<AppState>) -> Router {
+    Router::new()
+        .route("/metrics", get(metrics))
+        .with_state(state)
+}
+
+/// Serves the HTTP API for the machine on `addr` (and its metrics on `metrics_addr`, if given) until the server fails.
+pub async fn serve(
+    ecam: Ecam,
+    addr: SocketAddr,
+    metrics_addr: Option<SocketAddr>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let state = Arc::new(AppState {
+        ecam,
+        busy: Mutex::new(()),
+        metrics: Metrics::default(),
+    });
+    let api = axum::Server::try_bind(&addr)?.serve(router(state.clone()).into_make_service());
+    longshot::info!("Listening on http://{}", addr);
+    match metrics_addr {
+        Some(metrics_addr) => {
+            let metrics = axum::Server::try_bind(&metrics_addr)?
+                .serve(metrics_router(state).into_make_service());
+            longshot::info!("Serving metrics on http://{}/metrics", metrics_addr);
+            tokio::try_join!(api, metrics)?;
+        }
+        None => api.await?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use longshot::ecam::{get_ecam_simulator, EcamStatus};
+
+    async fn state() -> Result<State<Arc<AppState>>, EcamError> {
+        state_for("sim[on]").await
+    }
+
+    async fn state_for(simulator: &str) -> Result<State<Arc<AppState>>, EcamError> {
+        let ecam = Ecam::new(Box::new(get_ecam_simulator(simulator).await?), false).await;
+        ecam.wait_for_state(EcamStatus::Ready, |_| {}).await?;
+        Ok(State(Arc::new(AppState {
+            ecam,
+            busy: Mutex::new(()),
+            metrics: Metrics::default(),
+        })))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn status_and_brew() -> Result<(), EcamError> {
+        let state = state().await?;
+        assert_eq!(
+            status(state.clone()).await.unwrap().0.status,
+            EcamStatus::Ready
+        );
+
+        let body = serde_json::json!({"beverage": "espressocoffee", "ingredients": {"coffee": 50}});
+        let brewed = brew_beverage(state.clone(), Json(body)).await.unwrap().0;
+        assert_eq!(brewed["beverage"], "espressocoffee");
+        assert_eq!(brewed["ingredients"]["coffee"], 50);
+
+        let rendered = metrics(state.clone()).await;
+        assert!(
+            rendered.contains("longshot_brews_total 1\n"),
+            "{}",
+            rendered
+        );
+        assert!(
+            rendered.contains("longshot_brew_errors_total 0\n"),
+            "{}",
+            rendered
+        );
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn status_frames_start_with_current_status() -> Result<(), EcamError> {
+        let state = state().await?;
+        let frames: Vec<_> = status_frames(state.ecam.clone())
+            .await?
+            .take(2)
+            .collect()
+            .await;
+        let frames: Vec<serde_json::Value> = frames
+            .iter()
+            .map(|frame| serde_json::from_str(frame).expect("Invalid JSON"))
+            .collect();
+        assert_eq!(frames[0]["status"], "Ready");
+        assert_eq!(frames[0]["percentage"], 0);
+        assert_eq!(frames[1]["status"]["Busy"], 4);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn brew_rejects_invalid_recipes() -> Result<(), EcamError> {
+        let state = state().await?;
+        for body in [
+            serde_json::json!({"beverage": "mocha"}),
+            serde_json::json!({"beverage": "espressocoffee", "ingredients": {"sugar": 2}}),
+            serde_json::json!({"ingredients": {}}),
+        ] {
+            match brew_beverage(state.clone(), Json(body.clone())).await {
+                Err(e) => assert_eq!(e.status(), StatusCode::BAD_REQUEST, "{}", body),
+                Ok(_) => panic!("Expected {} to be rejected", body),
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn brew_rejects_unsupported_beverages() -> Result<(), EcamError> {
+        let state = state_for("sim[on][restricted]").await?;
+        let body = serde_json::json!({"beverage": "americano"});
+        match brew_beverage(state, Json(body)).await {
+            Err(e) => assert_eq!(e.status(), StatusCode::UNPROCESSABLE_ENTITY),
+            Ok(_) => panic!("Expected americano to be unsupported"),
+        }
+        Ok(())
+    }
+}
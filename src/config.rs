@@ -0,0 +1,144 @@
+//! The user's configuration file, which gives friendly names to devices so they don't have to be identified by their
+//! raw ids:
+//!
+//! ```toml
+//! [devices]
+//! kitchen = "00000000-0000-0000-0000-000000000000"
+//! ```
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::ecam::EcamError;
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Config {
+    /// Aliases for devices, mapped to the device ids shown by `longshot list`.
+    #[serde(default)]
+    pub devices: BTreeMap<String, String>,
+}
+
+fn invalid_data<E: std::error::Error + Send + Sync + 'static>(e: E) -> EcamError {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, e).into()
+}
+
+impl Config {
+    /// The default location of the configuration file, `$XDG_CONFIG_HOME/longshot/config.toml` (falling back to
+    /// `~/.config/longshot/config.toml`). Returns `None` if neither directory is known.
+    pub fn default_path() -> Option<PathBuf> {
+        let dir = std::env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+        Some(dir.join("longshot").join("config.toml"))
+    }
+
+    /// Loads the configuration file, returning an empty configuration if the file doesn't exist.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, EcamError> {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => toml::from_str(&contents).map_err(invalid_data),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Writes the configuration file, creating its directory if needed.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), EcamError> {
+        if let Some(dir) = path.as_ref().parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, toml::to_string(self).map_err(invalid_data)?)?;
+        Ok(())
+    }
+
+    /// Returns the device id for an alias. Anything that isn't an alias is returned unchanged, so raw device ids still
+    /// work.
+    pub fn resolve_device<'a>(&'a self, name: &'a str) -> &'a str {
+        self.devices.get(name).map(String::as_str).unwrap_or(name)
+    }
+
+    /// Adds an alias for each of the given `(name, id)` devices, as returned by
+    /// [`crate::ecam::ecam_scan_all`]. Existing aliases and devices that already have one are left alone. Returns the
+    /// number of aliases added.
+    pub fn add_devices(&mut self, devices: &[(String, String)]) -> usize {
+        let mut added = 0;
+        for (name, id) in devices {
+            if self.devices.contains_key(name) || self.devices.values().any(|x| x == id) {
+                continue;
+            }
+            self.devices.insert(name.clone(), id.clone());
+            added += 1;
+        }
+        added
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config_path(name: &str) -> PathBuf {
+        std::env::temp_dir()
+            .join(format!(
+                "longshot-test-config-{}-{}",
+                name,
+                std::process::id()
+            ))
+            .join("config.toml")
+    }
+
+    #[test]
+    fn resolve_device() {
+        let config: Config = toml::from_str(
+            r#"
+            [devices]
+            kitchen = "11111111-2222-3333-4444-555555555555"
+            "#,
+        )
+        .expect("Invalid config");
+        assert_eq!(
+            config.resolve_device("kitchen"),
+            "11111111-2222-3333-4444-555555555555"
+        );
+        assert_eq!(config.resolve_device("Kitchen"), "Kitchen");
+        assert_eq!(config.resolve_device("sim[on]"), "sim[on]");
+    }
+
+    #[test]
+    fn save_and_load() -> Result<(), EcamError> {
+        let path = config_path("round-trip");
+        assert_eq!(Config::load(&path)?, Config::default());
+
+        let mut config = Config::default();
+        let devices = vec![
+            ("D1234567".to_owned(), "aaaa".to_owned()),
+            ("D7654321".to_owned(), "bbbb".to_owned()),
+        ];
+        assert_eq!(config.add_devices(&devices), 2);
+        assert_eq!(config.add_devices(&devices), 0);
+        config.save(&path)?;
+        assert_eq!(Config::load(&path)?, config);
+
+        std::fs::write(&path, "[devices\n")?;
+        assert!(matches!(Config::load(&path), Err(EcamError::IOError(_))));
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn add_devices_keeps_aliases() {
+        let mut config = Config::default();
+        config
+            .devices
+            .insert("kitchen".to_owned(), "aaaa".to_owned());
+        config
+            .devices
+            .insert("D7654321".to_owned(), "cccc".to_owned());
+        let devices = vec![
+            ("D1234567".to_owned(), "aaaa".to_owned()),
+            ("D7654321".to_owned(), "bbbb".to_owned()),
+        ];
+        assert_eq!(config.add_devices(&devices), 0);
+        assert_eq!(config.resolve_device("D7654321"), "cccc");
+    }
+}
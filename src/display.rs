@@ -1,14 +1,18 @@
 //! Status display utilities.
 
 use crate::ecam::EcamStatus;
+use crate::logging::TraceDirection;
 use atty::Stream;
 use colored::*;
 use lazy_static::lazy_static;
 use std::io::Write;
 use std::sync::Mutex;
 
+type StatusSink = Box<dyn Fn(EcamStatus) + Send + Sync>;
+
 lazy_static! {
     static ref DISPLAY: Mutex<Option<Box<dyn StatusDisplay>>> = Mutex::new(None);
+    static ref STATUS_SINKS: Mutex<Vec<StatusSink>> = Mutex::new(vec![]);
 }
 
 /// Initializes the global display based on the `TERM` and `COLORTERM` environment variables.
@@ -34,8 +38,38 @@ pub fn initialize_display() {
     }
 }
 
+/// Replaces the global display with one that never shows statuses and sends all log output to stderr, leaving stdout
+/// free for machine-readable output.
+pub fn initialize_quiet_display() {
+    *DISPLAY
+        .lock()
+        .expect("Failed to lock display for initialization") =
+        Some(Box::new(QuietStatusDisplay::default()));
+}
+
+/// Replaces the global display with one that writes every log line and status change to stderr as a line of JSON, for
+/// log aggregators. Like [`initialize_quiet_display`], stdout is left free for machine-readable output.
+pub fn initialize_json_display() {
+    *DISPLAY
+        .lock()
+        .expect("Failed to lock display for initialization") =
+        Some(Box::new(JsonStatusDisplay::default()));
+}
+
+/// Registers an additional sink that receives every [`EcamStatus`] passed to [`display_status`].
+pub fn add_status_sink<F: Fn(EcamStatus) + Send + Sync + 'static>(f: F) {
+    if let Ok(mut sinks) = STATUS_SINKS.lock() {
+        sinks.push(Box::new(f));
+    }
+}
+
 /// Displays the [`EcamStatus`] according to the current mode.
 pub fn display_status(state: EcamStatus) {
+    if let Ok(sinks) = STATUS_SINKS.lock() {
+        for sink in sinks.iter() {
+            sink(state);
+        }
+    }
     if let Ok(mut display) = DISPLAY.lock() {
         if let Some(ref mut display) = *display {
             display.display(state);
@@ -61,15 +95,28 @@ pub fn shutdown() {
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum LogLevel {
     Trace,
+    Debug,
     Info,
     Warning,
     Error,
 }
 
 impl LogLevel {
+    /// The lowercase name of the level, as used in JSON log lines.
+    pub fn name(&self) -> &'static str {
+        match self {
+            LogLevel::Trace => "trace",
+            LogLevel::Debug => "debug",
+            LogLevel::Info => "info",
+            LogLevel::Warning => "warning",
+            LogLevel::Error => "error",
+        }
+    }
+
     pub fn prefix(&self) -> &'static str {
         match self {
             LogLevel::Trace => "[TRACE] ",
+            LogLevel::Debug => "[DEBUG] ",
             LogLevel::Warning => "[WARNING] ",
             LogLevel::Error => "[ERROR] ",
             LogLevel::Info => "",
@@ -79,9 +126,26 @@ impl LogLevel {
 
 /// Logs the [`EcamStatus`] according to the current mode.
 pub fn log(level: LogLevel, s: &str) {
+    log_from(level, "longshot", s)
+}
+
+/// Logs the given text according to the current mode, noting the module it came from (the `target`).
+pub fn log_from(level: LogLevel, target: &str, s: &str) {
+    if let Ok(mut display) = DISPLAY.lock() {
+        if let Some(ref mut display) = *display {
+            display.log_from(level, target, s);
+            return;
+        }
+    }
+    println!("[default] {:?}", s);
+}
+
+/// Logs the trace of a raw frame according to the current mode. `s` is the formatted trace, while the direction and
+/// bytes are kept for displays that show them separately.
+pub fn log_frame(level: LogLevel, target: &str, s: &str, direction: TraceDirection, bytes: &[u8]) {
     if let Ok(mut display) = DISPLAY.lock() {
         if let Some(ref mut display) = *display {
-            display.log(level, s);
+            display.log_frame(level, target, s, direction, bytes);
             return;
         }
     }
@@ -92,6 +156,21 @@ trait StatusDisplay: Send + Sync {
     fn display(&mut self, state: EcamStatus);
     fn clear_status(&mut self);
     fn log(&mut self, level: LogLevel, s: &str);
+
+    fn log_from(&mut self, level: LogLevel, _target: &str, s: &str) {
+        self.log(level, s);
+    }
+
+    fn log_frame(
+        &mut self,
+        level: LogLevel,
+        target: &str,
+        s: &str,
+        _direction: TraceDirection,
+        _bytes: &[u8],
+    ) {
+        self.log_from(level, target, s);
+    }
 }
 
 /// [`StatusDisplay`] for basic terminals, or non-TTY stdio.
@@ -122,6 +201,88 @@ impl StatusDisplay for NoTtyStatusDisplay {
     }
 }
 
+/// [`StatusDisplay`] that leaves stdout untouched.
+#[derive(Default)]
+struct QuietStatusDisplay {}
+
+impl StatusDisplay for QuietStatusDisplay {
+    fn display(&mut self, _state: EcamStatus) {}
+
+    fn clear_status(&mut self) {}
+
+    fn log(&mut self, level: LogLevel, s: &str) {
+        eprintln!("{}{}", level.prefix(), s);
+    }
+}
+
+/// Formats a log line as JSON. Frame traces also include the direction and bytes of the frame.
+fn json_log_line(
+    level: LogLevel,
+    target: &str,
+    s: &str,
+    frame: Option<(TraceDirection, &[u8])>,
+) -> serde_json::Value {
+    let mut line = serde_json::json!({
+        "level": level.name(),
+        "target": target,
+        "message": s,
+    });
+    if let Some((direction, bytes)) = frame {
+        line["direction"] = direction.name().into();
+        line["hex"] = hex::encode(bytes).into();
+    }
+    line
+}
+
+/// [`StatusDisplay`] that writes JSON lines to stderr, leaving stdout untouched.
+#[derive(Default)]
+struct JsonStatusDisplay {
+    last_state: Option<EcamStatus>,
+}
+
+impl StatusDisplay for JsonStatusDisplay {
+    fn display(&mut self, state: EcamStatus) {
+        if self.last_state == Some(state) {
+            return;
+        }
+        let mut line = json_log_line(
+            LogLevel::Info,
+            module_path!(),
+            &format!("{:?}", state),
+            None,
+        );
+        line["status"] = format!("{:?}", state).into();
+        eprintln!("{}", line);
+        self.last_state = Some(state);
+    }
+
+    fn clear_status(&mut self) {
+        self.last_state = None;
+    }
+
+    fn log(&mut self, level: LogLevel, s: &str) {
+        self.log_from(level, "longshot", s);
+    }
+
+    fn log_from(&mut self, level: LogLevel, target: &str, s: &str) {
+        eprintln!("{}", json_log_line(level, target, s, None));
+    }
+
+    fn log_frame(
+        &mut self,
+        level: LogLevel,
+        target: &str,
+        s: &str,
+        direction: TraceDirection,
+        bytes: &[u8],
+    ) {
+        eprintln!(
+            "{}",
+            json_log_line(level, target, s, Some((direction, bytes)))
+        );
+    }
+}
+
 struct TtyStatus {
     pub activity: usize,
     pub width: usize,
@@ -220,7 +381,16 @@ impl StatusDisplay for ColouredStatusDisplay {
             EcamStatus::Ready => (0, "✅", "Ready".to_string()),
             EcamStatus::StandBy => (0, "💤", "Standby".to_string()),
             EcamStatus::Busy(percent) => (percent, "☕", format!("Dispensing... ({}%)", percent)),
-            EcamStatus::Cleaning(percent) => (percent, "💧", format!("Cleaning... ({}%)", percent)),
+            EcamStatus::Rinsing(percent) => (percent, "💧", format!("Rinsing... ({}%)", percent)),
+            EcamStatus::MilkCleaning(percent) => {
+                (percent, "💧", format!("Cleaning milk... ({}%)", percent))
+            }
+            EcamStatus::HotWater(percent) => (
+                percent,
+                "♨",
+                format!("Dispensing hot water... ({}%)", percent),
+            ),
+            EcamStatus::Fault => (0, "⚠", "Fault".to_string()),
             EcamStatus::Descaling => (0, "💧", "Descaling".to_string()),
             EcamStatus::TurningOn(percent) => {
                 (percent, "💡", format!("Turning on... ({}%)", percent))
@@ -330,7 +500,10 @@ impl StatusDisplay for BasicStatusDisplay {
             EcamStatus::TurningOn(percent) => ("Turning on...".to_owned(), Some(percent)),
             EcamStatus::ShuttingDown(percent) => ("Shutting down...".to_owned(), Some(percent)),
             EcamStatus::Busy(percent) => ("Dispensing...".to_owned(), Some(percent)),
-            EcamStatus::Cleaning(percent) => ("Cleaning...".to_owned(), Some(percent)),
+            EcamStatus::Rinsing(percent) => ("Rinsing...".to_owned(), Some(percent)),
+            EcamStatus::MilkCleaning(percent) => ("Cleaning milk...".to_owned(), Some(percent)),
+            EcamStatus::HotWater(percent) => ("Dispensing hot water...".to_owned(), Some(percent)),
+            EcamStatus::Fault => ("Fault".to_owned(), None),
             EcamStatus::Descaling => ("Descaling...".to_owned(), None),
             EcamStatus::Alarm(alarm) => (format!("Alarm: {:?}", alarm), None),
             EcamStatus::Fetching(percent) => ("Fetching...".to_owned(), Some(percent)),
@@ -346,7 +519,9 @@ impl StatusDisplay for BasicStatusDisplay {
 
 #[cfg(test)]
 mod test {
-    use super::{make_bar, ColouredStatusDisplay, StatusDisplay};
+    use super::{json_log_line, make_bar, ColouredStatusDisplay, LogLevel, StatusDisplay};
+    use crate::logging::TraceDirection;
+    use crate::protocol::hexdump;
 
     #[test]
     fn format_no_progress() {
@@ -387,4 +562,28 @@ mod test {
             display.display(crate::ecam::EcamStatus::Busy(i));
         }
     }
+
+    #[test]
+    fn format_json() {
+        assert_eq!(
+            json_log_line(LogLevel::Warning, "longshot::ecam", "Connection lost", None),
+            serde_json::json!({"level": "warning", "target": "longshot::ecam", "message": "Connection lost"})
+        );
+        let trace = format!("{{host->device}} {}", hexdump(&[0x0d, 0x05, 0x75, 0x0f]));
+        assert_eq!(
+            json_log_line(
+                LogLevel::Trace,
+                "longshot::ecam::ecam_bt",
+                &trace,
+                Some((TraceDirection::HostToDevice, &[0x0d, 0x05, 0x75, 0x0f]))
+            ),
+            serde_json::json!({
+                "level": "trace",
+                "target": "longshot::ecam::ecam_bt",
+                "message": trace,
+                "direction": "host->device",
+                "hex": "0d05750f",
+            })
+        );
+    }
 }
@@ -0,0 +1,97 @@
+//! Detects when another longshot process is already connected to a device, as only one process can hold the BTLE
+//! connection at a time.
+
+use crate::ecam::EcamError;
+use crate::prelude::*;
+
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+
+/// Held for as long as this process is connected to a device. The lock is a Unix domain socket in the temporary
+/// directory: it is considered held while something is listening on it, so a lock left behind by a process that
+/// crashed is detected as stale and replaced.
+pub struct DeviceLock {
+    #[cfg(unix)]
+    path: PathBuf,
+    #[cfg(unix)]
+    _listener: UnixListener,
+}
+
+impl DeviceLock {
+    /// Takes the lock for the given device, failing with [`EcamError::DeviceInUse`] if another process holds it.
+    #[cfg(unix)]
+    pub fn acquire(device_name: &str) -> Result<Self, EcamError> {
+        let path = Self::path(device_name);
+        let listener = match UnixListener::bind(&path) {
+            Ok(listener) => listener,
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => {
+                if UnixStream::connect(&path).is_ok() {
+                    return Err(EcamError::DeviceInUse);
+                }
+                trace_packet!("Replacing stale device lock {}", path.display());
+                std::fs::remove_file(&path)?;
+                UnixListener::bind(&path)?
+            }
+            Err(e) => return Err(e.into()),
+        };
+        Ok(DeviceLock {
+            path,
+            _listener: listener,
+        })
+    }
+
+    /// Locking isn't supported on this platform, so this always succeeds.
+    #[cfg(not(unix))]
+    pub fn acquire(_device_name: &str) -> Result<Self, EcamError> {
+        Ok(DeviceLock {})
+    }
+
+    fn path(device_name: &str) -> PathBuf {
+        let name: String = device_name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        std::env::temp_dir().join(format!("longshot-{}.lock", name))
+    }
+}
+
+#[cfg(unix)]
+impl Drop for DeviceLock {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+
+    fn device_name(test: &str) -> String {
+        format!("test-{}-{}", test, std::process::id())
+    }
+
+    #[test]
+    fn lock_contention() -> Result<(), EcamError> {
+        let device = device_name("contention");
+        let lock = DeviceLock::acquire(&device)?;
+        assert!(matches!(
+            DeviceLock::acquire(&device),
+            Err(EcamError::DeviceInUse)
+        ));
+        drop(lock);
+        assert!(!DeviceLock::path(&device).exists());
+        DeviceLock::acquire(&device)?;
+        Ok(())
+    }
+
+    #[test]
+    fn stale_lock() -> Result<(), EcamError> {
+        // A listener that goes away without cleaning up leaves the socket file behind
+        let device = device_name("stale");
+        drop(UnixListener::bind(DeviceLock::path(&device))?);
+        assert!(DeviceLock::path(&device).exists());
+        DeviceLock::acquire(&device)?;
+        Ok(())
+    }
+}
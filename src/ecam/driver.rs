@@ -1,4 +1,6 @@
-use crate::{prelude::*, protocol::*};
+use crate::{ecam::EcamError, prelude::*, protocol::*};
+
+use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum EcamDriverOutput {
@@ -7,6 +9,24 @@ pub enum EcamDriverOutput {
     Done,
 }
 
+/// Metadata describing the device a driver is connected to.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct DeviceInfo {
+    /// The name the device advertises itself with.
+    pub name: String,
+    /// The platform-specific identifier of the device.
+    pub id: String,
+    /// The signal strength of the device, if known.
+    pub rssi: Option<i16>,
+    /// The UUID of the GATT service used to communicate with the device.
+    pub service_uuid: String,
+    /// The UUID of the GATT characteristic used to communicate with the device.
+    pub characteristic_uuid: String,
+    /// The scale the device reports percentages on, if the driver knows it.
+    #[serde(default)]
+    pub percentage_scale: Option<PercentageScale>,
+}
+
 /// Async-ish traits for read/write. See <https://smallcultfollowing.com/babysteps/blog/2019/10/26/async-fn-in-traits-are-hard/>
 /// for some tips on making async trait functions.
 pub trait EcamDriver: Send + Sync {
@@ -19,6 +39,11 @@ pub trait EcamDriver: Send + Sync {
     /// Returns true if the driver is alive.
     fn alive(&self) -> AsyncFuture<bool>;
 
+    /// Describes the connected device. Drivers that can't describe their device return [`EcamError::NotFound`].
+    fn info(&self) -> AsyncFuture<'_, DeviceInfo> {
+        Box::pin(async { Err(EcamError::NotFound) })
+    }
+
     /// Scan for the first matching device.
     fn scan<'a>() -> AsyncFuture<'a, (String, String)>
     where
@@ -28,7 +53,6 @@ pub trait EcamDriver: Send + Sync {
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::ecam::EcamError;
     use std::sync::Mutex;
 
     #[derive(Default)]
@@ -1,7 +1,10 @@
-use crate::ecam::{EcamDriver, EcamDriverOutput, EcamError, EcamPacketReceiver};
+use crate::ecam::{DeviceInfo, EcamDriver, EcamDriverOutput, EcamError, EcamPacketReceiver};
+use crate::logging::TraceDirection;
 use crate::{prelude::*, protocol::*};
+use async_stream::stream;
 use btleplug::api::{
-    Central, CharPropFlags, Characteristic, Manager as _, Peripheral as _, ScanFilter,
+    Central, CharPropFlags, Characteristic, Manager as _, Peripheral as _, ScanFilter, Service,
+    WriteType,
 };
 use btleplug::platform::{Adapter, Manager};
 use stream_cancel::{StreamExt as _, Tripwire};
@@ -10,8 +13,28 @@ use uuid::Uuid;
 
 use super::packet_stream::packet_stream;
 
-const SERVICE_UUID: Uuid = Uuid::from_u128(0x00035b03_58e6_07dd_021a_08123a000300);
-const CHARACTERISTIC_UUID: Uuid = Uuid::from_u128(0x00035b03_58e6_07dd_021a_08123a000301);
+/// The UUID of the ECAM GATT service.
+pub const SERVICE_UUID: Uuid = Uuid::from_u128(0x00035b03_58e6_07dd_021a_08123a000300);
+/// The UUID of the ECAM characteristic used for both requests and notifications.
+pub const CHARACTERISTIC_UUID: Uuid = Uuid::from_u128(0x00035b03_58e6_07dd_021a_08123a000301);
+
+/// How long to scan for ECAM devices when looking for any device.
+pub const DEFAULT_SCAN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often the peripherals are checked while scanning for ECAM devices.
+const SCAN_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How long to keep scanning for a device identified by its [`Uuid`] before giving up.
+pub const DEFAULT_SCAN_DEADLINE: Duration = Duration::from_secs(15);
+
+/// The delay before the first re-scan for a device that hasn't been found. This doubles after every failed scan.
+const INITIAL_SCAN_RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// The number of times the command-line tool tries to reconnect to a device that dropped its connection.
+pub const DEFAULT_RECONNECT_ATTEMPTS: usize = 3;
+
+/// How long to wait before each attempt to reconnect to a device that dropped its connection.
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
 
 /// The concrete peripheral type to avoid going crazy here managaing an unsized trait.
 type Peripheral = <Adapter as Central>::Peripheral;
@@ -25,73 +48,142 @@ pub struct EcamBT {
 impl EcamBT {
     /// Returns the given [`EcamBT`] instance identified by the [`Uuid`].
     pub async fn get(uuid: String) -> Result<Self, EcamError> {
+        Self::get_with_deadline(uuid, DEFAULT_SCAN_DEADLINE).await
+    }
+
+    /// Returns the given [`EcamBT`] instance identified by the [`Uuid`], scanning repeatedly until the device shows up
+    /// or the deadline elapses.
+    pub async fn get_with_deadline(uuid: String, deadline: Duration) -> Result<Self, EcamError> {
         let manager = Manager::new().await?;
-        Self::get_ecam_from_manager(&manager, uuid).await
+        Self::get_ecam_from_manager(&manager, uuid, None, deadline, 0).await
     }
 
-    async fn get_ecam_from_manager(manager: &Manager, uuid: String) -> Result<Self, EcamError> {
+    /// Returns the given [`EcamBT`] instance identified by the [`Uuid`], giving up with [`EcamError::Cancelled`] if the
+    /// [`Tripwire`] is triggered first.
+    pub async fn get_with_cancel(uuid: String, cancel: Tripwire) -> Result<Self, EcamError> {
+        let manager = Manager::new().await?;
+        Self::get_ecam_from_manager(&manager, uuid, Some(cancel), DEFAULT_SCAN_DEADLINE, 0).await
+    }
+
+    /// Returns the given [`EcamBT`] instance identified by the [`Uuid`], scanning until the device shows up or the
+    /// deadline elapses. If the connection drops, up to `max_attempts` attempts are made to reconnect before the device
+    /// is reported as gone.
+    pub async fn get_with_reconnect(
+        uuid: String,
+        deadline: Duration,
+        max_attempts: usize,
+    ) -> Result<Self, EcamError> {
+        let manager = Manager::new().await?;
+        Self::get_ecam_from_manager(&manager, uuid, None, deadline, max_attempts).await
+    }
+
+    async fn get_ecam_from_manager(
+        manager: &Manager,
+        uuid: String,
+        cancel: Option<Tripwire>,
+        deadline: Duration,
+        max_reconnect_attempts: usize,
+    ) -> Result<Self, EcamError> {
         let adapter_list = manager.adapters().await?;
         if adapter_list.is_empty() {
             return Result::Err(EcamError::NotFound);
         }
 
-        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
-        for adapter in adapter_list.into_iter() {
-            adapter.start_scan(ScanFilter::default()).await?;
-            let tx = tx.clone();
-            let uuid = uuid.clone();
-            let _ = tokio::spawn(async move {
-                trace_packet!("Looking for peripheral {}", uuid);
-                loop {
-                    let peripherals = adapter.peripherals().await?;
-                    let mut peripheral = None;
-                    if peripherals.is_empty() {
-                        println!("There is no peripherals...")
-                    }
-                    for periph in peripherals.iter() {
-                        println!("Found peripheral with id: {:?}", { periph.id() });
-                        if periph.id().to_string() == uuid {
-                            peripheral = Some(periph);
-                        }
-                    }
-                    // let peripheral = EcamBT::get_ecam_from_adapter(&adapter).await?;
-                    if let Some(peripheral) = peripheral {
-                        trace_packet!("Got peripheral");
-                        let peripheral = EcamPeripheral::connect(peripheral.clone()).await?;
-                        trace_packet!("Connected");
-                        let notifications = EcamPacketReceiver::from_stream(
-                            Box::pin(peripheral.notifications().await?),
-                            true,
-                        );
-                        trace_packet!("Notifications variable set");
-
-                        // Ignore errors here -- we just want the first peripheral that connects
-                        let _ = tx
-                            .send(EcamBT {
-                                peripheral,
-                                notifications,
-                            })
-                            .await;
-                        trace_packet!("Message send correctly :)");
-                        break;
-                    } else {
-                        return Result::Err(EcamError::NotFound);
+        let stop = async {
+            for adapter in adapter_list.iter() {
+                let _ = adapter.stop_scan().await;
+            }
+        };
+        until_cancelled(
+            cancel,
+            Self::connect_first(adapter_list.clone(), uuid, deadline, max_reconnect_attempts),
+            stop,
+        )
+        .await
+    }
+
+    async fn connect_first(
+        adapter_list: Vec<Adapter>,
+        uuid: String,
+        deadline: Duration,
+        max_reconnect_attempts: usize,
+    ) -> Result<Self, EcamError> {
+        debug!("Looking for peripheral {}", uuid);
+        let peripheral = retry_with_backoff(deadline, || async {
+            for adapter in adapter_list.iter() {
+                adapter.start_scan(ScanFilter::default()).await?;
+                for peripheral in adapter.peripherals().await?.into_iter() {
+                    debug!("Found peripheral with id: {:?}", peripheral.id());
+                    if peripheral_id(&peripheral) == uuid {
+                        return Ok(Some(peripheral));
                     }
                 }
-                Result::<_, EcamError>::Ok(())
-            })
-            .await;
-        }
+            }
+            Ok(None)
+        })
+        .await?;
+
+        debug!("Got peripheral");
+        let peripheral = EcamPeripheral::connect(peripheral).await?;
+        debug!("Connected");
+        let notifications = EcamPacketReceiver::from_stream(
+            Box::pin(
+                peripheral
+                    .notifications_with_reconnect(max_reconnect_attempts)
+                    .await?,
+            ),
+            true,
+        );
+        Ok(EcamBT {
+            peripheral,
+            notifications,
+        })
+    }
 
-        Ok(rx.recv().await.expect("Failed to receive anything"))
+    /// Writes monitor (status polling) requests with the given [`WriteType`]. Defaults to
+    /// [`WriteType::WithResponse`]; see [`EcamPeripheral::write`] for the tradeoff. All other requests, including brewing,
+    /// are always written with a response.
+    pub fn with_monitor_write_type(mut self, write_type: WriteType) -> Self {
+        self.peripheral.monitor_write_type = write_type;
+        self
     }
 
     /// Scans for ECAM devices.
     async fn scan() -> Result<(String, String), EcamError> {
+        Self::scan_until_cancelled(None, DEFAULT_SCAN_TIMEOUT).await
+    }
+
+    /// Scans for ECAM devices for up to `scan_timeout` on each adapter.
+    pub async fn scan_with_timeout(scan_timeout: Duration) -> Result<(String, String), EcamError> {
+        Self::scan_until_cancelled(None, scan_timeout).await
+    }
+
+    /// Scans for ECAM devices, stopping the scan and giving up with [`EcamError::Cancelled`] if the [`Tripwire`] is
+    /// triggered first.
+    pub async fn scan_with_cancel(cancel: Tripwire) -> Result<(String, String), EcamError> {
+        Self::scan_until_cancelled(Some(cancel), DEFAULT_SCAN_TIMEOUT).await
+    }
+
+    async fn scan_until_cancelled(
+        cancel: Option<Tripwire>,
+        scan_timeout: Duration,
+    ) -> Result<(String, String), EcamError> {
         let manager = Manager::new().await?;
         let adapter_list = manager.adapters().await?;
         for adapter in adapter_list.into_iter() {
-            if let Ok(Some(p)) = Self::get_ecam_from_adapter(&adapter).await {
+            let stop = async {
+                let _ = adapter.stop_scan().await;
+            };
+            let found = until_cancelled(
+                cancel.clone(),
+                Self::get_ecam_from_adapter(&adapter, scan_timeout),
+                stop,
+            )
+            .await;
+            if let Err(EcamError::Cancelled) = found {
+                return Err(EcamError::Cancelled);
+            }
+            if let Ok(Some(p)) = found {
                 let id = p.id();
                 return Ok((p.local_name, id));
             }
@@ -99,19 +191,68 @@ impl EcamBT {
         Err(EcamError::NotFound)
     }
 
+    /// Scans every adapter for the full `scan_timeout` window, returning the name and id of every ECAM device found.
+    pub async fn scan_all(scan_timeout: Duration) -> Result<Vec<(String, String)>, EcamError> {
+        let manager = Manager::new().await?;
+        let mut found: Vec<(String, String)> = vec![];
+        for adapter in manager.adapters().await?.into_iter() {
+            for p in Self::get_all_ecams_from_adapter(&adapter, scan_timeout).await? {
+                let id = p.id();
+                if !found.iter().any(|(_, existing)| *existing == id) {
+                    found.push((p.local_name, id));
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    /// Searches an adapter for everything that meets the definition of [`EcamPeripheral`] within the scan window.
+    async fn get_all_ecams_from_adapter(
+        adapter: &Adapter,
+        scan_timeout: Duration,
+    ) -> Result<Vec<EcamPeripheral>, EcamError> {
+        debug!("Starting scan on {}...", adapter.adapter_info().await?);
+        let filter = ScanFilter {
+            services: vec![SERVICE_UUID],
+        };
+        adapter.start_scan(filter).await?;
+
+        let mut seen = std::collections::HashSet::new();
+        let mut found = vec![];
+        for _ in 0..scan_polls(scan_timeout) {
+            time::sleep(SCAN_POLL_INTERVAL).await;
+            for peripheral in adapter.peripherals().await?.into_iter() {
+                // Each peripheral only needs to be validated once
+                if !seen.insert(peripheral.id()) {
+                    continue;
+                }
+                debug!("Found peripheral, address = {:?}", peripheral.address());
+                if let Some(peripheral) = EcamPeripheral::validate(peripheral).await? {
+                    found.push(peripheral);
+                }
+            }
+        }
+        let _ = adapter.stop_scan().await;
+
+        Ok(found)
+    }
+
     /// Searches an adapter for something that meets the definition of [`EcamPeripheral`].
-    async fn get_ecam_from_adapter(adapter: &Adapter) -> Result<Option<EcamPeripheral>, EcamError> {
-        trace_packet!("Starting scan on {}...", adapter.adapter_info().await?);
+    async fn get_ecam_from_adapter(
+        adapter: &Adapter,
+        scan_timeout: Duration,
+    ) -> Result<Option<EcamPeripheral>, EcamError> {
+        debug!("Starting scan on {}...", adapter.adapter_info().await?);
         let filter = ScanFilter {
             services: vec![SERVICE_UUID],
         };
         adapter.start_scan(filter).await?;
 
-        for _ in 0..10 {
-            time::sleep(Duration::from_millis(500)).await;
+        for _ in 0..scan_polls(scan_timeout) {
+            time::sleep(SCAN_POLL_INTERVAL).await;
             let peripherals = adapter.peripherals().await?;
             for peripheral in peripherals.into_iter() {
-                trace_packet!("Found peripheral, address = {:?}", peripheral.address());
+                debug!("Found peripheral, address = {:?}", peripheral.address());
                 if let Some(peripheral) = EcamPeripheral::validate(peripheral).await? {
                     adapter.stop_scan().await;
                     return Ok(Some(peripheral));
@@ -123,19 +264,185 @@ impl EcamBT {
     }
 }
 
+/// Returns the string used to identify a peripheral on the command-line, and to look it up again by id.
+#[cfg(target_os = "macos")]
+fn peripheral_id(peripheral: &Peripheral) -> String {
+    // Icky, but we don't have a PeripheralId to UUID function
+    debug!("{:?}", peripheral.id());
+    format!("{:?}", peripheral.id())[13..49].to_owned()
+}
+
+/// Returns the string used to identify a peripheral on the command-line, and to look it up again by id.
+#[cfg(target_os = "linux")]
+fn peripheral_id(peripheral: &Peripheral) -> String {
+    peripheral.id().to_string()
+}
+
+/// Returns the string used to identify a peripheral on the command-line, and to look it up again by id. Windows
+/// identifies peripherals by their Bluetooth address.
+#[cfg(target_os = "windows")]
+fn peripheral_id(peripheral: &Peripheral) -> String {
+    address_id(peripheral.address())
+}
+
+/// Formats a Bluetooth address as a peripheral id (ie: `1F:2A:00:CC:22:F1`), matching the `Display` implementation of
+/// the Windows `PeripheralId`.
+#[cfg(any(target_os = "windows", test))]
+fn address_id(address: btleplug::api::BDAddr) -> String {
+    address.to_string()
+}
+
+/// Finds the ECAM characteristic among a peripheral's services. The characteristic must be writable, and must be able
+/// to indicate (or notify) so that we can receive the device's responses.
+fn find_characteristic(services: impl IntoIterator<Item = Service>) -> Option<Characteristic> {
+    let characteristic = match services
+        .into_iter()
+        .flat_map(|service| service.characteristics)
+        .find(|characteristic| characteristic.uuid == CHARACTERISTIC_UUID)
+    {
+        Some(characteristic) => characteristic,
+        None => {
+            debug!("The peripheral doesn't have the ECAM characteristic");
+            return None;
+        }
+    };
+    let properties = characteristic.properties;
+    if !properties.intersects(CharPropFlags::WRITE | CharPropFlags::WRITE_WITHOUT_RESPONSE)
+        || !properties.intersects(CharPropFlags::INDICATE | CharPropFlags::NOTIFY)
+    {
+        debug!(
+            "The ECAM characteristic can't be used, as it only supports {:?}",
+            properties
+        );
+        return None;
+    }
+    Some(characteristic)
+}
+
+/// Picks the [`WriteType`] for a packet: monitor requests use `monitor_write_type`, and everything else is written with a
+/// response.
+fn write_type_for(data: &EcamDriverPacket, monitor_write_type: WriteType) -> WriteType {
+    let monitor: u8 = EcamRequestId::MonitorV2.into();
+    if data.bytes.first() == Some(&monitor) {
+        monitor_write_type
+    } else {
+        WriteType::WithResponse
+    }
+}
+
+/// The number of times to poll for peripherals while scanning for `scan_timeout`, always polling at least once.
+fn scan_polls(scan_timeout: Duration) -> u32 {
+    let polls = scan_timeout
+        .as_millis()
+        .div_ceil(SCAN_POLL_INTERVAL.as_millis());
+    polls.clamp(1, u32::MAX as u128) as u32
+}
+
+/// Runs `f` until it finds something, sleeping with exponential backoff between attempts. Gives up with
+/// [`EcamError::NotFound`] once the `deadline` has elapsed.
+async fn retry_with_backoff<T, F, Fut>(deadline: Duration, mut f: F) -> Result<T, EcamError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<Option<T>, EcamError>>,
+{
+    let start = time::Instant::now();
+    let mut delay = INITIAL_SCAN_RETRY_DELAY;
+    loop {
+        if let Some(found) = f().await? {
+            return Ok(found);
+        }
+        let remaining = deadline.saturating_sub(start.elapsed());
+        if remaining.is_zero() {
+            return Err(EcamError::NotFound);
+        }
+        time::sleep(delay.min(remaining)).await;
+        delay *= 2;
+    }
+}
+
+/// Runs `f` to completion unless the [`Tripwire`] is triggered first, in which case `stop` is run to clean up and
+/// [`EcamError::Cancelled`] is returned.
+async fn until_cancelled<T>(
+    cancel: Option<Tripwire>,
+    f: impl Future<Output = Result<T, EcamError>>,
+    stop: impl Future<Output = ()>,
+) -> Result<T, EcamError> {
+    match cancel {
+        None => f.await,
+        Some(cancel) => {
+            tokio::select! {
+                res = f => res,
+                true = cancel => {
+                    trace_shutdown!("scan cancelled");
+                    stop.await;
+                    Err(EcamError::Cancelled)
+                }
+            }
+        }
+    }
+}
+
+/// A boxed stream of driver output, so that the streams for each connection share a type.
+type BoxedOutputStream = Pin<Box<dyn Stream<Item = EcamDriverOutput> + Send>>;
+
+/// Forwards the output of the connection's stream. When it ends, `reconnect` is tried up to `max_attempts` times, and
+/// each successful reconnection is reported as [`EcamDriverOutput::Ready`] before forwarding the new connection's
+/// output. The stream only ends once reconnection fails, or fails with an error that isn't
+/// [retryable](EcamError::is_retryable).
+fn with_reconnect<F, Fut>(
+    first: BoxedOutputStream,
+    reconnect: F,
+    max_attempts: usize,
+) -> impl Stream<Item = EcamDriverOutput>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<BoxedOutputStream, EcamError>>,
+{
+    stream! {
+        let mut notifications = first;
+        'connected: loop {
+            while let Some(output) = notifications.next().await {
+                yield output;
+            }
+            for attempt in 1..=max_attempts {
+                time::sleep(RECONNECT_DELAY).await;
+                warning!("Connection lost, reconnecting ({}/{})...", attempt, max_attempts);
+                match reconnect().await {
+                    Ok(next) => {
+                        notifications = next;
+                        yield EcamDriverOutput::Ready;
+                        continue 'connected;
+                    }
+                    Err(e) if e.is_retryable() => warning!("Failed to reconnect: {}", e),
+                    Err(e) => {
+                        warning!("Failed to reconnect, giving up: {}", e);
+                        break;
+                    }
+                }
+            }
+            break;
+        }
+    }
+}
+
 impl EcamDriver for EcamBT {
     fn read<'a>(&self) -> AsyncFuture<Option<EcamDriverOutput>> {
         Box::pin(self.notifications.recv())
     }
 
     fn write<'a>(&self, data: EcamDriverPacket) -> AsyncFuture<()> {
-        Box::pin(self.peripheral.write(data.packetize()))
+        let write_type = write_type_for(&data, self.peripheral.monitor_write_type);
+        Box::pin(self.peripheral.write(data.packetize(), write_type))
     }
 
     fn alive(&self) -> AsyncFuture<bool> {
         Box::pin(self.peripheral.is_alive())
     }
 
+    fn info(&self) -> AsyncFuture<'_, DeviceInfo> {
+        Box::pin(self.peripheral.info())
+    }
+
     fn scan<'a>() -> AsyncFuture<'a, (String, String)>
     where
         Self: Sized,
@@ -150,18 +457,22 @@ struct EcamPeripheral {
     pub local_name: String,
     peripheral: Peripheral,
     characteristic: Characteristic,
+    /// The write type used for monitor (status polling) requests. All other requests are written with a response.
+    monitor_write_type: WriteType,
 }
 
 impl EcamPeripheral {
-    pub async fn write(&self, data: Vec<u8>) -> Result<(), EcamError> {
-        trace_packet!("{{host->device}} {}", hexdump(&data));
+    /// Writes a packet to the device.
+    ///
+    /// [`WriteType::WithResponse`] waits for the device to acknowledge the write, so failures are reported but each
+    /// write costs an extra round-trip. [`WriteType::WithoutResponse`] is faster, but a write that is dropped is lost
+    /// silently. That's fine for status polling, which is repeated constantly, but commands like brewing should always
+    /// be written with a response.
+    pub async fn write(&self, data: Vec<u8>, write_type: WriteType) -> Result<(), EcamError> {
+        trace_frame!(TraceDirection::HostToDevice, &data);
         Result::Ok(
             self.peripheral
-                .write(
-                    &self.characteristic,
-                    &data,
-                    btleplug::api::WriteType::WithResponse,
-                )
+                .write(&self.characteristic, &data, write_type)
                 .await?,
         )
     }
@@ -170,26 +481,29 @@ impl EcamPeripheral {
         Ok(self.peripheral.is_connected().await?)
     }
 
-    #[cfg(target_os = "macos")]
-    pub fn id(&self) -> String {
-        // Icky, but we don't have a PeripheralId to UUID function
-        println!("{:?}", self.peripheral.id());
-        format!("{:?}", self.peripheral.id())[13..49].to_owned()
+    pub async fn info(&self) -> Result<DeviceInfo, EcamError> {
+        let properties = self.peripheral.properties().await?.unwrap_or_default();
+        Ok(DeviceInfo {
+            name: properties
+                .local_name
+                .unwrap_or_else(|| self.local_name.clone()),
+            id: self.id(),
+            rssi: properties.rssi,
+            service_uuid: self.characteristic.service_uuid.to_string(),
+            characteristic_uuid: self.characteristic.uuid.to_string(),
+            percentage_scale: None,
+        })
     }
 
-    #[cfg(target_os = "linux")]
     pub fn id(&self) -> String {
-        self.peripheral.id().to_string()
+        peripheral_id(&self.peripheral)
     }
 
     pub async fn notifications(&self) -> Result<impl Stream<Item = EcamDriverOutput>, EcamError> {
-        trace_packet!("TRYING TO SUBSCRIBE...");
-        self.peripheral
-            .subscribe(&self.characteristic)
-            .await
-            .expect("Could not subscribe to characteristic");
-        trace_packet!("SUBSCRIBED");
-        trace_packet!("Is connected ? {:?}", self.peripheral.is_connected().await);
+        debug!("TRYING TO SUBSCRIBE...");
+        self.peripheral.subscribe(&self.characteristic).await?;
+        debug!("SUBSCRIBED");
+        debug!("Is connected ? {:?}", self.peripheral.is_connected().await);
         let peripheral = self.peripheral.clone();
         let (trigger, tripwire) = Tripwire::new();
         tokio::spawn(async move {
@@ -202,7 +516,7 @@ impl EcamPeripheral {
 
         // Raw stream of bytes from device
         let notifications = self.peripheral.notifications().await?.map(|m| m.value);
-        trace_packet!("GOT NOTIFICATIONS stream setup");
+        debug!("GOT NOTIFICATIONS stream setup");
         // Parse into packets and stop when device disconnected
         let n = packet_stream(notifications)
             .map(|v| EcamDriverOutput::Packet(EcamDriverPacket::from_slice(unwrap_packet(&v))))
@@ -210,20 +524,47 @@ impl EcamPeripheral {
         Ok(n)
     }
 
-    /// Assumes that a [`Peripheral`] is a valid ECAM, and connects to it.
+    /// Like [`EcamPeripheral::notifications`], but tries to reconnect to the same peripheral up to `max_attempts` times
+    /// when the connection drops. See [`with_reconnect`].
+    pub async fn notifications_with_reconnect(
+        &self,
+        max_attempts: usize,
+    ) -> Result<impl Stream<Item = EcamDriverOutput>, EcamError> {
+        let first: BoxedOutputStream = Box::pin(self.notifications().await?);
+        let peripheral = self.clone();
+        Ok(with_reconnect(
+            first,
+            move || {
+                let peripheral = peripheral.clone();
+                async move {
+                    peripheral.peripheral.connect().await?;
+                    peripheral.peripheral.discover_services().await?;
+                    let next: BoxedOutputStream = Box::pin(peripheral.notifications().await?);
+                    Ok(next)
+                }
+            },
+            max_attempts,
+        ))
+    }
+
+    /// Connects to a [`Peripheral`] that is expected to be an ECAM, failing with [`EcamError::NotFound`] if it doesn't
+    /// have a usable ECAM characteristic.
     pub async fn connect(peripheral: Peripheral) -> Result<Self, EcamError> {
         peripheral.connect().await?;
         peripheral.discover_services().await?;
-        let characteristic = Characteristic {
-            uuid: CHARACTERISTIC_UUID,
-            service_uuid: SERVICE_UUID,
-            properties: CharPropFlags::WRITE | CharPropFlags::READ | CharPropFlags::INDICATE,
-        };
+        let characteristic =
+            find_characteristic(peripheral.services()).ok_or(EcamError::NotFound)?;
+        let local_name = peripheral
+            .properties()
+            .await?
+            .and_then(|p| p.local_name)
+            .unwrap_or_else(|| peripheral_id(&peripheral));
 
         Ok(EcamPeripheral {
-            local_name: "unknown".to_owned(),
+            local_name,
             peripheral,
             characteristic,
+            monitor_write_type: WriteType::WithResponse,
         })
     }
 
@@ -238,19 +579,205 @@ impl EcamPeripheral {
             }
             peripheral.is_connected().await?;
             peripheral.discover_services().await?;
-            for service in peripheral.services() {
-                for characteristic in service.characteristics {
-                    if characteristic.uuid == CHARACTERISTIC_UUID {
-                        return Ok(Some(EcamPeripheral {
-                            local_name,
-                            peripheral,
-                            characteristic,
-                        }));
-                    }
-                }
-            }
-            return Ok(None);
+            return Ok(
+                find_characteristic(peripheral.services()).map(|characteristic| EcamPeripheral {
+                    local_name,
+                    peripheral,
+                    characteristic,
+                    monitor_write_type: WriteType::WithResponse,
+                }),
+            );
         }
         Ok(None)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[tokio::test(start_paused = true)]
+    async fn cancel_scan_mid_window() {
+        let (trigger, tripwire) = Tripwire::new();
+        let stopped = AtomicBool::new(false);
+        let start = tokio::time::Instant::now();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            trigger.cancel();
+        });
+        let window = async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Ok(())
+        };
+        let stop = async {
+            stopped.store(true, Ordering::SeqCst);
+        };
+        let res = until_cancelled(Some(tripwire), window, stop).await;
+        assert!(matches!(res, Err(EcamError::Cancelled)));
+        assert!(stopped.load(Ordering::SeqCst));
+        assert_eq!(start.elapsed(), Duration::from_millis(100));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn scan_completes_without_cancel() {
+        let (trigger, tripwire) = Tripwire::new();
+        let stopped = AtomicBool::new(false);
+        let window = async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Ok(1)
+        };
+        let stop = async {
+            stopped.store(true, Ordering::SeqCst);
+        };
+        let res = until_cancelled(Some(tripwire), window, stop).await;
+        assert_eq!(res.expect("Scan failed"), 1);
+        assert!(!stopped.load(Ordering::SeqCst));
+        drop(trigger);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reconnect_until_attempts_exhausted() {
+        fn packets(bytes: &'static [u8]) -> BoxedOutputStream {
+            Box::pin(futures::stream::iter(bytes.iter().map(|b| {
+                EcamDriverOutput::Packet(EcamDriverPacket::from_slice(&[*b]))
+            })))
+        }
+        let attempts = std::sync::atomic::AtomicUsize::new(0);
+        let reconnect = || {
+            // Only the second attempt succeeds
+            let attempt = attempts.fetch_add(1, Ordering::SeqCst);
+            async move {
+                if attempt == 1 {
+                    Ok(packets(&[2]))
+                } else {
+                    Err(EcamError::Timeout)
+                }
+            }
+        };
+        let start = tokio::time::Instant::now();
+        let output: Vec<_> = with_reconnect(packets(&[1]), reconnect, 2).collect().await;
+        assert_eq!(
+            output,
+            vec![
+                EcamDriverOutput::Packet(EcamDriverPacket::from_slice(&[1])),
+                EcamDriverOutput::Ready,
+                EcamDriverOutput::Packet(EcamDriverPacket::from_slice(&[2])),
+            ]
+        );
+        assert_eq!(attempts.load(Ordering::SeqCst), 4);
+        assert_eq!(start.elapsed(), RECONNECT_DELAY * 4);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reconnect_gives_up_on_fatal_error() {
+        let attempts = std::sync::atomic::AtomicUsize::new(0);
+        let reconnect = || {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            async { Err(EcamError::DeviceInUse) }
+        };
+        let first: BoxedOutputStream = Box::pin(futures::stream::empty());
+        let output: Vec<_> = with_reconnect(first, reconnect, 3).collect().await;
+        assert_eq!(output, vec![]);
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retry_scan_with_backoff() {
+        let start = tokio::time::Instant::now();
+        let mut scans = 0;
+        let found = retry_with_backoff(DEFAULT_SCAN_DEADLINE, || {
+            scans += 1;
+            let scans = scans;
+            async move { Ok((scans == 4).then_some(scans)) }
+        })
+        .await;
+        assert_eq!(found.expect("Scan failed"), 4);
+        // 250ms + 500ms + 1s
+        assert_eq!(start.elapsed(), Duration::from_millis(1750));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retry_scan_until_deadline() {
+        let start = tokio::time::Instant::now();
+        let found: Result<(), _> =
+            retry_with_backoff(Duration::from_secs(3), || async { Ok(None) }).await;
+        assert!(matches!(found, Err(EcamError::NotFound)));
+        assert_eq!(start.elapsed(), Duration::from_secs(3));
+    }
+
+    #[rstest::rstest]
+    #[case(DEFAULT_SCAN_TIMEOUT, 10)]
+    #[case(Duration::ZERO, 1)]
+    #[case(Duration::from_millis(1200), 3)]
+    #[case(Duration::from_secs(30), 60)]
+    fn scan_poll_count(#[case] scan_timeout: Duration, #[case] polls: u32) {
+        assert_eq!(scan_polls(scan_timeout), polls);
+    }
+
+    #[test]
+    fn find_ecam_characteristic() {
+        let service = |uuid, properties| Service {
+            uuid: SERVICE_UUID,
+            primary: true,
+            characteristics: [Characteristic {
+                uuid,
+                service_uuid: SERVICE_UUID,
+                properties,
+            }]
+            .into(),
+        };
+        let usable = CharPropFlags::WRITE | CharPropFlags::READ | CharPropFlags::INDICATE;
+        assert_eq!(
+            find_characteristic([service(CHARACTERISTIC_UUID, usable)]).map(|c| c.properties),
+            Some(usable)
+        );
+        assert_eq!(find_characteristic([service(SERVICE_UUID, usable)]), None);
+        assert_eq!(find_characteristic([]), None);
+        // The characteristic must be writable, and must be able to send us the device's responses
+        assert_eq!(
+            find_characteristic([service(
+                CHARACTERISTIC_UUID,
+                CharPropFlags::READ | CharPropFlags::INDICATE
+            )]),
+            None
+        );
+        assert_eq!(
+            find_characteristic([service(
+                CHARACTERISTIC_UUID,
+                CharPropFlags::WRITE | CharPropFlags::READ
+            )]),
+            None
+        );
+    }
+
+    #[test]
+    fn format_address_id() {
+        let address = btleplug::api::BDAddr::from([0x1f, 0x2a, 0x00, 0xcc, 0x22, 0xf1]);
+        assert_eq!(address_id(address), "1F:2A:00:CC:22:F1");
+    }
+
+    #[test]
+    fn monitor_write_type() {
+        let monitor = EcamPacket::from_represenation(Request::MonitorV2()).bytes;
+        let brew = EcamPacket::from_represenation(Request::BeverageDispensingMode(
+            EcamBeverageId::Cappuccino.into(),
+            EcamOperationTrigger::Start.into(),
+            vec![],
+            EcamBeverageTasteType::Prepare.into(),
+        ))
+        .bytes;
+        assert_eq!(
+            write_type_for(&monitor, WriteType::WithoutResponse),
+            WriteType::WithoutResponse
+        );
+        assert_eq!(
+            write_type_for(&brew, WriteType::WithoutResponse),
+            WriteType::WithResponse
+        );
+        assert_eq!(
+            write_type_for(&monitor, WriteType::WithResponse),
+            WriteType::WithResponse
+        );
+    }
+}
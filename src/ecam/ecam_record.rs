@@ -0,0 +1,270 @@
+//! Records the packets exchanged with a device to a file, and replays them later as a simulated device.
+
+use std::io::{BufRead, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+use crate::ecam::{
+    DeviceInfo, EcamDriver, EcamDriverOutput, EcamError, CHARACTERISTIC_UUID, SERVICE_UUID,
+};
+use crate::logging::TraceDirection;
+use crate::prelude::*;
+use crate::protocol::EcamDriverPacket;
+
+/// Something that happened while recording a session.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum RecordedEvent {
+    /// The device became ready.
+    Ready,
+    /// The device sent a packet, as hex.
+    Read { packet: String },
+    /// The host wrote a packet, as hex.
+    Write { packet: String },
+    /// The device went away.
+    Done,
+}
+
+/// One line of a recording: an event, and when it happened relative to the start of the recording.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RecordedEntry {
+    pub elapsed_ms: u64,
+    #[serde(flatten)]
+    pub event: RecordedEvent,
+}
+
+/// Wraps another driver, writing every packet read from or written to it to a file as newline-delimited JSON.
+pub struct EcamRecorder {
+    driver: Box<dyn EcamDriver>,
+    file: std::sync::Mutex<std::fs::File>,
+    start: Instant,
+}
+
+impl EcamRecorder {
+    /// Starts recording the given driver to a file, replacing the file if it exists.
+    pub fn new<P: AsRef<Path>>(driver: Box<dyn EcamDriver>, path: P) -> Result<Self, EcamError> {
+        Ok(EcamRecorder {
+            driver,
+            file: std::sync::Mutex::new(std::fs::File::create(path)?),
+            start: Instant::now(),
+        })
+    }
+
+    fn record(&self, event: RecordedEvent) -> Result<(), EcamError> {
+        let entry = RecordedEntry {
+            elapsed_ms: self.start.elapsed().as_millis() as u64,
+            event,
+        };
+        let line = serde_json::to_string(&entry).map_err(std::io::Error::from)?;
+        // Flush every entry so a crash still leaves a usable recording
+        let mut file = self.file.lock().map_err(|_| EcamError::Unknown)?;
+        writeln!(file, "{}", line)?;
+        file.flush()?;
+        Ok(())
+    }
+}
+
+impl EcamDriver for EcamRecorder {
+    fn read(&self) -> AsyncFuture<'_, Option<EcamDriverOutput>> {
+        Box::pin(async {
+            let output = self.driver.read().await?;
+            match &output {
+                Some(EcamDriverOutput::Ready) => self.record(RecordedEvent::Ready)?,
+                Some(EcamDriverOutput::Packet(packet)) => self.record(RecordedEvent::Read {
+                    packet: packet.stringify(),
+                })?,
+                Some(EcamDriverOutput::Done) | None => self.record(RecordedEvent::Done)?,
+            }
+            Ok(output)
+        })
+    }
+
+    fn write(&self, data: EcamDriverPacket) -> AsyncFuture<'_, ()> {
+        Box::pin(async move {
+            self.record(RecordedEvent::Write {
+                packet: data.stringify(),
+            })?;
+            self.driver.write(data).await
+        })
+    }
+
+    fn alive(&self) -> AsyncFuture<'_, bool> {
+        self.driver.alive()
+    }
+
+    fn info(&self) -> AsyncFuture<'_, DeviceInfo> {
+        self.driver.info()
+    }
+
+    fn scan<'a>() -> AsyncFuture<'a, (String, String)>
+    where
+        Self: Sized,
+    {
+        Box::pin(async { Err(EcamError::NotFound) })
+    }
+}
+
+/// Loads a recording written by [`EcamRecorder`].
+pub fn load_recording<P: AsRef<Path>>(path: P) -> Result<Vec<RecordedEntry>, EcamError> {
+    let file = std::io::BufReader::new(std::fs::File::open(path)?);
+    let mut entries = vec![];
+    for line in file.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        entries.push(serde_json::from_str(&line).map_err(std::io::Error::from)?);
+    }
+    Ok(entries)
+}
+
+struct EcamReplay {
+    rx: Mutex<tokio::sync::mpsc::Receiver<EcamDriverOutput>>,
+    name: String,
+}
+
+impl EcamDriver for EcamReplay {
+    fn read(&self) -> AsyncFuture<'_, Option<EcamDriverOutput>> {
+        Box::pin(async { Ok(self.rx.lock().await.recv().await) })
+    }
+
+    fn write(&self, data: EcamDriverPacket) -> AsyncFuture<'_, ()> {
+        // The recording already contains the device's responses, so writes go nowhere
+        trace_frame!(TraceDirection::HostToDevice, &data.packetize(), "replay");
+        Box::pin(async { Ok(()) })
+    }
+
+    fn alive(&self) -> AsyncFuture<'_, bool> {
+        Box::pin(async { Ok(true) })
+    }
+
+    fn info(&self) -> AsyncFuture<'_, DeviceInfo> {
+        Box::pin(async {
+            Ok(DeviceInfo {
+                name: self.name.clone(),
+                id: "00000000-0000-0000-0000-000000000000".to_owned(),
+                rssi: None,
+                service_uuid: SERVICE_UUID.to_string(),
+                characteristic_uuid: CHARACTERISTIC_UUID.to_string(),
+                percentage_scale: None,
+            })
+        })
+    }
+
+    fn scan<'a>() -> AsyncFuture<'a, (String, String)>
+    where
+        Self: Sized,
+    {
+        Box::pin(async { Err(EcamError::NotFound) })
+    }
+}
+
+/// Creates a simulated device that replays the device's side of a recording written by [`EcamRecorder`], honoring
+/// the original timing. The host's writes are ignored.
+pub async fn get_ecam_replay<P: AsRef<Path>>(path: P) -> Result<impl EcamDriver, EcamError> {
+    let name = format!("replay:{}", path.as_ref().display());
+    let entries = load_recording(path)?;
+    let (tx, rx) = tokio::sync::mpsc::channel(1);
+    trace_packet!("Initializing replay: {}", name);
+    tokio::spawn(async move {
+        let start = Instant::now();
+        for entry in entries {
+            let output = match entry.event {
+                RecordedEvent::Ready => EcamDriverOutput::Ready,
+                RecordedEvent::Read { packet } => match hex::decode(&packet) {
+                    Ok(bytes) => EcamDriverOutput::Packet(EcamDriverPacket::from_vec(bytes)),
+                    Err(_) => {
+                        warning!("Skipping malformed packet in recording: {}", packet);
+                        continue;
+                    }
+                },
+                RecordedEvent::Write { .. } => continue,
+                RecordedEvent::Done => break,
+            };
+            tokio::time::sleep_until(start + Duration::from_millis(entry.elapsed_ms)).await;
+            if tx.send(output).await.is_err() {
+                return;
+            }
+        }
+        let _ = tx.send(EcamDriverOutput::Done).await;
+        trace_shutdown!("EcamReplay");
+    });
+    Ok(EcamReplay {
+        rx: Mutex::new(rx),
+        name,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ecam::{get_ecam_simulator, Ecam, EcamStatus};
+
+    fn recording_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!(
+            "longshot-test-{}-{}.jsonl",
+            name,
+            std::process::id()
+        ))
+    }
+
+    #[test]
+    fn serialize_entry() {
+        let entry = RecordedEntry {
+            elapsed_ms: 250,
+            event: RecordedEvent::Read {
+                packet: "75f0".to_owned(),
+            },
+        };
+        let json = serde_json::to_string(&entry).expect("Failed to serialize");
+        assert_eq!(json, r#"{"elapsed_ms":250,"event":"read","packet":"75f0"}"#);
+        assert_eq!(
+            serde_json::from_str::<RecordedEntry>(&json).expect("Failed to deserialize"),
+            entry
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn record_and_replay() -> Result<(), EcamError> {
+        let path = recording_path("record");
+        let recorder = EcamRecorder::new(Box::new(get_ecam_simulator("sim[on]").await?), &path)?;
+        let ecam = Ecam::new(Box::new(recorder), false).await;
+        ecam.wait_for_state(EcamStatus::Busy(8), |_| {}).await?;
+        drop(ecam);
+
+        // Load the recording alongside the replay, in case the recorder is still finishing up
+        let replay = get_ecam_replay(&path).await?;
+        let entries = load_recording(&path)?;
+        assert_eq!(entries[0].event, RecordedEvent::Ready);
+        assert!(entries
+            .iter()
+            .any(|e| matches!(e.event, RecordedEvent::Write { .. })));
+
+        // The replay reports the same packets at the same times
+        let start = Instant::now();
+        assert_eq!(replay.read().await?, Some(EcamDriverOutput::Ready));
+        for entry in entries {
+            let packet = match entry.event {
+                RecordedEvent::Read { packet } => packet,
+                _ => continue,
+            };
+            assert_eq!(
+                replay.read().await?,
+                Some(EcamDriverOutput::Packet(EcamDriverPacket::from_vec(
+                    hex::decode(packet).expect("Invalid hex")
+                )))
+            );
+            assert_eq!(start.elapsed().as_millis() as u64, entry.elapsed_ms);
+        }
+        assert_eq!(replay.read().await?, Some(EcamDriverOutput::Done));
+        assert_eq!(
+            replay.info().await?.name,
+            format!("replay:{}", path.display())
+        );
+        let _ = std::fs::remove_file(&path);
+        Ok(())
+    }
+}
@@ -1,17 +1,206 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
 use tokio::sync::Mutex;
 
-use crate::ecam::{EcamDriver, EcamDriverOutput, EcamError};
+use crate::ecam::{
+    DeviceInfo, EcamDriver, EcamDriverOutput, EcamError, CHARACTERISTIC_UUID, SERVICE_UUID,
+};
+use crate::logging::TraceDirection;
 use crate::prelude::*;
 use crate::protocol::{
-    hexdump, EcamAccessory, EcamBeverageId, EcamDriverPacket, EcamMachineState, EcamMachineSwitch,
-    EcamRequestId, MonitorV2Response, PartialEncode, SwitchSet,
+    checksum, EcamAccessory, EcamBeverageId, EcamDriverPacket, EcamMachineAlarm, EcamMachineState,
+    EcamMachineSwitch, EcamRequestId, MachineEnumerable, MonitorV2Response, PartialEncode,
+    PercentageScale, SwitchSet, PARAMETER_CLOCK, PARAMETER_CUP_WARMER, PARAMETER_ENERGY_SAVING,
+    PARAMETER_STARTUP_RINSE,
 };
 
 struct EcamSimulate {
     rx: Mutex<tokio::sync::mpsc::Receiver<EcamDriverOutput>>,
     tx: Mutex<tokio::sync::mpsc::Sender<EcamDriverOutput>>,
+    requests: tokio::sync::mpsc::UnboundedSender<Vec<u8>>,
+    parameters: Arc<std::sync::Mutex<HashMap<u16, Vec<u8>>>>,
+    restricted: bool,
+    variants: bool,
+    no_recipes: bool,
+    name: String,
+    log: SimulatorLog,
+    fail_writes: Arc<AtomicBool>,
+}
+
+/// One step of a scripted simulation, see [`get_ecam_simulator_scripted`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SimulatorStep {
+    /// Report the given status a number of times, one status update at a time.
+    Status(MonitorV2Response, usize),
+    /// Keep reporting the last status until the host writes a request with the given ID.
+    WaitForRequest(EcamRequestId),
+    /// Report nothing for the given duration, as a stalled machine might.
+    Delay(Duration),
+    /// Disconnect abruptly, skipping the rest of the script.
+    Disconnect,
+    /// Fail (or stop failing) every write from the host.
+    FailWrites(bool),
+}
+
+impl SimulatorStep {
+    /// Report a status with the given state, progress and percentage a number of times.
+    pub fn status(state: EcamMachineState, progress: u8, percentage: u8, count: usize) -> Self {
+        Self::Status(
+            MonitorV2Response {
+                state: state.into(),
+                switches: SwitchSet::of(&[EcamMachineSwitch::WaterSpout]),
+                progress,
+                percentage,
+                ..Default::default()
+            },
+            count,
+        )
+    }
 }
 
+/// One entry of a simulator script file, see [`load_script`].
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ScriptEntry {
+    Status {
+        state: String,
+        accessory: Option<String>,
+        #[serde(default)]
+        progress: u8,
+        #[serde(default)]
+        percentage: u8,
+        #[serde(default)]
+        alarms: Vec<String>,
+        #[serde(default = "default_count")]
+        count: usize,
+    },
+    Delay {
+        delay_ms: u64,
+    },
+    WaitFor {
+        wait_for: String,
+    },
+    Fault {
+        fault: String,
+    },
+}
+
+fn default_count() -> usize {
+    1
+}
+
+fn lookup<T: MachineEnumerable<T>>(what: &str, name: &str) -> Result<T, EcamError> {
+    T::lookup_by_name_case_insensitive(name).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Unknown {} '{}' in simulator script", what, name),
+        )
+        .into()
+    })
+}
+
+impl TryFrom<ScriptEntry> for SimulatorStep {
+    type Error = EcamError;
+
+    fn try_from(entry: ScriptEntry) -> Result<Self, Self::Error> {
+        Ok(match entry {
+            ScriptEntry::Status {
+                state,
+                accessory,
+                progress,
+                percentage,
+                alarms,
+                count,
+            } => {
+                let state: EcamMachineState = lookup("state", &state)?;
+                let accessory: EcamAccessory = match accessory {
+                    Some(accessory) => lookup("accessory", &accessory)?,
+                    None => EcamAccessory::None,
+                };
+                let alarms = alarms
+                    .iter()
+                    .map(|alarm| lookup("alarm", alarm))
+                    .collect::<Result<Vec<EcamMachineAlarm>, _>>()?;
+                Self::Status(
+                    MonitorV2Response {
+                        state: state.into(),
+                        accessory: accessory.into(),
+                        switches: SwitchSet::of(&[EcamMachineSwitch::WaterSpout]),
+                        alarms: SwitchSet::of(&alarms),
+                        progress,
+                        percentage,
+                        ..Default::default()
+                    },
+                    count,
+                )
+            }
+            ScriptEntry::Delay { delay_ms } => Self::Delay(Duration::from_millis(delay_ms)),
+            ScriptEntry::Fault { fault } => match fault.as_str() {
+                "disconnect" => Self::Disconnect,
+                "fail-writes" => Self::FailWrites(true),
+                "recover-writes" => Self::FailWrites(false),
+                _ => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("Unknown fault '{}' in simulator script", fault),
+                    )
+                    .into())
+                }
+            },
+            ScriptEntry::WaitFor { wait_for } => {
+                Self::WaitForRequest(lookup("request", &wait_for)?)
+            }
+        })
+    }
+}
+
+/// Loads a simulator script from a JSON file. The script is an array of entries, each of which is one of:
+///
+///  - `{"state": "ReadyOrDispensing", "progress": 1, "percentage": 50, "count": 4}`: report a status `count` times
+///    (defaults to once), optionally with an `"accessory"` and a list of `"alarms"`
+///  - `{"delay_ms": 5000}`: report nothing for a while
+///  - `{"wait_for": "BeverageDispensingMode"}`: keep reporting the last status until the host sends this request
+///  - `{"fault": "disconnect"}`: disconnect abruptly, ignoring the rest of the script
+///  - `{"fault": "fail-writes"}`/`{"fault": "recover-writes"}`: start/stop failing every write from the host
+///
+/// Names are matched case-insensitively against [`EcamMachineState`], [`EcamAccessory`], [`EcamMachineAlarm`] and
+/// [`EcamRequestId`].
+pub fn load_script<P: AsRef<std::path::Path>>(path: P) -> Result<Vec<SimulatorStep>, EcamError> {
+    let file = std::fs::File::open(path)?;
+    let entries: Vec<ScriptEntry> =
+        serde_json::from_reader(std::io::BufReader::new(file)).map_err(std::io::Error::from)?;
+    entries.into_iter().map(SimulatorStep::try_from).collect()
+}
+
+/// The requests written to a simulator, in order.
+#[derive(Clone, Default)]
+pub struct SimulatorLog(Arc<std::sync::Mutex<Vec<Vec<u8>>>>);
+
+impl SimulatorLog {
+    /// Returns the encoded requests written to the simulator so far.
+    pub fn requests(&self) -> Vec<Vec<u8>> {
+        self.0.lock().map(|log| log.clone()).unwrap_or_default()
+    }
+
+    fn push(&self, request: Vec<u8>) {
+        if let Ok(mut log) = self.0.lock() {
+            log.push(request);
+        }
+    }
+}
+
+/// The beverages made by the `[restricted]` simulator.
+const RESTRICTED_BEVERAGES: [EcamBeverageId; 3] = [
+    EcamBeverageId::EspressoCoffee,
+    EcamBeverageId::RegularCoffee,
+    EcamBeverageId::HotWater,
+];
+
+/// The second variant of the cappuccino recipe stored by the `[variants]` simulator: more coffee, less milk and a
+/// stronger taste.
+const CAPPUCCINO_VARIANT: &str = "0100500900a002040c001b0419011c02";
+
 /// These are the recipes the simulator will make
 fn get_recipes(beverage: EcamBeverageId) -> Option<(Vec<u8>, Vec<u8>)> {
     use EcamBeverageId::*;
@@ -113,6 +302,54 @@ fn get_recipes(beverage: EcamBeverageId) -> Option<(Vec<u8>, Vec<u8>)> {
     ))
 }
 
+impl EcamSimulate {
+    fn new(
+        simulator: &str,
+        rx: tokio::sync::mpsc::Receiver<EcamDriverOutput>,
+        tx: tokio::sync::mpsc::Sender<EcamDriverOutput>,
+        requests: tokio::sync::mpsc::UnboundedSender<Vec<u8>>,
+    ) -> Self {
+        let mut parameters = HashMap::new();
+        if !simulator.contains("[no-rtc]") {
+            parameters.insert(PARAMETER_CLOCK, vec![8, 0]);
+        }
+        parameters.insert(PARAMETER_ENERGY_SAVING, vec![0, 1]);
+        if !simulator.contains("[no-cup-warmer]") {
+            parameters.insert(PARAMETER_CUP_WARMER, vec![0, 0]);
+        }
+        if !simulator.contains("[no-startup-rinse]") {
+            parameters.insert(PARAMETER_STARTUP_RINSE, vec![0, 1]);
+        }
+        EcamSimulate {
+            rx: Mutex::new(rx),
+            tx: Mutex::new(tx),
+            requests,
+            parameters: Arc::new(std::sync::Mutex::new(parameters)),
+            restricted: simulator.contains("[restricted]"),
+            variants: simulator.contains("[variants]"),
+            no_recipes: simulator.contains("[no-recipes]"),
+            name: simulator.to_owned(),
+            log: Default::default(),
+            fail_writes: Default::default(),
+        }
+    }
+
+    fn get_recipes(&self, beverage: EcamBeverageId, variant: u8) -> Option<(Vec<u8>, Vec<u8>)> {
+        if self.restricted && !RESTRICTED_BEVERAGES.contains(&beverage) {
+            return None;
+        }
+        let (recipe, minmax) = get_recipes(beverage)?;
+        match variant {
+            1 => Some((recipe, minmax)),
+            2 if self.variants && beverage == EcamBeverageId::Cappuccino => Some((
+                hex::decode(CAPPUCCINO_VARIANT).expect("Failed to decode constant"),
+                minmax,
+            )),
+            _ => None,
+        }
+    }
+}
+
 impl EcamDriver for EcamSimulate {
     fn read(&self) -> AsyncFuture<Option<EcamDriverOutput>> {
         Box::pin(async {
@@ -122,12 +359,28 @@ impl EcamDriver for EcamSimulate {
     }
 
     fn write(&self, data: crate::protocol::EcamDriverPacket) -> AsyncFuture<()> {
-        trace_packet!("{{host->device}} {}", hexdump(&data.bytes));
+        trace_frame!(TraceDirection::HostToDevice, &data.packetize());
         Box::pin(async move {
+            if self.fail_writes.load(Ordering::SeqCst) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::BrokenPipe,
+                    "simulated write failure",
+                )
+                .into());
+            }
+            self.log.push(data.bytes.clone());
+            // The simulation may be waiting on this request, but it's fine if it's no longer listening
+            let _ = self.requests.send(data.bytes.clone());
+            if self.no_recipes
+                && (data.bytes[0] == EcamRequestId::RecipeQuantityRead as u8
+                    || data.bytes[0] == EcamRequestId::RecipeMinMaxSync as u8)
+            {
+                return Ok(());
+            }
             if data.bytes[0] == EcamRequestId::RecipeQuantityRead as u8 {
-                let mut packet = vec![data.bytes[0], 0xf0, 1, data.bytes[3]];
+                let mut packet = vec![data.bytes[0], 0xf0, data.bytes[2], data.bytes[3]];
                 if let Ok(beverage) = data.bytes[3].try_into() {
-                    if let Some((recipe, _)) = get_recipes(beverage) {
+                    if let Some((recipe, _)) = self.get_recipes(beverage, data.bytes[2]) {
                         packet = [packet, recipe].concat();
                     }
                 }
@@ -136,12 +389,35 @@ impl EcamDriver for EcamSimulate {
             if data.bytes[0] == EcamRequestId::RecipeMinMaxSync as u8 {
                 let mut packet = vec![data.bytes[0], 0xf0, data.bytes[2]];
                 if let Ok(beverage) = data.bytes[2].try_into() {
-                    if let Some((_, minmax)) = get_recipes(beverage) {
+                    if let Some((_, minmax)) = self.get_recipes(beverage, 1) {
                         packet = [packet, minmax].concat();
                     }
                 }
                 send(&*self.tx.lock().await, packet).await?;
             }
+            if data.bytes[0] == EcamRequestId::ParameterRead as u8
+                || data.bytes[0] == EcamRequestId::ParameterReadExt as u8
+            {
+                // Unknown parameters are returned without any data
+                let param = u16::from_be_bytes([data.bytes[2], data.bytes[3]]);
+                let value = self
+                    .parameters
+                    .lock()
+                    .map_err(eat_errors_with_warning)?
+                    .get(&param)
+                    .cloned()
+                    .unwrap_or_default();
+                let packet = vec![data.bytes[0], 0xf0, data.bytes[2], data.bytes[3]];
+                send(&*self.tx.lock().await, [packet, value].concat()).await?;
+            }
+            if data.bytes[0] == EcamRequestId::ParameterWrite as u8 {
+                let param = u16::from_be_bytes([data.bytes[2], data.bytes[3]]);
+                self.parameters
+                    .lock()
+                    .map_err(eat_errors_with_warning)?
+                    .insert(param, data.bytes[4..].to_vec());
+                send(&*self.tx.lock().await, vec![data.bytes[0], 0xf0]).await?;
+            }
             Ok(())
         })
     }
@@ -150,6 +426,19 @@ impl EcamDriver for EcamSimulate {
         Box::pin(async { Ok(true) })
     }
 
+    fn info(&self) -> AsyncFuture<'_, DeviceInfo> {
+        Box::pin(async {
+            Ok(DeviceInfo {
+                name: self.name.clone(),
+                id: "00000000-0000-0000-0000-000000000000".to_owned(),
+                rssi: Some(-50),
+                service_uuid: SERVICE_UUID.to_string(),
+                characteristic_uuid: CHARACTERISTIC_UUID.to_string(),
+                percentage_scale: Some(simulated_percentage_scale(&self.name)),
+            })
+        })
+    }
+
     fn scan<'a>() -> AsyncFuture<'a, (String, String)>
     where
         Self: Sized,
@@ -159,21 +448,40 @@ impl EcamDriver for EcamSimulate {
 }
 
 /// Create a Vec<u8> that mocks a machine response.
-fn make_simulated_response(state: EcamMachineState, progress: u8, percentage: u8) -> Vec<u8> {
-    let mut v = vec![EcamRequestId::MonitorV2.into(), 0xf0];
-    v.extend_from_slice(
-        &MonitorV2Response {
-            state: state.into(),
-            accessory: EcamAccessory::None.into(),
-            switches: SwitchSet::of(&[EcamMachineSwitch::WaterSpout]),
-            alarms: SwitchSet::empty(),
-            progress,
-            percentage,
-            ..Default::default()
-        }
-        .encode(),
-    );
-    v
+fn make_simulated_response(
+    state: EcamMachineState,
+    accessory: EcamAccessory,
+    progress: u8,
+    percentage: u8,
+) -> Vec<u8> {
+    make_simulated_alarm_response(state, accessory, progress, percentage, &[])
+}
+
+fn make_simulated_alarm_response(
+    state: EcamMachineState,
+    accessory: EcamAccessory,
+    progress: u8,
+    percentage: u8,
+    alarms: &[EcamMachineAlarm],
+) -> Vec<u8> {
+    encode_status(&MonitorV2Response {
+        state: state.into(),
+        accessory: accessory.into(),
+        switches: SwitchSet::of(&[EcamMachineSwitch::WaterSpout]),
+        alarms: SwitchSet::of(alarms),
+        progress,
+        percentage,
+        ..Default::default()
+    })
+}
+
+/// Encodes a status response as the machine would send it.
+fn encode_status(response: &MonitorV2Response) -> Vec<u8> {
+    [
+        vec![EcamRequestId::MonitorV2.into(), 0xf0],
+        response.encode(),
+    ]
+    .concat()
 }
 
 fn eat_errors_with_warning<T: std::fmt::Debug>(e: T) -> EcamError {
@@ -192,65 +500,313 @@ async fn send(
     tx: &tokio::sync::mpsc::Sender<EcamDriverOutput>,
     v: Vec<u8>,
 ) -> Result<(), EcamError> {
-    trace_packet!("{}", hexdump(&v));
+    // Trace the frame as the device would send it
+    let mut frame = vec![0xd0, (v.len() + 3) as u8];
+    frame.extend_from_slice(&v);
+    frame.extend_from_slice(&checksum(&frame));
+    trace_frame!(TraceDirection::DeviceToHost, &frame);
     send_output(tx, EcamDriverOutput::Packet(EcamDriverPacket::from_vec(v))).await
 }
 
+/// Drains the requests written to the simulator, returning true if any of them had the given request ID.
+fn received(
+    requests: &mut tokio::sync::mpsc::UnboundedReceiver<Vec<u8>>,
+    id: EcamRequestId,
+) -> bool {
+    let mut found = false;
+    while let Ok(request) = requests.try_recv() {
+        found |= request.first() == Some(&id.into());
+    }
+    found
+}
+
+const DELAY: Duration = Duration::from_millis(250);
+
+/// Returns the canned script for a simulator that injects a fault, if the name is one of:
+///
+///  - `sim-disconnect`: the machine disconnects abruptly partway through a beverage
+///  - `sim-noready`: the machine starts turning on when asked, but never becomes ready
+///  - `sim-nowater`: the water tank runs dry partway through a beverage
+///  - `sim-writefail`: the machine reports that it is ready, but every write to it fails
+pub fn fault_script(simulator: &str) -> Option<Vec<SimulatorStep>> {
+    use EcamMachineState::*;
+    let ready_to_brew = vec![
+        SimulatorStep::status(ReadyOrDispensing, 0, 0, 3),
+        SimulatorStep::WaitForRequest(EcamRequestId::BeverageDispensingMode),
+    ];
+    let dispensing: Vec<_> = (1..=6)
+        .map(|i| SimulatorStep::status(ReadyOrDispensing, i, i * 4, 1))
+        .collect();
+    let script = match simulator {
+        "sim-disconnect" => [ready_to_brew, dispensing, vec![SimulatorStep::Disconnect]].concat(),
+        "sim-noready" => vec![
+            SimulatorStep::status(StandBy, 0, 0, 1),
+            SimulatorStep::WaitForRequest(EcamRequestId::AppControl),
+            SimulatorStep::status(TurningOn, 0, 20, usize::MAX),
+        ],
+        "sim-nowater" => {
+            let empty = MonitorV2Response {
+                state: ReadyOrDispensing.into(),
+                switches: SwitchSet::of(&[EcamMachineSwitch::WaterSpout]),
+                alarms: SwitchSet::of(&[EcamMachineAlarm::EmptyWaterTank]),
+                ..Default::default()
+            };
+            [
+                ready_to_brew,
+                dispensing,
+                vec![SimulatorStep::Status(empty, 10)],
+            ]
+            .concat()
+        }
+        "sim-writefail" => vec![
+            SimulatorStep::FailWrites(true),
+            SimulatorStep::status(ReadyOrDispensing, 0, 0, 20),
+        ],
+        _ => return None,
+    };
+    Some(script)
+}
+
+/// Creates a simulated device that follows a script, returning it along with a log of the requests written to it. The
+/// simulator reports that it is done once the script completes.
+pub async fn get_ecam_simulator_scripted(
+    script: Vec<SimulatorStep>,
+) -> Result<(impl EcamDriver, SimulatorLog), EcamError> {
+    run_script("sim[scripted]", script).await
+}
+
+async fn run_script(
+    name: &str,
+    script: Vec<SimulatorStep>,
+) -> Result<(EcamSimulate, SimulatorLog), EcamError> {
+    let (tx, rx) = tokio::sync::mpsc::channel(1);
+    send_output(&tx, EcamDriverOutput::Ready).await?;
+    let tx_out = tx.clone();
+    let (requests, mut requests_rx) = tokio::sync::mpsc::unbounded_channel();
+    trace_packet!("Initializing scripted simulator: {}", name);
+    let simulator = EcamSimulate::new(name, rx, tx_out, requests);
+    let fail_writes = simulator.fail_writes.clone();
+    tokio::spawn(async move {
+        let mut last = None;
+        for step in script {
+            match step {
+                SimulatorStep::Status(response, count) => {
+                    for _ in 0..count {
+                        send(&tx, encode_status(&response)).await?;
+                        tokio::time::sleep(DELAY).await;
+                    }
+                    last = Some(response);
+                }
+                SimulatorStep::WaitForRequest(id) => {
+                    while !received(&mut requests_rx, id) {
+                        if let Some(response) = &last {
+                            send(&tx, encode_status(response)).await?;
+                        }
+                        tokio::time::sleep(DELAY).await;
+                    }
+                }
+                SimulatorStep::Delay(duration) => {
+                    tokio::time::sleep(duration).await;
+                }
+                SimulatorStep::Disconnect => break,
+                SimulatorStep::FailWrites(fail) => {
+                    fail_writes.store(fail, Ordering::SeqCst);
+                }
+            }
+        }
+
+        send_output(&tx, EcamDriverOutput::Done).await?;
+
+        trace_shutdown!("EcamSimulate (scripted)");
+        Result::<(), EcamError>::Ok(())
+    });
+    let log = simulator.log.clone();
+    Ok((simulator, log))
+}
+
+/// The scale the simulator with the given name reports percentages on.
+fn simulated_percentage_scale(simulator: &str) -> PercentageScale {
+    if simulator.contains("[byte-percent]") {
+        PercentageScale::Byte
+    } else {
+        PercentageScale::Percent
+    }
+}
+
+/// Creates a simulated device. The name of the simulator selects the profile it runs:
+///
+///  - `[on]`: the machine starts already turned on
+///  - `[no-rtc]`: the machine has no clock
+///  - `[no-cup-warmer]`: the machine has no cup warmer
+///  - `[restricted]`: the machine only makes espresso, regular coffee and hot water
+///  - `[variants]`: the machine stores a second variant of the cappuccino recipe
+///  - `[no-recipes]`: the machine doesn't answer recipe requests
+///  - `[no-startup-rinse]`: the machine has no startup rinse setting
+///  - `[cold]`: the machine stays in standby until it is turned on, reports a brief ready state before running its
+///    startup rinse (unless the startup rinse setting is turned off), and only dispenses once a beverage is requested
+///  - `[milk-detach]`: the milk carafe is attached, but is detached halfway through dispensing
+///  - `[grounds-full]`: the coffee grounds container fills up halfway through dispensing
+///  - `[byte-percent]`: percentages are reported from 0 to 255
+///
+/// A simulator named `sim:<path>` instead replays the script at the given path, see [`load_script`]. The simulators
+/// named in [`fault_script`] replay canned scripts that misbehave in various ways.
 pub async fn get_ecam_simulator(simulator: &str) -> Result<impl EcamDriver, EcamError> {
+    if let Some(path) = simulator.strip_prefix("sim:") {
+        let (simulate, _) = run_script(simulator, load_script(path)?).await?;
+        return Ok(simulate);
+    }
+    if let Some(script) = fault_script(simulator) {
+        let (simulate, _) = run_script(simulator, script).await?;
+        return Ok(simulate);
+    }
     let (tx, rx) = tokio::sync::mpsc::channel(1);
-    const DELAY: Duration = Duration::from_millis(250);
     send_output(&tx, EcamDriverOutput::Ready).await?;
     let tx_out = tx.clone();
-    let on = simulator.ends_with("[on]");
+    let (requests, mut requests_rx) = tokio::sync::mpsc::unbounded_channel();
+    let on = simulator.contains("[on]");
+    let cold = simulator.contains("[cold]");
+    let milk_detach = simulator.contains("[milk-detach]");
+    let grounds_full = simulator.contains("[grounds-full]");
+    let scale = simulated_percentage_scale(simulator);
+    let status = move |state, accessory, progress, percentage| {
+        make_simulated_response(state, accessory, progress, scale.from_percent(percentage))
+    };
+    let accessory = if milk_detach {
+        EcamAccessory::Milk
+    } else {
+        EcamAccessory::None
+    };
     trace_packet!("Initializing simulator: {}", simulator);
+    let simulate = EcamSimulate::new(simulator, rx, tx_out, requests);
+    let parameters = simulate.parameters.clone();
     tokio::spawn(async move {
-        if !on {
-            // Start in standby
-            for _ in 0..5 {
+        if cold {
+            // Standby until we're asked to turn on
+            while !received(&mut requests_rx, EcamRequestId::AppControl) {
+                send(&tx, status(EcamMachineState::StandBy, accessory, 0, 0)).await?;
+                tokio::time::sleep(DELAY).await;
+            }
+
+            // Turning on
+            for i in 0..5 {
                 send(
                     &tx,
-                    make_simulated_response(EcamMachineState::StandBy, 0, 0),
+                    status(EcamMachineState::TurningOn, accessory, 0, i * 20),
                 )
                 .await?;
                 tokio::time::sleep(DELAY).await;
             }
 
-            // Turning on
-            for i in 0..5 {
+            let startup_rinse = !matches!(
+                parameters
+                    .lock()
+                    .map_err(eat_errors_with_warning)?
+                    .get(&PARAMETER_STARTUP_RINSE)
+                    .map(Vec::as_slice),
+                Some([0, 0])
+            );
+            if startup_rinse {
+                // A single ready report before the startup rinse
                 send(
                     &tx,
-                    make_simulated_response(EcamMachineState::TurningOn, 0, i * 20),
+                    status(EcamMachineState::ReadyOrDispensing, accessory, 0, 0),
                 )
                 .await?;
                 tokio::time::sleep(DELAY).await;
+
+                // Startup rinse: the machine ignores requests while rinsing
+                for i in 0..8 {
+                    send(&tx, status(EcamMachineState::Rinsing, accessory, 0, i * 12)).await?;
+                    tokio::time::sleep(DELAY).await;
+                }
+                received(&mut requests_rx, EcamRequestId::BeverageDispensingMode);
             }
-        }
 
-        // Ready
-        for _ in 0..3 {
-            send(
-                &tx,
-                make_simulated_response(EcamMachineState::ReadyOrDispensing, 0, 0),
-            )
-            .await?;
-            tokio::time::sleep(DELAY).await;
+            // Ready until we're asked to brew
+            while !received(&mut requests_rx, EcamRequestId::BeverageDispensingMode) {
+                send(
+                    &tx,
+                    status(EcamMachineState::ReadyOrDispensing, accessory, 0, 0),
+                )
+                .await?;
+                tokio::time::sleep(DELAY).await;
+            }
+        } else {
+            if !on {
+                // Start in standby
+                for _ in 0..5 {
+                    send(&tx, status(EcamMachineState::StandBy, accessory, 0, 0)).await?;
+                    tokio::time::sleep(DELAY).await;
+                }
+
+                // Turning on
+                for i in 0..5 {
+                    send(
+                        &tx,
+                        status(EcamMachineState::TurningOn, accessory, 0, i * 20),
+                    )
+                    .await?;
+                    tokio::time::sleep(DELAY).await;
+                }
+            }
+
+            // Ready
+            for _ in 0..3 {
+                send(
+                    &tx,
+                    status(EcamMachineState::ReadyOrDispensing, accessory, 0, 0),
+                )
+                .await?;
+                tokio::time::sleep(DELAY).await;
+            }
         }
 
         // Dispensing
         for i in 0..25 {
+            if grounds_full && i >= 12 {
+                // The machine stops dispensing and reports the alarm until the container is emptied
+                for _ in 0..10 {
+                    send(
+                        &tx,
+                        make_simulated_alarm_response(
+                            EcamMachineState::ReadyOrDispensing,
+                            accessory,
+                            0,
+                            0,
+                            &[EcamMachineAlarm::CoffeeWasteContainerFull],
+                        ),
+                    )
+                    .await?;
+                    tokio::time::sleep(DELAY).await;
+                }
+                send_output(&tx, EcamDriverOutput::Done).await?;
+
+                trace_shutdown!("EcamSimulate");
+                return Result::<(), EcamError>::Ok(());
+            }
+            let accessory = if milk_detach && i >= 12 {
+                EcamAccessory::None
+            } else {
+                accessory
+            };
             send(
                 &tx,
-                make_simulated_response(EcamMachineState::ReadyOrDispensing, i, i * 4),
+                status(EcamMachineState::ReadyOrDispensing, accessory, i, i * 4),
             )
             .await?;
             tokio::time::sleep(DELAY).await;
         }
 
         // Ready forever
+        let accessory = if milk_detach {
+            EcamAccessory::None
+        } else {
+            accessory
+        };
         for _ in 0..10 {
             send(
                 &tx,
-                make_simulated_response(EcamMachineState::ReadyOrDispensing, 0, 0),
+                status(EcamMachineState::ReadyOrDispensing, accessory, 0, 0),
             )
             .await?;
             tokio::time::sleep(DELAY).await;
@@ -261,8 +817,173 @@ pub async fn get_ecam_simulator(simulator: &str) -> Result<impl EcamDriver, Ecam
         trace_shutdown!("EcamSimulate");
         Result::<(), EcamError>::Ok(())
     });
-    Ok(EcamSimulate {
-        rx: Mutex::new(rx),
-        tx: Mutex::new(tx_out),
-    })
+    Ok(simulate)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ecam::{Ecam, EcamStatus};
+    use crate::protocol::{AppControl, EcamBeverageTasteType, EcamOperationTrigger, Request};
+
+    fn brew_request() -> Request {
+        Request::BeverageDispensingMode(
+            EcamBeverageId::RegularCoffee.into(),
+            EcamOperationTrigger::Start.into(),
+            vec![],
+            EcamBeverageTasteType::Prepare.into(),
+        )
+    }
+
+    async fn fault_simulator(name: &str) -> Result<Ecam, EcamError> {
+        Ok(Ecam::new(Box::new(get_ecam_simulator(name).await?), false).await)
+    }
+
+    fn write_script(name: &str, script: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "longshot-test-{}-{}.json",
+            name,
+            std::process::id()
+        ));
+        std::fs::write(&path, script).expect("Failed to write script");
+        path
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn scripted_from_file() -> Result<(), EcamError> {
+        let path = write_script(
+            "stall",
+            r#"[
+                {"state": "StandBy", "count": 2},
+                {"state": "turningon", "percentage": 50},
+                {"delay_ms": 10000},
+                {"state": "ReadyOrDispensing", "count": 2},
+                {"state": "ReadyOrDispensing", "count": 2, "alarms": ["EmptyWaterTank"]}
+            ]"#,
+        );
+        let name = format!("sim:{}", path.display());
+        let ecam = Ecam::new(Box::new(get_ecam_simulator(&name).await?), false).await;
+        assert_eq!(ecam.device_info().await?.name, name);
+
+        // The machine stalls while turning on, so it isn't ready in time
+        match ecam
+            .wait_for_state_timeout(EcamStatus::Ready, Duration::from_secs(5), |_| {})
+            .await
+        {
+            Err(EcamError::Timeout) => {}
+            x => panic!("Expected a timeout, got {:?}", x),
+        }
+        ecam.wait_for_state_timeout(EcamStatus::Ready, Duration::from_secs(10), |_| {})
+            .await?;
+        ecam.wait_for_state(
+            EcamStatus::Alarm(EcamMachineAlarm::EmptyWaterTank.into()),
+            |_| {},
+        )
+        .await?;
+        let _ = std::fs::remove_file(path);
+        Ok(())
+    }
+
+    #[test]
+    fn script_errors() {
+        let path = write_script("bad-state", r#"[{"state": "Brewing"}]"#);
+        assert!(matches!(load_script(&path), Err(EcamError::IOError(_))));
+        let _ = std::fs::remove_file(path);
+        let path = write_script("bad-json", r#"[{"state": "StandBy""#);
+        assert!(matches!(load_script(&path), Err(EcamError::IOError(_))));
+        let _ = std::fs::remove_file(path);
+        assert!(matches!(
+            load_script("/nonexistent/script.json"),
+            Err(EcamError::IOError(_))
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fault_no_water() -> Result<(), EcamError> {
+        let ecam = fault_simulator("sim-nowater").await?;
+        ecam.wait_for_state(EcamStatus::Ready, |_| {}).await?;
+        ecam.write_request(brew_request()).await?;
+        ecam.wait_for_not_state(EcamStatus::Ready, |_| {}).await?;
+        ecam.wait_for_state(
+            EcamStatus::Alarm(EcamMachineAlarm::EmptyWaterTank.into()),
+            |_| {},
+        )
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fault_disconnect() -> Result<(), EcamError> {
+        let ecam = fault_simulator("sim-disconnect").await?;
+        ecam.wait_for_state(EcamStatus::Ready, |_| {}).await?;
+        ecam.write_request(brew_request()).await?;
+        ecam.wait_for_not_state(EcamStatus::Ready, |_| {}).await?;
+        assert!(ecam
+            .wait_for_state(EcamStatus::Ready, |_| {})
+            .await
+            .is_err());
+        assert!(!ecam.is_alive());
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fault_never_ready() -> Result<(), EcamError> {
+        let ecam = fault_simulator("sim-noready").await?;
+        ecam.wait_for_state(EcamStatus::StandBy, |_| {}).await?;
+        ecam.write_request(Request::AppControl(AppControl::TurnOn))
+            .await?;
+        match ecam
+            .wait_for_state_timeout(EcamStatus::Ready, Duration::from_secs(60), |_| {})
+            .await
+        {
+            Err(EcamError::Timeout) => {}
+            x => panic!("Expected a timeout, got {:?}", x),
+        }
+        assert_eq!(ecam.current_state().await?, EcamStatus::TurningOn(20));
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fault_write_failure() -> Result<(), EcamError> {
+        let ecam = fault_simulator("sim-writefail").await?;
+        ecam.wait_for_state(EcamStatus::Ready, |_| {}).await?;
+        assert!(matches!(
+            ecam.write_request(brew_request()).await,
+            Err(EcamError::IOError(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn script_faults() -> Result<(), EcamError> {
+        let path = write_script(
+            "faults",
+            r#"[{"fault": "fail-writes"}, {"fault": "recover-writes"}, {"fault": "disconnect"}]"#,
+        );
+        assert_eq!(
+            load_script(&path)?,
+            vec![
+                SimulatorStep::FailWrites(true),
+                SimulatorStep::FailWrites(false),
+                SimulatorStep::Disconnect
+            ]
+        );
+        let _ = std::fs::remove_file(path);
+        let path = write_script("bad-fault", r#"[{"fault": "explode"}]"#);
+        assert!(matches!(load_script(&path), Err(EcamError::IOError(_))));
+        let _ = std::fs::remove_file(path);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reports_percentage_scale() -> Result<(), EcamError> {
+        for (name, scale) in [
+            ("sim[on]", PercentageScale::Percent),
+            ("sim[on][byte-percent]", PercentageScale::Byte),
+        ] {
+            let info = get_ecam_simulator(name).await?.info().await?;
+            assert_eq!(info.percentage_scale, Some(scale));
+        }
+        Ok(())
+    }
 }
@@ -1,3 +1,4 @@
+use crate::logging::TraceDirection;
 use crate::prelude::*;
 
 use async_stream::stream;
@@ -11,14 +12,28 @@ use tokio::{
 use tokio_stream::wrappers::LinesStream;
 
 use crate::{
-    ecam::{AsyncFuture, EcamDriver, EcamDriverOutput, EcamError, EcamPacketReceiver},
+    ecam::{
+        AsyncFuture, DeviceInfo, DeviceLock, EcamDriver, EcamDriverOutput, EcamError,
+        EcamPacketReceiver,
+    },
     protocol::*,
 };
 
+/// How long to wait for the subprocess to describe the device once it has had `scan_timeout` to find it.
+const DEVICE_INFO_GRACE: Duration = Duration::from_secs(10);
+
+/// The description sent by the subprocess: `None` until it arrives, then the [`DeviceInfo`] if the subprocess was able
+/// to describe the device.
+type DeviceInfoUpdate = Option<Option<DeviceInfo>>;
+
 pub struct EcamSubprocess {
     stdin: Arc<Mutex<ChildStdin>>,
     receiver: EcamPacketReceiver,
     alive: Arc<Mutex<bool>>,
+    info: tokio::sync::watch::Receiver<DeviceInfoUpdate>,
+    info_timeout: Duration,
+    #[allow(unused)]
+    lock: DeviceLock,
 }
 
 impl EcamSubprocess {
@@ -36,6 +51,30 @@ impl EcamSubprocess {
     async fn is_alive(&self) -> Result<bool, EcamError> {
         Ok(*self.alive.lock().await)
     }
+
+    async fn device_info(&self) -> Result<DeviceInfo, EcamError> {
+        wait_for_device_info(self.info.clone(), self.info_timeout).await
+    }
+}
+
+/// Waits for the subprocess to describe the device. Fails with [`EcamError::NotSupported`] if the device can't be
+/// described, [`EcamError::NotFound`] if the subprocess exits first and [`EcamError::Timeout`] if it doesn't say
+/// either way in time.
+async fn wait_for_device_info(
+    mut info: tokio::sync::watch::Receiver<DeviceInfoUpdate>,
+    timeout: Duration,
+) -> Result<DeviceInfo, EcamError> {
+    let wait = async move {
+        loop {
+            if let Some(info) = info.borrow().clone() {
+                return info.ok_or(EcamError::NotSupported("device info"));
+            }
+            info.changed().await.map_err(|_| EcamError::NotFound)?;
+        }
+    };
+    tokio::time::timeout(timeout, wait)
+        .await
+        .map_err(|_| EcamError::Timeout)?
 }
 
 impl EcamDriver for EcamSubprocess {
@@ -51,6 +90,10 @@ impl EcamDriver for EcamSubprocess {
         Box::pin(self.is_alive())
     }
 
+    fn info(&self) -> AsyncFuture<'_, DeviceInfo> {
+        Box::pin(self.device_info())
+    }
+
     fn scan<'a>() -> AsyncFuture<'a, (String, String)>
     where
         Self: Sized,
@@ -62,6 +105,7 @@ impl EcamDriver for EcamSubprocess {
 pub async fn stream(
     mut child: tokio::process::Child,
     alive: Arc<Mutex<bool>>,
+    info: tokio::sync::watch::Sender<DeviceInfoUpdate>,
 ) -> Result<impl StreamExt<Item = EcamDriverOutput>, EcamError> {
     let mut stderr =
         LinesStream::new(BufReader::new(child.stderr.take().expect("stderr was missing")).lines());
@@ -72,6 +116,24 @@ pub async fn stream(
         while let Some(Ok(s)) = stdout.next().await {
             if s == "R: READY" {
                 yield EcamDriverOutput::Ready;
+            } else if let Some(s) = s.strip_prefix("I: ") {
+                match serde_json::from_str(s) {
+                    Ok(device_info) => {
+                        info.send_replace(Some(device_info));
+                    }
+                    Err(_) => {
+                        trace_packet!("Failed to decode device info '{}'", s);
+                        info.send_replace(Some(None));
+                    }
+                }
+            } else if let Some(s) = s.strip_prefix("T: ") {
+                let frame = s.split_once(' ').and_then(|(direction, bytes)| {
+                    Some((TraceDirection::from_name(direction)?, hex::decode(bytes).ok()?))
+                });
+                match frame {
+                    Some((direction, bytes)) => trace_frame!(direction, &bytes),
+                    None => trace_packet!("Failed to decode frame '{}'", s),
+                }
             } else if let Some(s) = s.strip_prefix("R: ") {
                 if let Ok(bytes) = hex::decode(&s) {
                     yield EcamDriverOutput::Packet(EcamDriverPacket::from_vec(bytes));
@@ -106,12 +168,19 @@ pub async fn stream(
     Result::Ok(stdout.merge(stderr).merge(termination))
 }
 
-pub async fn connect(device_name: &str) -> Result<EcamSubprocess, EcamError> {
+pub async fn connect(
+    device_name: &str,
+    scan_timeout: Duration,
+) -> Result<EcamSubprocess, EcamError> {
+    // Fail clearly up front rather than letting the subprocess fight another process for the connection
+    let lock = DeviceLock::acquire(device_name)?;
     let mut cmd = tokio::process::Command::new(std::env::current_exe()?);
     cmd.arg("--trace");
     cmd.arg("x-internal-pipe");
     cmd.arg("--device-name");
     cmd.arg(device_name);
+    cmd.arg("--scan-timeout");
+    cmd.arg(scan_timeout.as_secs_f64().to_string());
     cmd.stdin(Stdio::piped());
     cmd.stdout(Stdio::piped());
     cmd.stderr(Stdio::piped());
@@ -120,10 +189,51 @@ pub async fn connect(device_name: &str) -> Result<EcamSubprocess, EcamError> {
     let stdin = Arc::new(Mutex::new(child.stdin.take().expect("stdin was missing")));
 
     let alive = Arc::new(Mutex::new(true));
-    let s = Box::pin(stream(child, alive.clone()).await?);
+    let (info_tx, info) = tokio::sync::watch::channel(None);
+    let s = Box::pin(stream(child, alive.clone(), info_tx).await?);
     Result::Ok(EcamSubprocess {
         stdin,
         receiver: EcamPacketReceiver::from_stream(s, false),
         alive,
+        info,
+        info_timeout: scan_timeout + DEVICE_INFO_GRACE,
+        lock,
     })
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn device_info_always_resolves() {
+        let timeout = Duration::from_secs(5);
+        let info = DeviceInfo {
+            name: "sim".to_owned(),
+            ..Default::default()
+        };
+        let (tx, rx) = tokio::sync::watch::channel(Some(Some(info.clone())));
+        assert_eq!(wait_for_device_info(rx, timeout).await.unwrap(), info);
+        tx.send_replace(Some(None));
+        assert!(matches!(
+            wait_for_device_info(tx.subscribe(), timeout).await,
+            Err(EcamError::NotSupported(_))
+        ));
+
+        // A subprocess that never describes the device is given up on
+        let (tx, rx) = tokio::sync::watch::channel(None);
+        assert!(matches!(
+            wait_for_device_info(rx, timeout).await,
+            Err(EcamError::Timeout)
+        ));
+        drop(tx);
+
+        // ...and one that exits first wasn't able to find it
+        let (tx, rx) = tokio::sync::watch::channel(None);
+        drop(tx);
+        assert!(matches!(
+            wait_for_device_info(rx, timeout).await,
+            Err(EcamError::NotFound)
+        ));
+    }
+}
@@ -1,24 +1,49 @@
 use crate::prelude::*;
 
+use serde::Serialize;
 use tokio::sync::{Mutex, OwnedSemaphorePermit};
-use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
 
-use crate::ecam::{EcamDriver, EcamDriverOutput, EcamError};
+use crate::ecam::{DeviceInfo, EcamDriver, EcamDriverOutput, EcamError};
 use crate::protocol::*;
 
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize)]
 pub enum EcamStatus {
     StandBy,
     TurningOn(usize),
     ShuttingDown(usize),
     Ready,
     Busy(usize),
-    Cleaning(usize),
+    Rinsing(usize),
+    MilkCleaning(usize),
+    HotWater(usize),
     Descaling,
     Alarm(MachineEnum<EcamMachineAlarm>),
+    /// The machine reported a state we don't recognize.
+    Fault,
     Fetching(usize),
 }
 
+/// An [`EcamStatus`] along with the raw progress fields reported by the device.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct EcamStatusDetail {
+    pub status: EcamStatus,
+    /// The step of the current operation, as reported by the device.
+    pub progress: u8,
+    /// The progress of the current operation from 0 to 100, regardless of the scale the device reports it on.
+    pub percentage: u8,
+}
+
+impl EcamStatusDetail {
+    pub fn extract(state: &MonitorV2Response) -> EcamStatusDetail {
+        EcamStatusDetail {
+            status: EcamStatus::extract(state),
+            progress: state.progress,
+            percentage: state.progress_percent(),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum EcamOutput {
     Ready,
@@ -26,6 +51,18 @@ pub enum EcamOutput {
     Done,
 }
 
+/// Higher-level events derived from the stream of status responses sent by the device.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum EcamEvent {
+    /// The accessory attached to the accessory port changed (ie: the milk carafe was attached or detached).
+    AccessoryChanged {
+        from: MachineEnum<EcamAccessory>,
+        to: MachineEnum<EcamAccessory>,
+    },
+    /// The water tank was removed or put back.
+    WaterTankChanged { present: bool },
+}
+
 impl EcamOutput {
     /// Takes the underlying packet, if it exists.
     pub fn take_packet(self) -> Option<Response> {
@@ -63,28 +100,35 @@ impl From<EcamOutput> for EcamDriverOutput {
 impl EcamStatus {
     pub fn extract(state: &MonitorV2Response) -> EcamStatus {
         if state.state == EcamMachineState::TurningOn {
-            return EcamStatus::TurningOn(state.percentage as usize);
+            return EcamStatus::TurningOn(state.progress_percent() as usize);
         }
         if state.state == EcamMachineState::ShuttingDown {
-            if state.percentage < 100 {
-                return EcamStatus::ShuttingDown(state.percentage as usize);
+            if state.progress_percent() < 100 {
+                return EcamStatus::ShuttingDown(state.progress_percent() as usize);
             }
             // Emulate status % using progress
             return EcamStatus::ShuttingDown((state.progress as usize * 10).clamp(0, 100));
         }
-        if state.state == EcamMachineState::MilkCleaning || state.state == EcamMachineState::Rinsing
-        {
-            return EcamStatus::Cleaning(state.percentage as usize);
+        if state.state == EcamMachineState::Rinsing {
+            return EcamStatus::Rinsing(state.progress_percent() as usize);
+        }
+        if state.state == EcamMachineState::MilkCleaning {
+            return EcamStatus::MilkCleaning(state.progress_percent() as usize);
+        }
+        if state.state == EcamMachineState::HotWaterDelivery {
+            return EcamStatus::HotWater(state.progress_percent() as usize);
         }
         if state.state == EcamMachineState::MilkPreparation
-            || state.state == EcamMachineState::HotWaterDelivery
             || (state.state == EcamMachineState::ReadyOrDispensing && state.progress != 0)
         {
-            return EcamStatus::Busy(state.percentage as usize);
+            return EcamStatus::Busy(state.progress_percent() as usize);
         }
         if state.state == EcamMachineState::Descaling {
             return EcamStatus::Descaling;
         }
+        if let MachineEnum::Unknown(_) = state.state {
+            return EcamStatus::Fault;
+        }
         #[allow(clippy::never_loop)]
         for alarm in state.alarms.set() {
             if alarm != MachineEnum::Value(EcamMachineAlarm::CleanKnob) {
@@ -97,6 +141,29 @@ impl EcamStatus {
         EcamStatus::Ready
     }
 
+    /// Is the machine dispensing a beverage or hot water?
+    pub fn is_dispensing(&self) -> bool {
+        matches!(self, EcamStatus::Busy(_) | EcamStatus::HotWater(_))
+    }
+
+    /// The progress of the current operation from 0 to 100, or 0 for states that don't report progress.
+    pub fn percentage(&self) -> u8 {
+        match self {
+            EcamStatus::TurningOn(percent)
+            | EcamStatus::ShuttingDown(percent)
+            | EcamStatus::Busy(percent)
+            | EcamStatus::Rinsing(percent)
+            | EcamStatus::MilkCleaning(percent)
+            | EcamStatus::HotWater(percent)
+            | EcamStatus::Fetching(percent) => (*percent).min(100) as u8,
+            EcamStatus::StandBy
+            | EcamStatus::Ready
+            | EcamStatus::Descaling
+            | EcamStatus::Alarm(_)
+            | EcamStatus::Fault => 0,
+        }
+    }
+
     fn matches(&self, state: &MonitorV2Response) -> bool {
         *self == Self::extract(state)
     }
@@ -138,31 +205,83 @@ impl Drop for StatusInterestHandle {
 
 /// Internal struct determining if the interface is still alive.
 #[derive(Clone)]
-struct Alive(Arc<std::sync::Mutex<bool>>);
+struct Alive(Arc<tokio::sync::watch::Sender<bool>>);
 
 impl Alive {
     fn new() -> Self {
-        Self(Arc::new(std::sync::Mutex::new(true)))
+        Self(Arc::new(tokio::sync::watch::channel(true).0))
     }
 
     fn is_alive(&self) -> bool {
-        if let Ok(alive) = self.0.lock() {
-            *alive
-        } else {
-            false
-        }
+        *self.0.borrow()
     }
 
     fn deaden(&self) {
-        if let Ok(mut alive) = self.0.lock() {
+        self.0.send_if_modified(|alive| {
             if *alive {
                 trace_shutdown!("Alive::deaden");
             }
-            *alive = false;
+            std::mem::replace(alive, false)
+        });
+    }
+
+    /// Waits until the interface is no longer alive.
+    async fn dead(&self) {
+        let mut rx = self.0.subscribe();
+        while *rx.borrow_and_update() {
+            if rx.changed().await.is_err() {
+                break;
+            }
         }
     }
 }
 
+/// Skips (and logs) the items a lagging broadcast subscriber missed, rather than failing. The stream ends when the
+/// sender is closed.
+fn skip_lagged<T: Clone + Send + 'static>(
+    name: &'static str,
+    stream: BroadcastStream<T>,
+) -> impl Stream<Item = T> {
+    stream.filter_map(move |x| match x {
+        Ok(x) => Some(x),
+        Err(BroadcastStreamRecvError::Lagged(n)) => {
+            warning!("{} lagged, skipping {} item(s)", name, n);
+            None
+        }
+    })
+}
+
+/// Held by each of the background tasks of an [`Ecam`]. Once every guard has been dropped, the tasks have finished.
+type TaskGuard = tokio::sync::mpsc::Sender<()>;
+
+/// Blocks [`Ecam::current_state`] until fresh status has arrived from the device. The lock is held from the time we
+/// connect (or reconnect) until the first status response on that connection.
+struct ReadyLock {
+    semaphore: Arc<tokio::sync::Semaphore>,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl ReadyLock {
+    /// Creates a lock that is already held.
+    fn new() -> Self {
+        let semaphore = Arc::new(tokio::sync::Semaphore::new(1));
+        let permit = semaphore.clone().try_acquire_owned().ok();
+        ReadyLock { semaphore, permit }
+    }
+
+    /// Holds the lock, unless we're already holding it.
+    async fn hold(&mut self) {
+        if self.permit.is_none() {
+            self.permit = self.semaphore.clone().acquire_owned().await.ok();
+        }
+    }
+
+    /// Releases the lock, if we're holding it.
+    fn release(&mut self) {
+        self.permit.take();
+    }
+}
+
 struct EcamDropHandle {
     alive: Alive,
 }
@@ -180,6 +299,8 @@ pub struct Ecam {
     driver: Arc<Box<dyn EcamDriver>>,
     internals: Arc<Mutex<EcamInternals>>,
     alive: Alive,
+    percentage_scale: Arc<std::sync::Mutex<PercentageScale>>,
+    tasks: Arc<Mutex<tokio::sync::mpsc::Receiver<()>>>,
     #[allow(unused)]
     drop_handle: Arc<EcamDropHandle>,
 }
@@ -187,6 +308,7 @@ pub struct Ecam {
 struct EcamInternals {
     last_status: tokio::sync::watch::Receiver<Option<MonitorV2Response>>,
     packet_tap: Arc<tokio::sync::broadcast::Sender<EcamOutput>>,
+    event_tap: Arc<tokio::sync::broadcast::Sender<EcamEvent>>,
     ready_lock: Arc<tokio::sync::Semaphore>,
     status_interest: StatusInterest,
     dump_packets: bool,
@@ -198,29 +320,27 @@ impl Ecam {
         let driver = Arc::new(driver);
         let (tx, rx) = tokio::sync::watch::channel(None);
         let (txb, _) = tokio::sync::broadcast::channel(100);
+        let (txe, _) = tokio::sync::broadcast::channel(100);
 
         // We want to lock the status until we've received at least one packet
-        let ready_lock = Arc::new(tokio::sync::Semaphore::new(1));
-        let ready_lock_semaphore = Some(
-            ready_lock
-                .clone()
-                .acquire_owned()
-                .await
-                .expect("Failed to lock mutex"),
-        );
+        let ready_lock = ReadyLock::new();
 
         let internals = Arc::new(Mutex::new(EcamInternals {
             last_status: rx,
             packet_tap: Arc::new(txb),
-            ready_lock,
+            event_tap: Arc::new(txe),
+            ready_lock: ready_lock.semaphore.clone(),
             status_interest: StatusInterest::new(),
             started: false,
             dump_packets,
         }));
         let alive = Alive::new();
+        let (task_guard, tasks) = tokio::sync::mpsc::channel(1);
         let ecam_result = Ecam {
             driver,
             internals,
+            percentage_scale: Default::default(),
+            tasks: Arc::new(Mutex::new(tasks)),
             drop_handle: Arc::new(EcamDropHandle {
                 alive: alive.clone(),
             }),
@@ -228,18 +348,24 @@ impl Ecam {
         };
 
         tokio::spawn(Self::operation_loop(
-            ready_lock_semaphore,
+            ready_lock,
             tx,
             ecam_result.driver.clone(),
             ecam_result.internals.clone(),
             ecam_result.alive.clone(),
+            ecam_result.percentage_scale.clone(),
+            task_guard.clone(),
         ));
         let (driver, alive) = (ecam_result.driver.clone(), ecam_result.alive.clone());
-        tokio::spawn(Self::alive_watch(driver, alive));
+        tokio::spawn(Self::alive_watch(driver, alive, task_guard));
         ecam_result
     }
 
-    async fn alive_watch(driver: Arc<Box<dyn EcamDriver>>, alive: Alive) -> Result<(), EcamError> {
+    async fn alive_watch(
+        driver: Arc<Box<dyn EcamDriver>>,
+        alive: Alive,
+        _task_guard: TaskGuard,
+    ) -> Result<(), EcamError> {
         while let Ok(b) = driver.alive().await {
             if !alive.is_alive() || !b {
                 break;
@@ -253,22 +379,34 @@ impl Ecam {
     }
 
     async fn operation_loop(
-        mut ready_lock_semaphore: Option<OwnedSemaphorePermit>,
+        mut ready_lock: ReadyLock,
         tx: tokio::sync::watch::Sender<Option<MonitorV2Response>>,
         driver: Arc<Box<dyn EcamDriver>>,
         internals: Arc<Mutex<EcamInternals>>,
         alive: Alive,
+        percentage_scale: Arc<std::sync::Mutex<PercentageScale>>,
+        task_guard: TaskGuard,
     ) -> Result<(), EcamError> {
         let packet_tap_sender = internals.lock().await.packet_tap.clone();
+        let event_tap_sender = internals.lock().await.event_tap.clone();
         let dump_packets = internals.lock().await.dump_packets;
         let mut started = false;
+        let mut last_accessory = None;
+        let mut last_water_tank = None;
         while alive.is_alive() {
             // Treat end-of-stream as EcamOutput::Done, but we might want to reconsider this in the future
-            let packet: EcamOutput = driver
-                .read()
-                .await?
-                .unwrap_or(EcamDriverOutput::Done)
-                .into();
+            let read = tokio::select! {
+                read = driver.read() => read?,
+                _ = alive.dead() => break,
+            };
+            let mut packet: EcamOutput = read.unwrap_or(EcamDriverOutput::Done).into();
+            if let EcamOutput::Packet(EcamPacket {
+                representation: Some(Response::MonitorV2(x)),
+                ..
+            }) = &mut packet
+            {
+                x.scale = percentage_scale.lock().map(|s| *s).unwrap_or_default();
+            }
             let _ = packet_tap_sender.send(packet.clone());
             if dump_packets {
                 trace_packet!("{:?}", packet);
@@ -276,12 +414,16 @@ impl Ecam {
             match packet {
                 EcamOutput::Ready => {
                     if started {
-                        warning!("Got multiple start requests");
+                        // The driver reconnected, so anything we knew about the device's state is stale
+                        warning!("Device reconnected");
+                        ready_lock.hold().await;
+                        tx.send_replace(None);
                     } else {
                         tokio::spawn(Self::write_monitor_loop(
                             driver.clone(),
                             internals.clone(),
                             alive.clone(),
+                            task_guard.clone(),
                         ));
                         started = true;
                         internals.lock().await.started = true;
@@ -295,11 +437,26 @@ impl Ecam {
                     representation: Some(Response::MonitorV2(x)),
                     ..
                 }) => {
+                    if let Some(from) = last_accessory.replace(x.accessory) {
+                        if from != x.accessory {
+                            let _ = event_tap_sender.send(EcamEvent::AccessoryChanged {
+                                from,
+                                to: x.accessory,
+                            });
+                        }
+                    }
+                    if let Some(was_present) = last_water_tank.replace(x.water_tank_present()) {
+                        if was_present != x.water_tank_present() {
+                            let _ = event_tap_sender.send(EcamEvent::WaterTankChanged {
+                                present: x.water_tank_present(),
+                            });
+                        }
+                    }
                     if tx.send(Some(x)).is_err() {
                         warning!("Failed to send a monitor response");
                         break;
                     }
-                    ready_lock_semaphore.take();
+                    ready_lock.release();
                 }
                 _ => {}
             }
@@ -309,32 +466,75 @@ impl Ecam {
         Ok(())
     }
 
+    /// Describes the device this ECAM is connected to.
+    pub async fn device_info(&self) -> Result<DeviceInfo, EcamError> {
+        self.driver.info().await
+    }
+
+    /// Sets the scale the connected model uses to report percentages. See [`PercentageScale`].
+    pub fn set_percentage_scale(&self, scale: PercentageScale) {
+        if let Ok(mut percentage_scale) = self.percentage_scale.lock() {
+            *percentage_scale = scale;
+        }
+    }
+
     /// Is this ECAM still alive?
     pub fn is_alive(&self) -> bool {
         self.alive.is_alive()
     }
 
+    /// Closes the connection, and waits for the background tasks to notice. The protocol has no request that stops
+    /// monitoring, so the device simply stops receiving monitor requests. Clones of this [`Ecam`] are closed as well.
+    pub async fn close(&self) {
+        trace_shutdown!("Ecam::close()");
+        self.alive.deaden();
+        // Nothing is ever sent on this channel, so this returns once every task has dropped its guard
+        while self.tasks.lock().await.recv().await.is_some() {}
+    }
+
     /// Blocks until the device state reaches our desired state.
     pub async fn wait_for_state(
         &self,
         state: EcamStatus,
         monitor: fn(EcamStatus) -> (),
     ) -> Result<(), EcamError> {
-        self.wait_for(|status| state.matches(status), monitor).await
+        self.wait_for_state_timeout(state, Duration::MAX, monitor)
+            .await
+    }
+
+    /// Blocks until the device state reaches our desired state, failing with [`EcamError::Timeout`] if it doesn't
+    /// get there in time.
+    pub async fn wait_for_state_timeout(
+        &self,
+        state: EcamStatus,
+        timeout: Duration,
+        monitor: fn(EcamStatus) -> (),
+    ) -> Result<(), EcamError> {
+        // Dropping the wait on timeout releases our interest in the status
+        tokio::time::timeout(
+            timeout,
+            self.wait_for(|status| state.matches(status), monitor),
+        )
+        .await
+        .map_err(|_| EcamError::Timeout)?
     }
 
     /// Blocks until the device state is not in the undesired state.
     pub async fn wait_for_not_state(
         &self,
         state: EcamStatus,
-        monitor: fn(EcamStatus) -> (),
+        monitor: impl FnMut(EcamStatus),
     ) -> Result<(), EcamError> {
         self.wait_for(|status| !state.matches(status), monitor)
             .await
     }
 
     /// Blocks until the state test function returns true.
-    pub async fn wait_for<F>(&self, f: F, monitor: fn(EcamStatus) -> ()) -> Result<(), EcamError>
+    pub async fn wait_for<F>(
+        &self,
+        f: F,
+        mut monitor: impl FnMut(EcamStatus),
+    ) -> Result<(), EcamError>
     where
         F: Fn(&MonitorV2Response) -> bool,
     {
@@ -351,10 +551,17 @@ impl Ecam {
                     return Ok(());
                 }
             }
-            // TODO: timeout
-            rx.changed().await.map_err(|_| EcamError::Unknown)?;
+            rx.changed().await.map_err(|_| EcamError::Disconnected)?;
         }
-        Err(EcamError::Unknown)
+        Err(EcamError::Disconnected)
+    }
+
+    /// Returns the last monitor response received from the device without blocking, or `None` if we haven't received
+    /// one yet (or the internals are currently locked).
+    pub fn last_monitor_response(&self) -> Option<MonitorV2Response> {
+        let internals = self.internals.try_lock().ok()?;
+        let last_status = internals.last_status.borrow().clone();
+        last_status
     }
 
     /// Wait for the connection to establish, but not any particular state.
@@ -365,26 +572,62 @@ impl Ecam {
 
     /// Returns the current state, or blocks if we don't know what the current state is yet.
     pub async fn current_state(&self) -> Result<EcamStatus, EcamError> {
+        Ok(self.current_state_detailed().await?.status)
+    }
+
+    /// Returns the current state along with the progress of the current operation, or blocks if we don't know what the
+    /// current state is yet.
+    pub async fn current_state_detailed(&self) -> Result<EcamStatusDetail, EcamError> {
         let mut internals = self.internals.lock().await;
         let status_interest = internals.status_interest.lock();
         let rx = internals.last_status.clone();
         let ready_lock = internals.ready_lock.clone();
         drop(internals);
-        drop(
-            ready_lock
-                .acquire_owned()
-                .await
-                .map_err(|_| EcamError::Unknown)?,
-        );
+        // Hold the ready lock while reading, so a reconnect can't clear the status out from under us
+        let permit = ready_lock
+            .acquire_owned()
+            .await
+            .map_err(|_| EcamError::Disconnected)?;
+        // The lock is only released without a status if the device went away before reporting one
         let ret = if let Some(test) = rx.borrow().as_ref() {
-            Ok(EcamStatus::extract(test))
+            Ok(EcamStatusDetail::extract(test))
         } else {
-            Err(EcamError::Unknown)
+            Err(EcamError::Disconnected)
         };
+        drop(permit);
         drop(status_interest);
         ret
     }
 
+    /// Returns the current state without blocking, or `None` if we don't know what the current state is yet (or the
+    /// internals are currently locked).
+    pub fn try_current_state(&self) -> Option<EcamStatus> {
+        let internals = self.internals.try_lock().ok()?;
+        // If the ready lock is held, we haven't received fresh status on this connection
+        let _permit = internals.ready_lock.try_acquire().ok()?;
+        let status = internals
+            .last_status
+            .borrow()
+            .as_ref()
+            .map(EcamStatus::extract);
+        status
+    }
+
+    /// Is the machine ready? Blocks if we don't know what the current state is yet.
+    pub async fn is_ready(&self) -> Result<bool, EcamError> {
+        Ok(self.current_state().await? == EcamStatus::Ready)
+    }
+
+    /// Is the machine busy dispensing (including hot water)? Blocks if we don't know what the current state is yet.
+    pub async fn is_busy(&self) -> Result<bool, EcamError> {
+        Ok(self.current_state().await?.is_dispensing())
+    }
+
+    /// Is the machine in standby? Blocks if we don't know what the current state is yet.
+    pub async fn is_standby(&self) -> Result<bool, EcamError> {
+        Ok(self.current_state().await? == EcamStatus::StandBy)
+    }
+
     pub async fn write(&self, packet: EcamPacket<Request>) -> Result<(), EcamError> {
         let internals = self.internals.lock().await;
         if !internals.started {
@@ -399,10 +642,73 @@ impl Ecam {
         self.write(EcamPacket::from_represenation(r)).await
     }
 
+    /// Writes a hand-built payload (starting with the request ID), which is packetized before it is sent. Payloads
+    /// longer than [`MAX_PAYLOAD_LEN`] are rejected with [`EcamError::PacketTooLarge`].
+    pub async fn write_raw(&self, payload: &[u8]) -> Result<(), EcamError> {
+        if payload.len() > MAX_PAYLOAD_LEN {
+            return Err(EcamError::PacketTooLarge(payload.len()));
+        }
+        self.write(EcamPacket::from_raw(payload)).await
+    }
+
+    /// Subscribes to the packets received from the device. A subscriber that falls too far behind skips the packets
+    /// it missed.
     pub async fn packet_tap(&self) -> Result<impl Stream<Item = EcamOutput>, EcamError> {
         let internals = self.internals.lock().await;
-        Ok(BroadcastStream::new(internals.packet_tap.subscribe())
-            .map(|x| x.expect("Unexpected receive error")))
+        Ok(skip_lagged(
+            "Packet tap",
+            BroadcastStream::new(internals.packet_tap.subscribe()),
+        ))
+    }
+
+    /// Subscribes to the [`EcamEvent`]s derived from the device's status. A subscriber that falls too far behind skips
+    /// the events it missed.
+    pub async fn event_tap(&self) -> Result<impl Stream<Item = EcamEvent>, EcamError> {
+        let internals = self.internals.lock().await;
+        Ok(skip_lagged(
+            "Event tap",
+            BroadcastStream::new(internals.event_tap.subscribe()),
+        ))
+    }
+
+    /// Subscribes to the device's status, yielding only when it changes. The device is polled for status for as long as
+    /// the stream is held.
+    pub async fn status_stream(&self) -> Result<impl Stream<Item = EcamStatus>, EcamError> {
+        self.changes(EcamStatus::extract).await
+    }
+
+    /// Like [`Ecam::status_stream`], but yields an [`EcamStatusDetail`] whenever the status or the progress of the
+    /// current operation changes.
+    pub async fn status_detail_stream(
+        &self,
+    ) -> Result<impl Stream<Item = EcamStatusDetail>, EcamError> {
+        self.changes(EcamStatusDetail::extract).await
+    }
+
+    /// Subscribes to the device's status, yielding the value `extract` derives from it whenever that value changes.
+    async fn changes<T: Copy + PartialEq>(
+        &self,
+        extract: fn(&MonitorV2Response) -> T,
+    ) -> Result<impl Stream<Item = T>, EcamError> {
+        let mut internals = self.internals.lock().await;
+        let status_interest = internals.status_interest.lock();
+        let rx = internals.last_status.clone();
+        drop(internals);
+        Ok(futures::stream::unfold(
+            (rx, None, status_interest),
+            move |(mut rx, last, status_interest)| async move {
+                loop {
+                    let status = rx.borrow_and_update().as_ref().map(extract);
+                    if let Some(status) = status {
+                        if Some(status) != last {
+                            return Some((status, (rx, Some(status), status_interest)));
+                        }
+                    }
+                    // The device has gone away
+                    rx.changed().await.ok()?;
+                }
+            },
+        ))
     }
 
     /// The monitor loop is booted when the underlying driver reports that it is ready.
@@ -410,6 +716,7 @@ impl Ecam {
         driver: Arc<Box<dyn EcamDriver>>,
         internals: Arc<Mutex<EcamInternals>>,
         alive: Alive,
+        _task_guard: TaskGuard,
     ) -> Result<(), EcamError> {
         let status_request = EcamDriverPacket::from_vec(Request::MonitorV2().encode());
         while alive.is_alive() {
@@ -449,7 +756,7 @@ mod test {
 
     #[rstest]
     #[case(EcamStatus::Busy(0), &crate::protocol::test::RESPONSE_STATUS_CAPPUCCINO_MILK)]
-    #[case(EcamStatus::Cleaning(9), &crate::protocol::test::RESPONSE_STATUS_CLEANING_AFTER_CAPPUCCINO)]
+    #[case(EcamStatus::MilkCleaning(9), &crate::protocol::test::RESPONSE_STATUS_CLEANING_AFTER_CAPPUCCINO)]
     // We removed the need to test the CleanKnob alarm since it's technically a warning - should handle this better
     // #[case(EcamStatus::Alarm(EcamMachineAlarm::CleanKnob.into()), &crate::protocol::test::RESPONSE_STATUS_READY_AFTER_CAPPUCCINO)]
     #[case(EcamStatus::StandBy, &crate::protocol::test::RESPONSE_STATUS_STANDBY_NO_ALARMS)]
@@ -468,4 +775,443 @@ mod test {
             assert_eq!(status, expected_status);
         }
     }
+
+    #[rstest]
+    #[case(EcamStatus::Rinsing(50), MachineEnum::Value(EcamMachineState::Rinsing))]
+    #[case(
+        EcamStatus::MilkCleaning(50),
+        MachineEnum::Value(EcamMachineState::MilkCleaning)
+    )]
+    #[case(
+        EcamStatus::HotWater(50),
+        MachineEnum::Value(EcamMachineState::HotWaterDelivery)
+    )]
+    #[case(
+        EcamStatus::Busy(50),
+        MachineEnum::Value(EcamMachineState::MilkPreparation)
+    )]
+    #[case(EcamStatus::Descaling, MachineEnum::Value(EcamMachineState::Descaling))]
+    #[case(EcamStatus::Fault, MachineEnum::Unknown(99))]
+    fn extract_ecam_status(
+        #[case] expected_status: EcamStatus,
+        #[case] state: MachineEnum<EcamMachineState>,
+    ) {
+        let response = MonitorV2Response {
+            state,
+            progress: 1,
+            percentage: 50,
+            ..Default::default()
+        };
+        assert_eq!(EcamStatus::extract(&response), expected_status);
+        assert!(expected_status.matches(&response));
+    }
+
+    /// A driver fed by the test, which ignores everything written to it.
+    struct ManualDriver(Mutex<tokio::sync::mpsc::Receiver<EcamDriverOutput>>);
+
+    impl EcamDriver for ManualDriver {
+        fn read(&self) -> AsyncFuture<Option<EcamDriverOutput>> {
+            Box::pin(async { Ok(self.0.lock().await.recv().await) })
+        }
+
+        fn write(&self, _data: EcamDriverPacket) -> AsyncFuture<()> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn alive(&self) -> AsyncFuture<bool> {
+            Box::pin(async { Ok(true) })
+        }
+
+        fn scan<'a>() -> AsyncFuture<'a, (String, String)>
+        where
+            Self: Sized,
+        {
+            unimplemented!()
+        }
+    }
+
+    fn status(state: EcamMachineState) -> EcamDriverOutput {
+        status_with_progress(state, 0)
+    }
+
+    fn status_with_progress(state: EcamMachineState, progress: u8) -> EcamDriverOutput {
+        let response = MonitorV2Response {
+            state: state.into(),
+            progress,
+            ..Default::default()
+        };
+        let bytes = [
+            vec![EcamRequestId::MonitorV2.into(), 0xf0],
+            response.encode(),
+        ]
+        .concat();
+        EcamDriverOutput::Packet(EcamDriverPacket::from_vec(bytes))
+    }
+
+    /// Does [`Ecam::current_state`] return within a second?
+    async fn current_state_ready(ecam: &Ecam) -> bool {
+        tokio::time::timeout(Duration::from_secs(1), ecam.current_state())
+            .await
+            .is_ok()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ready_lock_across_reconnects() -> Result<(), EcamError> {
+        let (tx, rx) = tokio::sync::mpsc::channel(10);
+        let ecam = Ecam::new(Box::new(ManualDriver(Mutex::new(rx))), false).await;
+        let send = |output| async {
+            tx.send(output).await.expect("Failed to send");
+            // Give the operation loop a chance to process the output
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+
+        send(EcamDriverOutput::Ready).await;
+        assert!(!current_state_ready(&ecam).await);
+        send(status(EcamMachineState::StandBy)).await;
+        assert_eq!(ecam.current_state().await?, EcamStatus::StandBy);
+
+        for state in [
+            EcamMachineState::ReadyOrDispensing,
+            EcamMachineState::StandBy,
+            EcamMachineState::ReadyOrDispensing,
+        ] {
+            send(EcamDriverOutput::Ready).await;
+            assert!(!current_state_ready(&ecam).await);
+            assert!(ecam.last_monitor_response().is_none());
+            send(status(state)).await;
+            assert_eq!(
+                ecam.current_state().await?,
+                EcamStatus::extract(&MonitorV2Response {
+                    state: state.into(),
+                    ..Default::default()
+                })
+            );
+            // Further status responses on the same connection don't affect the lock
+            send(status(state)).await;
+            assert!(current_state_ready(&ecam).await);
+        }
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn state_predicates() -> Result<(), EcamError> {
+        let (tx, rx) = tokio::sync::mpsc::channel(10);
+        let ecam = Ecam::new(Box::new(ManualDriver(Mutex::new(rx))), false).await;
+        let send = |output| async {
+            tx.send(output).await.expect("Failed to send");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+
+        // Status isn't known yet
+        assert_eq!(ecam.try_current_state(), None);
+        send(EcamDriverOutput::Ready).await;
+        assert_eq!(ecam.try_current_state(), None);
+        assert!(
+            tokio::time::timeout(Duration::from_secs(1), ecam.is_ready())
+                .await
+                .is_err()
+        );
+
+        send(status(EcamMachineState::StandBy)).await;
+        assert_eq!(ecam.try_current_state(), Some(EcamStatus::StandBy));
+        assert!(ecam.is_standby().await?);
+        assert!(!ecam.is_ready().await?);
+        assert!(!ecam.is_busy().await?);
+
+        send(status(EcamMachineState::ReadyOrDispensing)).await;
+        assert_eq!(ecam.try_current_state(), Some(EcamStatus::Ready));
+        assert!(ecam.is_ready().await?);
+        assert!(!ecam.is_standby().await?);
+
+        send(status(EcamMachineState::HotWaterDelivery)).await;
+        assert!(ecam.is_busy().await?);
+        assert!(!ecam.is_ready().await?);
+
+        // A reconnect forgets the status until fresh status arrives
+        send(EcamDriverOutput::Ready).await;
+        assert_eq!(ecam.try_current_state(), None);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn current_state_detailed() -> Result<(), EcamError> {
+        let ecam = Ecam::new(
+            Box::new(crate::ecam::get_ecam_simulator("sim[on][byte-percent]").await?),
+            false,
+        )
+        .await;
+        ecam.set_percentage_scale(PercentageScale::Byte);
+        ecam.wait_for(|m| m.progress == 10, |_| {}).await?;
+        assert_eq!(
+            ecam.current_state_detailed().await?,
+            EcamStatusDetail {
+                status: EcamStatus::Busy(40),
+                progress: 10,
+                percentage: 40,
+            }
+        );
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn status_stream() -> Result<(), EcamError> {
+        let ecam = Ecam::new(
+            Box::new(crate::ecam::get_ecam_simulator("sim[on]").await?),
+            false,
+        )
+        .await;
+        let interest = || {
+            ecam.internals
+                .try_lock()
+                .expect("Failed to lock")
+                .status_interest
+                .count()
+        };
+        let stream = ecam.status_stream().await?;
+        assert_eq!(interest(), 1);
+        let statuses: Vec<_> = stream.take(3).collect().await;
+        assert_eq!(
+            statuses,
+            vec![EcamStatus::Ready, EcamStatus::Busy(4), EcamStatus::Busy(8)]
+        );
+        assert_eq!(interest(), 0);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn status_detail_stream() -> Result<(), EcamError> {
+        let (tx, rx) = tokio::sync::mpsc::channel(10);
+        let ecam = Ecam::new(Box::new(ManualDriver(Mutex::new(rx))), false).await;
+        tx.send(EcamDriverOutput::Ready)
+            .await
+            .expect("Failed to send");
+        let details = ecam.status_detail_stream().await?;
+        futures::pin_mut!(details);
+        let send = |progress| {
+            tx.try_send(status_with_progress(EcamMachineState::StandBy, progress))
+                .expect("Failed to send")
+        };
+        send(0);
+        let detail = details.next().await.expect("Stream ended");
+        assert_eq!((detail.status, detail.progress), (EcamStatus::StandBy, 0));
+        // Repeats are skipped, but a change in progress is sent even though the status is the same
+        send(0);
+        assert!(tokio::time::timeout(Duration::from_secs(1), details.next())
+            .await
+            .is_err());
+        send(1);
+        let detail = details.next().await.expect("Stream ended");
+        assert_eq!((detail.status, detail.progress), (EcamStatus::StandBy, 1));
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn wait_for_state_timeout() -> Result<(), EcamError> {
+        let (tx, rx) = tokio::sync::mpsc::channel(10);
+        let ecam = Ecam::new(Box::new(ManualDriver(Mutex::new(rx))), false).await;
+        tx.send(EcamDriverOutput::Ready)
+            .await
+            .expect("Failed to send");
+        tx.send(status(EcamMachineState::StandBy))
+            .await
+            .expect("Failed to send");
+        let interest = || {
+            ecam.internals
+                .try_lock()
+                .expect("Failed to lock")
+                .status_interest
+                .count()
+        };
+
+        match ecam
+            .wait_for_state_timeout(EcamStatus::Ready, Duration::from_secs(5), |_| {})
+            .await
+        {
+            Err(EcamError::Timeout) => {}
+            x => panic!("Expected a timeout, got {:?}", x),
+        }
+        assert_eq!(interest(), 0);
+
+        tx.send(status(EcamMachineState::ReadyOrDispensing))
+            .await
+            .expect("Failed to send");
+        ecam.wait_for_state_timeout(EcamStatus::Ready, Duration::from_secs(5), |_| {})
+            .await?;
+        assert_eq!(interest(), 0);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn packet_tap_lagged() -> Result<(), EcamError> {
+        let (tx, rx) = tokio::sync::mpsc::channel(300);
+        let ecam = Ecam::new(Box::new(ManualDriver(Mutex::new(rx))), false).await;
+        let mut tap = ecam.packet_tap().await?;
+        let mut events = ecam.event_tap().await?;
+
+        // Overflow the broadcast channels without reading from either tap
+        tx.send(EcamDriverOutput::Ready)
+            .await
+            .expect("Failed to send");
+        for i in 0..150 {
+            // Alternate the accessory so each status is also an event
+            let response = MonitorV2Response {
+                state: EcamMachineState::ReadyOrDispensing.into(),
+                accessory: [EcamAccessory::None, EcamAccessory::Milk][i % 2].into(),
+                ..Default::default()
+            };
+            let bytes = [
+                vec![EcamRequestId::MonitorV2.into(), 0xf0],
+                response.encode(),
+            ]
+            .concat();
+            tx.send(EcamDriverOutput::Packet(EcamDriverPacket::from_vec(bytes)))
+                .await
+                .expect("Failed to send");
+        }
+        tx.send(EcamDriverOutput::Done)
+            .await
+            .expect("Failed to send");
+
+        while ecam.is_alive() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // Everything the lagging taps missed is skipped, rather than failing
+        let mut outputs = vec![];
+        while let Some(output) = tap.next().await {
+            outputs.push(output);
+            if outputs.last() == Some(&EcamOutput::Done) {
+                break;
+            }
+        }
+        assert!(outputs.len() < 152);
+        assert_eq!(outputs.last(), Some(&EcamOutput::Done));
+        let mut received = vec![];
+        while let Some(Some(event)) = futures::FutureExt::now_or_never(events.next()) {
+            received.push(event);
+        }
+        assert!(!received.is_empty() && received.len() < 149);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn close_waits_for_tasks() -> Result<(), EcamError> {
+        // The device goes quiet after reporting that it is ready, so the read loop has to be woken up to close
+        let (driver, log) = crate::ecam::get_ecam_simulator_scripted(vec![
+            crate::ecam::SimulatorStep::status(EcamMachineState::ReadyOrDispensing, 0, 0, 1),
+            crate::ecam::SimulatorStep::Delay(Duration::from_secs(60)),
+        ])
+        .await?;
+        let ecam = Ecam::new(Box::new(driver), false).await;
+        let status = ecam.status_stream().await?;
+        ecam.wait_for_state(EcamStatus::Ready, |_| {}).await?;
+
+        tokio::time::timeout(Duration::from_secs(1), ecam.close())
+            .await
+            .expect("Timed out closing");
+        assert!(!ecam.is_alive());
+        let requests = log.requests().len();
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        assert_eq!(log.requests().len(), requests);
+        drop(status);
+
+        // Closing again is harmless
+        ecam.close().await;
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn write_raw() -> Result<(), EcamError> {
+        let (driver, log) = crate::ecam::get_ecam_simulator_scripted(vec![]).await?;
+        let ecam = Ecam::new(Box::new(driver), false).await;
+        ecam.write_raw(&[0x75, 0x0f]).await?;
+        assert_eq!(log.requests(), vec![vec![0x75, 0x0f]]);
+
+        let payload = vec![0; MAX_PAYLOAD_LEN + 1];
+        assert!(matches!(
+            ecam.write_raw(&payload).await,
+            Err(EcamError::PacketTooLarge(253))
+        ));
+        assert_eq!(log.requests().len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn device_info_from_simulator() -> Result<(), EcamError> {
+        let ecam = Ecam::new(
+            Box::new(crate::ecam::get_ecam_simulator("sim[on]").await?),
+            false,
+        )
+        .await;
+        let info = ecam.device_info().await?;
+        assert_eq!(info.name, "sim[on]");
+        assert_eq!(info.service_uuid, crate::ecam::SERVICE_UUID.to_string());
+        assert_eq!(
+            info.characteristic_uuid,
+            crate::ecam::CHARACTERISTIC_UUID.to_string()
+        );
+
+        // Drivers that can't describe their device say so
+        let (_tx, rx) = tokio::sync::mpsc::channel(1);
+        let ecam = Ecam::new(Box::new(ManualDriver(Mutex::new(rx))), false).await;
+        assert!(matches!(ecam.device_info().await, Err(EcamError::NotFound)));
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn water_tank_event() -> Result<(), EcamError> {
+        let (tx, rx) = tokio::sync::mpsc::channel(10);
+        let ecam = Ecam::new(Box::new(ManualDriver(Mutex::new(rx))), false).await;
+        let mut events = ecam.event_tap().await?;
+        let tank = |switches: &[EcamMachineSwitch]| {
+            let response = MonitorV2Response {
+                state: EcamMachineState::StandBy.into(),
+                switches: SwitchSet::of(switches),
+                ..Default::default()
+            };
+            let bytes = [
+                vec![EcamRequestId::MonitorV2.into(), 0xf0],
+                response.encode(),
+            ]
+            .concat();
+            EcamDriverOutput::Packet(EcamDriverPacket::from_vec(bytes))
+        };
+        for output in [
+            EcamDriverOutput::Ready,
+            tank(&[]),
+            tank(&[EcamMachineSwitch::WaterSpout]),
+            tank(&[EcamMachineSwitch::WaterTankAbsent]),
+            tank(&[EcamMachineSwitch::WaterTankAbsent]),
+            tank(&[]),
+        ] {
+            tx.send(output).await.expect("Failed to send");
+        }
+        assert_eq!(
+            events.next().await,
+            Some(EcamEvent::WaterTankChanged { present: false })
+        );
+        assert_eq!(
+            events.next().await,
+            Some(EcamEvent::WaterTankChanged { present: true })
+        );
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn accessory_change_event() -> Result<(), EcamError> {
+        let ecam = Ecam::new(
+            Box::new(crate::ecam::get_ecam_simulator("sim[on][milk-detach]").await?),
+            false,
+        )
+        .await;
+        let mut events = ecam.event_tap().await?;
+        assert_eq!(
+            events.next().await,
+            Some(EcamEvent::AccessoryChanged {
+                from: EcamAccessory::Milk.into(),
+                to: EcamAccessory::None.into()
+            })
+        );
+        Ok(())
+    }
 }
@@ -1,11 +1,14 @@
 //! Low-level communication with ECAM-based devices.
 
 use crate::prelude::*;
+use crate::protocol::{EcamAccessory, EcamBeverageId, MachineEnum};
 
 use thiserror::Error;
 
+mod device_lock;
 mod driver;
 mod ecam_bt;
+mod ecam_record;
 mod ecam_simulate;
 mod ecam_subprocess;
 mod ecam_wrapper;
@@ -13,27 +16,148 @@ mod packet_receiver;
 mod packet_stream;
 mod stdin_stream;
 
-pub use self::ecam_bt::EcamBT;
-pub use driver::{EcamDriver, EcamDriverOutput};
-pub use ecam_simulate::get_ecam_simulator;
+pub use self::ecam_bt::{
+    EcamBT, CHARACTERISTIC_UUID, DEFAULT_RECONNECT_ATTEMPTS, DEFAULT_SCAN_DEADLINE,
+    DEFAULT_SCAN_TIMEOUT, SERVICE_UUID,
+};
+pub use device_lock::DeviceLock;
+pub use driver::{DeviceInfo, EcamDriver, EcamDriverOutput};
+pub use ecam_record::{
+    get_ecam_replay, load_recording, EcamRecorder, RecordedEntry, RecordedEvent,
+};
+pub use ecam_simulate::{
+    fault_script, get_ecam_simulator, get_ecam_simulator_scripted, load_script, SimulatorLog,
+    SimulatorStep,
+};
 pub use ecam_subprocess::connect as get_ecam_subprocess;
-pub use ecam_wrapper::{Ecam, EcamOutput, EcamStatus};
+pub use ecam_wrapper::{Ecam, EcamEvent, EcamOutput, EcamStatus, EcamStatusDetail};
 pub use packet_receiver::EcamPacketReceiver;
 pub use stdin_stream::pipe_stdin;
+pub use stream_cancel::{Trigger, Tripwire};
 
 pub async fn ecam_scan() -> Result<(String, String), EcamError> {
     EcamBT::scan().await
 }
 
+/// Scans for every device that shows up within `scan_timeout`.
+pub async fn ecam_scan_all(scan_timeout: Duration) -> Result<Vec<(String, String)>, EcamError> {
+    EcamBT::scan_all(scan_timeout).await
+}
+
+/// Scans for a device for up to `scan_timeout`.
+pub async fn ecam_scan_with_timeout(scan_timeout: Duration) -> Result<(String, String), EcamError> {
+    EcamBT::scan_with_timeout(scan_timeout).await
+}
+
+/// Scans for a device, giving up with [`EcamError::Cancelled`] if the [`Tripwire`] is triggered first.
+pub async fn ecam_scan_with_cancel(cancel: Tripwire) -> Result<(String, String), EcamError> {
+    EcamBT::scan_with_cancel(cancel).await
+}
+
 pub async fn ecam_lookup(device_name: &str, dump_packets: bool) -> Result<Ecam, EcamError> {
-    let driver = Box::new(get_ecam_subprocess(device_name).await?);
-    Ok(Ecam::new(driver, dump_packets).await)
+    Ok(
+        connect_with_info(device_name, dump_packets, DEFAULT_SCAN_DEADLINE, None)
+            .await?
+            .0,
+    )
+}
+
+/// Opens the driver for a device in this process: a replay of a recording for `replay:<file>` (see
+/// [`get_ecam_replay`]), a simulator for names starting with `sim` (see [`get_ecam_simulator`]), or otherwise the
+/// Bluetooth device with that id. Bluetooth devices are scanned for until they show up or `scan_timeout` elapses, and
+/// are reconnected up to [`DEFAULT_RECONNECT_ATTEMPTS`] times if the connection drops.
+pub async fn get_ecam_driver(
+    device_name: &str,
+    scan_timeout: Duration,
+) -> Result<Box<dyn EcamDriver>, EcamError> {
+    if let Some(path) = device_name.strip_prefix("replay:") {
+        Ok(Box::new(get_ecam_replay(path.to_owned()).await?))
+    } else if device_name.starts_with("sim") {
+        Ok(Box::new(get_ecam_simulator(device_name).await?))
+    } else {
+        Ok(Box::new(
+            EcamBT::get_with_reconnect(
+                device_name.to_owned(),
+                scan_timeout,
+                DEFAULT_RECONNECT_ATTEMPTS,
+            )
+            .await?,
+        ))
+    }
+}
+
+/// Connects to the given device, returning both the [`Ecam`] handle and the [`DeviceInfo`] describing the device. The
+/// device is scanned for until it shows up or `scan_timeout` elapses. If `record` is given, the session is recorded
+/// to that file (see [`EcamRecorder`]). If the device can't be described (or doesn't describe itself in time), the
+/// returned [`DeviceInfo`] only has its name. Percentages are assumed to use the default
+/// [`crate::protocol::PercentageScale`] unless the driver reports otherwise.
+pub async fn connect_with_info(
+    device_name: &str,
+    dump_packets: bool,
+    scan_timeout: Duration,
+    record: Option<&std::path::Path>,
+) -> Result<(Ecam, DeviceInfo), EcamError> {
+    let mut driver: Box<dyn EcamDriver> =
+        Box::new(get_ecam_subprocess(device_name, scan_timeout).await?);
+    if let Some(record) = record {
+        driver = Box::new(EcamRecorder::new(driver, record)?);
+    }
+    let ecam = Ecam::new(driver, dump_packets).await;
+    let info = match ecam.device_info().await {
+        Ok(info) => {
+            ecam.set_percentage_scale(info.percentage_scale.unwrap_or_default());
+            info
+        }
+        Err(e @ (EcamError::NotSupported(_) | EcamError::Timeout)) => {
+            warning!(
+                "Unable to describe {}, assuming the default percentage scale: {}",
+                device_name,
+                e
+            );
+            DeviceInfo {
+                name: device_name.to_owned(),
+                ..Default::default()
+            }
+        }
+        Err(e) => return Err(e),
+    };
+    Ok((ecam, info))
 }
 
 #[derive(Error, Debug)]
 pub enum EcamError {
     #[error("not found")]
     NotFound,
+    #[error("{0:?} accessory was detached during the operation")]
+    AccessoryDetached(MachineEnum<EcamAccessory>),
+    #[error("timed out")]
+    Timeout,
+    #[error("device already in use by another longshot process")]
+    DeviceInUse,
+    #[error("cancelled")]
+    Cancelled,
+    #[error("{0} is not supported by this machine")]
+    NotSupported(&'static str),
+    #[error("{0:?} is not supported by this machine")]
+    UnsupportedBeverage(EcamBeverageId),
+    #[error("this machine doesn't have a variant {1} of {0:?}")]
+    MissingRecipeVariant(EcamBeverageId, u8),
+    #[error("the machine did not apply the new {0}")]
+    NotApplied(&'static str),
+    #[error("parameter {0:#06x} is not safe to write")]
+    UnsafeParameter(u16),
+    #[error("the coffee grounds container is full, empty it and try again")]
+    GroundsContainerFull,
+    #[error("the machine is busy ({0:?})")]
+    Busy(EcamStatus),
+    #[error("a payload of {0} bytes is too long for a single packet")]
+    PacketTooLarge(usize),
+    #[error("the device disconnected")]
+    Disconnected,
+    #[error("protocol error: {0}")]
+    ProtocolError(String),
+    #[error("the machine is not ready ({0:?})")]
+    NotReady(EcamStatus),
     #[error(transparent)]
     BTError(#[from] btleplug::Error),
     #[error(transparent)]
@@ -41,3 +165,75 @@ pub enum EcamError {
     #[error("Unknown error")]
     Unknown,
 }
+
+impl EcamError {
+    /// Whether the operation that failed with this error might succeed if it's tried again, so that callers can decide
+    /// whether to back off and retry or give up.
+    ///
+    ///  * [`EcamError::Timeout`], [`EcamError::Disconnected`] and [`EcamError::Busy`] are transient, as is anything
+    ///    the Bluetooth stack reports about a dropped or slow connection (or can't explain).
+    ///  * [`EcamError::IOError`]s are retryable if they describe an interrupted or broken connection.
+    ///  * Everything else needs the user to do something first (pick a different device, empty the grounds container,
+    ///    close the other longshot process, and so on), or will fail the same way every time, like
+    ///    [`EcamError::ProtocolError`].
+    pub fn is_retryable(&self) -> bool {
+        use std::io::ErrorKind;
+        match self {
+            EcamError::Timeout | EcamError::Disconnected | EcamError::Busy(_) => true,
+            EcamError::BTError(e) => matches!(
+                e,
+                btleplug::Error::NotConnected
+                    | btleplug::Error::DeviceNotFound
+                    | btleplug::Error::TimedOut(_)
+                    | btleplug::Error::Other(_)
+            ),
+            EcamError::IOError(e) => matches!(
+                e.kind(),
+                ErrorKind::Interrupted
+                    | ErrorKind::TimedOut
+                    | ErrorKind::WouldBlock
+                    | ErrorKind::BrokenPipe
+                    | ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+            ),
+            EcamError::NotFound
+            | EcamError::AccessoryDetached(_)
+            | EcamError::DeviceInUse
+            | EcamError::Cancelled
+            | EcamError::NotSupported(_)
+            | EcamError::UnsupportedBeverage(_)
+            | EcamError::MissingRecipeVariant(..)
+            | EcamError::NotApplied(_)
+            | EcamError::UnsafeParameter(_)
+            | EcamError::GroundsContainerFull
+            | EcamError::PacketTooLarge(_)
+            | EcamError::ProtocolError(_)
+            | EcamError::NotReady(_)
+            | EcamError::Unknown => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::*;
+
+    #[rstest]
+    #[case(EcamError::Timeout, true)]
+    #[case(EcamError::Disconnected, true)]
+    #[case(EcamError::Busy(EcamStatus::Descaling), true)]
+    #[case(btleplug::Error::NotConnected.into(), true)]
+    #[case(btleplug::Error::TimedOut(Duration::from_secs(1)).into(), true)]
+    #[case(btleplug::Error::PermissionDenied.into(), false)]
+    #[case(std::io::Error::from(std::io::ErrorKind::ConnectionReset).into(), true)]
+    #[case(std::io::Error::from(std::io::ErrorKind::NotFound).into(), false)]
+    #[case(EcamError::NotFound, false)]
+    #[case(EcamError::DeviceInUse, false)]
+    #[case(EcamError::UnsupportedBeverage(EcamBeverageId::Americano), false)]
+    #[case(EcamError::GroundsContainerFull, false)]
+    #[case(EcamError::ProtocolError("bad frame".to_owned()), false)]
+    fn is_retryable(#[case] error: EcamError, #[case] retryable: bool) {
+        assert_eq!(error.is_retryable(), retryable, "{:?}", error);
+    }
+}
@@ -15,19 +15,19 @@ impl EcamPacketReceiver {
     ) -> Self {
         let (tx, rx) = tokio::sync::mpsc::channel(100);
         tokio::spawn(async move {
-            if wrap_start_end {
-                tx.send(EcamDriverOutput::Ready)
-                    .await
-                    .expect("Failed to forward notification");
+            // The receiver goes away when the device is closed, so stop forwarding rather than failing
+            if wrap_start_end && tx.send(EcamDriverOutput::Ready).await.is_err() {
+                return;
             }
             while let Some(m) = stream.next().await {
-                tx.send(m).await.expect("Failed to forward notification");
+                if tx.send(m).await.is_err() {
+                    trace_shutdown!("EcamPacketReceiver (receiver closed)");
+                    return;
+                }
             }
             trace_shutdown!("EcamPacketReceiver");
             if wrap_start_end {
-                tx.send(EcamDriverOutput::Done)
-                    .await
-                    .expect("Failed to forward notification");
+                let _ = tx.send(EcamDriverOutput::Done).await;
             }
         });
 
@@ -1,3 +1,4 @@
+use crate::logging::TraceDirection;
 use crate::prelude::*;
 
 use async_stream::stream;
@@ -11,10 +12,9 @@ const MIN_PACKET_LEN: u8 = 4;
 
 /// Builds a packet from collections of bytes and emits it if and only if the length and checksum are valid.
 ///
-/// The [`PacketBuilder`] assumes that packets are aligned to the input chunks, and that a starting chunk
-/// that doesn't start with the sync byte, is corrupted or orphaned.
-///
-/// A starting chunk is defined as the next chunk recieved after a packet is emitted.
+/// Packets may be split across any number of input chunks, and a chunk may contain the end of one packet and the start
+/// of the next. Bytes following an emitted packet are kept for the next packet, while bytes that can't start a valid
+/// packet are treated as corrupted or orphaned and skipped.
 #[derive(Default)]
 struct PacketBuilder {
     packet_buffer: Vec<u8>,
@@ -31,9 +31,15 @@ impl PacketBuilder {
         self.packet_buffer.is_empty()
     }
 
-    /// Accumulates a single packet chunk, returning the entire packet as a [`Vec<u8>`] if it is complete.
+    /// Accumulates a single packet chunk, returning the first complete packet as a [`Vec<u8>`] if there is one. Any
+    /// further packets completed by this chunk are returned by [`PacketBuilder::next_packet`].
     pub fn accumulate(&mut self, chunk: &[u8]) -> Option<Vec<u8>> {
         self.packet_buffer.extend_from_slice(chunk);
+        self.next_packet()
+    }
+
+    /// Returns the next complete packet from the bytes accumulated so far, if there is one.
+    pub fn next_packet(&mut self) -> Option<Vec<u8>> {
         let is_valid_packet = |p: &[u8]| p[0] == SYNC_BYTE && p[1] >= MIN_PACKET_LEN;
 
         'reparse: loop {
@@ -56,21 +62,23 @@ impl PacketBuilder {
                 // If the checksum doesn't match, assume these are spurious bytes and attempt to reparse one position forward
                 if p[packet_size - 1..=packet_size] != checksum {
                     trace_packet!(
-                        "Checksum mismatch: {:?} vs {:?}",
+                        "Checksum mismatch, dropping {}: {:?} vs {:?}",
+                        hexdump(&p[..=packet_size]),
                         &p[packet_size - 1..=packet_size],
                         checksum
                     );
                     self.offset += 1;
                     continue 'reparse;
                 }
-                // We have a full packet, so take what we need
+                // We have a full packet, so take what we need and keep the rest for the next packet
                 let offset = std::mem::take(&mut self.offset);
-                let packet_buffer = std::mem::take(&mut self.packet_buffer);
                 // Optimization: we have exactly the packet we wanted, so just return the buffer
-                if offset == 0 && packet_buffer.len() == packet_size + 1 {
-                    return Some(packet_buffer);
+                if offset == 0 && self.packet_buffer.len() == packet_size + 1 {
+                    return Some(std::mem::take(&mut self.packet_buffer));
                 }
-                return Some(packet_buffer[offset..=offset + packet_size].to_vec());
+                let packet = self.packet_buffer[offset..=offset + packet_size].to_vec();
+                self.packet_buffer.drain(..=offset + packet_size);
+                return Some(packet);
             }
 
             break;
@@ -92,9 +100,11 @@ where
     stream! {
         let mut p = PacketBuilder::new();
         while let Some(m) = n.next().await {
-            trace_packet!("{{device->host}} {}", hexdump(&m));
-            if let Some(v) = p.accumulate(&m) {
+            trace_frame!(TraceDirection::DeviceToHost, &m);
+            let mut next = p.accumulate(&m);
+            while let Some(v) = next {
                 yield v;
+                next = p.next_packet();
             }
         }
         trace_shutdown!("packet_stream()");
@@ -117,7 +127,7 @@ mod test {
         assert!(p.is_empty());
     }
 
-    /// Test that extra bytes are tossed away
+    /// Test that extra bytes are kept for the next packet
     #[rstest]
     #[case(vec![SYNC_BYTE, 4, 10, 25, 22, 99])]
     #[case(vec![SYNC_BYTE, 5, 10, 20, 240, 157, 99, 99, 99])]
@@ -126,6 +136,30 @@ mod test {
         let len = bytes[1] as usize;
         let out = bytes[0..len + 1].to_vec();
         assert_eq!(Some(out), p.accumulate(&bytes));
+        assert_eq!(p.current_packet(), &bytes[len + 1..]);
+    }
+
+    /// Test that a chunk holding the end of one packet and the start of the next carries over into the next chunk.
+    #[test]
+    fn packet_leftover_carries_over() {
+        let first = vec![SYNC_BYTE, 4, 10, 25, 22];
+        let second = vec![SYNC_BYTE, 5, 10, 20, 240, 157];
+        let mut p = PacketBuilder::new();
+        assert!(p.accumulate(&first[..3]).is_none());
+        assert_eq!(
+            Some(first.clone()),
+            p.accumulate(&[&first[3..], &second[..2]].concat())
+        );
+        assert_eq!(Some(second.clone()), p.accumulate(&second[2..]));
+        assert!(p.is_empty());
+
+        // Two packets in a single chunk
+        assert_eq!(
+            Some(first.clone()),
+            p.accumulate(&[first.as_slice(), &second].concat())
+        );
+        assert_eq!(Some(second), p.next_packet());
+        assert!(p.next_packet().is_none());
         assert!(p.is_empty());
     }
 
@@ -140,9 +174,7 @@ mod test {
         ];
         let expected = packet.clone();
         if garbage_before {
-            let mut tmp = vec![1, 2, 3];
-            tmp.splice(0..0, packet);
-            packet = tmp;
+            packet.splice(0..0, [1, 2, 3]);
         }
         if garbage_after {
             packet.extend_from_slice(&[1, 2, 3]);
@@ -151,7 +183,64 @@ mod test {
             let mut p = PacketBuilder::new();
             assert!(p.accumulate(&packet[..i]).is_none());
             assert_eq!(Some(expected.clone()), p.accumulate(&packet[i..]));
-            assert!(p.is_empty());
+            assert_eq!(p.is_empty(), !garbage_after);
+        }
+    }
+
+    /// Ensure that we parse a packet split across three chunks, wherever it is split.
+    #[test]
+    fn packet_three_chunks() {
+        let packet = vec![
+            SYNC_BYTE, 18, 117, 15, 1, 1, 0, 0, 0, 0, 3, 100, 0, 0, 0, 0, 0, 214, 150,
+        ];
+        for i in 0..packet.len() {
+            for j in i..packet.len() {
+                let mut p = PacketBuilder::new();
+                assert!(p.accumulate(&packet[..i]).is_none());
+                assert!(p.accumulate(&packet[i..j]).is_none());
+                assert_eq!(Some(packet.clone()), p.accumulate(&packet[j..]));
+                assert!(p.is_empty());
+            }
         }
     }
+
+    #[tokio::test]
+    async fn packets_reassembled_from_stream() {
+        let first = vec![SYNC_BYTE, 4, 10, 25, 22];
+        let second = vec![
+            SYNC_BYTE, 18, 117, 15, 1, 1, 0, 0, 0, 0, 3, 100, 0, 0, 0, 0, 0, 214, 150,
+        ];
+        let chunks = vec![
+            [first.as_slice(), &second[..4]].concat(),
+            second[4..12].to_vec(),
+            [&second[12..], first.as_slice()].concat(),
+        ];
+        let packets: Vec<_> = packet_stream(futures::stream::iter(chunks)).collect().await;
+        assert_eq!(packets, vec![first.clone(), second, first]);
+    }
+
+    /// Test that a packet with a corrupted byte is discarded, and that the following packet is still decoded.
+    #[rstest]
+    #[case(2)]
+    #[case(10)]
+    #[case(18)]
+    fn corrupted_packet_discarded(#[case] flipped: usize) {
+        let mut corrupted = vec![
+            SYNC_BYTE, 18, 117, 15, 1, 1, 0, 0, 0, 0, 3, 100, 0, 0, 0, 0, 0, 214, 150,
+        ];
+        corrupted[flipped] ^= 0xff;
+        let next = vec![SYNC_BYTE, 4, 10, 25, 22];
+        let mut p = PacketBuilder::new();
+        assert!(p.accumulate(&corrupted).is_none());
+        assert_eq!(Some(next.clone()), p.accumulate(&next));
+        assert!(p.is_empty());
+    }
+
+    #[tokio::test]
+    async fn corrupted_packet_not_streamed() {
+        let next = vec![SYNC_BYTE, 4, 10, 25, 22];
+        let chunks = vec![vec![SYNC_BYTE, 4, 10, 26, 22], next.clone()];
+        let packets: Vec<_> = packet_stream(futures::stream::iter(chunks)).collect().await;
+        assert_eq!(packets, vec![next]);
+    }
 }
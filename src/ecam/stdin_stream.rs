@@ -81,13 +81,22 @@ macro_rules! spawn_loop {
 }
 
 /// Pipes an EcamDriver to/from stdio.
-pub async fn pipe_stdin<T: EcamDriver + 'static>(
-    ecam: T,
-) -> Result<(), Box<dyn std::error::Error>> {
+pub async fn pipe_stdin(ecam: Box<dyn EcamDriver>) -> Result<(), Box<dyn std::error::Error>> {
     let mut bt_out = Box::pin(packet_stdio_stream());
-    let ecam = Arc::new(Box::new(ecam));
+    let ecam: Arc<dyn EcamDriver> = Arc::from(ecam);
     let (tx, rx) = std::sync::mpsc::sync_channel(1);
 
+    // Describe the device before anything else, so the host has it by the time the device is ready. The host waits
+    // for this line, so send `null` if the device can't be described.
+    let info = match ecam.info().await {
+        Ok(info) => Some(info),
+        Err(e) => {
+            trace_packet!("Unable to describe the device: {}", e);
+            None
+        }
+    };
+    println!("I: {}", serde_json::to_string(&info)?);
+
     // Watchdog timer: if we don't get _some_ event within the timeout, we assume that things havegone sideways
     // in the underlying driver.
     std::thread::spawn(move || {
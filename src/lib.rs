@@ -59,10 +59,13 @@
 //! ![Demo of brewing a cappuccino](https://user-images.githubusercontent.com/512240/200137316-a09304e8-b34a-41ff-a847-af71af521ef8.gif)
 #![warn(clippy::all)]
 
+pub mod config;
 pub mod display;
 pub mod ecam;
 pub mod logging;
 pub mod operations;
 mod prelude;
 pub mod protocol;
+#[cfg(unix)]
+pub mod status_socket;
 pub mod util;
@@ -1,19 +1,248 @@
 //! Logging utilities.
 
+use crate::display::LogLevel;
+use crate::protocol::hexdump;
+use lazy_static::lazy_static;
+use std::io::Write;
+use std::path::Path;
 use std::sync::atomic::AtomicBool;
-pub(crate) static TRACE_ENABLED: AtomicBool = AtomicBool::new(false);
+use std::sync::Mutex;
+use thiserror::Error;
+
+#[doc(hidden)]
+pub static TRACE_ENABLED: AtomicBool = AtomicBool::new(false);
+#[doc(hidden)]
+pub static TRACE_FILE_ENABLED: AtomicBool = AtomicBool::new(false);
+static TRACE_FORWARD_ENABLED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref TRACE_FILTER: Mutex<TraceFilterState> = Mutex::new(Default::default());
+    static ref TRACE_FILE: Mutex<Option<std::fs::File>> = Mutex::new(None);
+}
 
 /// Enable tracing display to standard error.
 pub fn enable_tracing() {
     TRACE_ENABLED.store(true, std::sync::atomic::Ordering::Relaxed);
 }
 
-/// Writes a trace of the given communication packet or event if [`enable_tracing`] has been called.
+/// Writes every traced frame to a file, replacing the file if it exists. This works with or without
+/// [`enable_tracing`], and the [`TraceFilter`] applies to both.
+pub fn set_trace_file<P: AsRef<Path>>(path: P) -> std::io::Result<()> {
+    let file = std::fs::File::create(path)?;
+    if let Ok(mut trace_file) = TRACE_FILE.lock() {
+        *trace_file = Some(file);
+        TRACE_FILE_ENABLED.store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+/// Writes traced frames to stdout as `T: <direction> <hex>` lines rather than tracing them here, for the process on the
+/// other end of [`crate::ecam::pipe_stdin`] to filter and trace.
+pub fn forward_frames() {
+    TRACE_FORWARD_ENABLED.store(true, std::sync::atomic::Ordering::Relaxed);
+}
+
+/// The direction a traced frame travelled in.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TraceDirection {
+    HostToDevice,
+    DeviceToHost,
+}
+
+impl TraceDirection {
+    fn tag(&self) -> &'static str {
+        match self {
+            Self::HostToDevice => "{host->device}",
+            Self::DeviceToHost => "{device->host}",
+        }
+    }
+
+    /// The direction as shown in traces, ie: `host->device`.
+    pub fn name(&self) -> &'static str {
+        let tag = self.tag();
+        &tag[1..tag.len() - 1]
+    }
+
+    /// The direction with the given [`TraceDirection::name`].
+    pub fn from_name(name: &str) -> Option<Self> {
+        [Self::HostToDevice, Self::DeviceToHost]
+            .into_iter()
+            .find(|direction| direction.name() == name)
+    }
+}
+
+/// Limits the raw frames shown by the packet trace. Trace lines that aren't frames are always shown.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct TraceFilter {
+    /// Only show frames travelling in this direction.
+    pub direction: Option<TraceDirection>,
+    /// Only show frames with this leading opcode byte (ie: the [`crate::protocol::EcamRequestId`]).
+    pub opcode: Option<u8>,
+}
+
+/// The filter passed to [`TraceFilter::from_str`] wasn't understood.
+#[derive(Clone, Debug, Eq, PartialEq, Error)]
+#[error("invalid trace filter '{0}', expected a comma-separated list of host, device or an opcode (ie: 0x75)")]
+pub struct TraceFilterParseError(pub String);
+
+impl std::str::FromStr for TraceFilter {
+    type Err = TraceFilterParseError;
+
+    /// Parses a comma-separated list of `host` (host->device frames), `device` (device->host frames) and opcodes
+    /// in hex (`0x75`) or decimal (`117`).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut filter = TraceFilter::default();
+        for part in s.split(',').map(str::trim) {
+            let err = || TraceFilterParseError(part.to_owned());
+            match part {
+                "host" => filter.direction = Some(TraceDirection::HostToDevice),
+                "device" => filter.direction = Some(TraceDirection::DeviceToHost),
+                _ => {
+                    let opcode = if let Some(hex) = part.strip_prefix("0x") {
+                        u8::from_str_radix(hex, 16)
+                    } else {
+                        part.parse()
+                    };
+                    filter.opcode = Some(opcode.map_err(|_| err())?);
+                }
+            }
+        }
+        Ok(filter)
+    }
+}
+
+/// The filter along with the decisions made for the last frame in each direction, as frames may be split across
+/// several trace lines.
+struct TraceFilterState {
+    filter: TraceFilter,
+    last_allowed: [bool; 2],
+}
+
+impl Default for TraceFilterState {
+    fn default() -> Self {
+        TraceFilterState {
+            filter: TraceFilter::default(),
+            last_allowed: [true; 2],
+        }
+    }
+}
+
+impl TraceFilterState {
+    fn allows(&mut self, direction: TraceDirection, bytes: &[u8]) -> bool {
+        if matches!(self.filter.direction, Some(d) if d != direction) {
+            return false;
+        }
+        // Frames start with a sync byte and length, followed by the opcode. Anything else is the continuation of the
+        // previous frame.
+        let i = direction as usize;
+        if let [0x0d | 0xd0, _, opcode, ..] = bytes[..] {
+            self.last_allowed[i] = !matches!(self.filter.opcode, Some(o) if o != opcode);
+        }
+        self.last_allowed[i]
+    }
+}
+
+/// Sets the filter applied to raw frames in the packet trace.
+pub fn set_trace_filter(filter: TraceFilter) {
+    if let Ok(mut state) = TRACE_FILTER.lock() {
+        *state = TraceFilterState {
+            filter,
+            ..Default::default()
+        };
+    }
+}
+
+/// Formats a line of the trace file: the time, the direction and the bytes in hex, ie:
+/// `2022-11-05T14:23:01.123Z host->device 0d05750fda25`.
+fn format_trace_file_line(
+    time: chrono::DateTime<chrono::Utc>,
+    direction: TraceDirection,
+    bytes: &[u8],
+) -> String {
+    format!(
+        "{} {} {}",
+        time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        direction.name(),
+        hex::encode(bytes)
+    )
+}
+
+/// Writes the frame to the file set by [`set_trace_file`].
+fn trace_to_file(direction: TraceDirection, bytes: &[u8]) {
+    if let Ok(mut trace_file) = TRACE_FILE.lock() {
+        if let Some(file) = trace_file.as_mut() {
+            // Flush every frame so a crash still leaves a usable trace
+            let line = format_trace_file_line(chrono::Utc::now(), direction, bytes);
+            let _ = writeln!(file, "{}", line).and_then(|_| file.flush());
+        }
+    }
+}
+
+/// Returns true if frames are being traced in any way, so callers can skip building them otherwise.
+#[doc(hidden)]
+pub fn tracing_frames() -> bool {
+    use std::sync::atomic::Ordering::Relaxed;
+    TRACE_ENABLED.load(Relaxed)
+        || TRACE_FILE_ENABLED.load(Relaxed)
+        || TRACE_FORWARD_ENABLED.load(Relaxed)
+}
+
+/// Traces a raw frame (or part of one) travelling in the given direction, if it passes the filter set by
+/// [`set_trace_filter`]. Use [`trace_frame!`] rather than calling this directly.
+#[doc(hidden)]
+pub fn trace_frame(target: &str, direction: TraceDirection, bytes: &[u8], note: Option<&str>) {
+    if TRACE_FORWARD_ENABLED.load(std::sync::atomic::Ordering::Relaxed) {
+        println!("T: {} {}", direction.name(), hex::encode(bytes));
+        return;
+    }
+    let display = TRACE_ENABLED.load(std::sync::atomic::Ordering::Relaxed);
+    let file = TRACE_FILE_ENABLED.load(std::sync::atomic::Ordering::Relaxed);
+    let allowed = TRACE_FILTER
+        .lock()
+        .map_or(true, |mut state| state.allows(direction, bytes));
+    if !allowed {
+        return;
+    }
+    if file {
+        trace_to_file(direction, bytes);
+    }
+    if display {
+        let mut s = format!("{} {}", direction.tag(), hexdump(bytes));
+        if let Some(note) = note {
+            s += &format!(" ({})", note);
+        }
+        crate::display::log_frame(LogLevel::Trace, target, &s, direction, bytes);
+    }
+}
+
+/// Writes a trace of the given raw frame if [`enable_tracing`] has been called, and to the trace file if
+/// [`set_trace_file`] has been called. The bytes are only evaluated when frames are being traced.
+#[macro_export]
+macro_rules! trace_frame {
+    (@note $direction:expr, $bytes:expr, $note:expr) => {{
+        if $crate::logging::tracing_frames() {
+            $crate::logging::trace_frame(std::module_path!(), $direction, $bytes, $note);
+        }
+    }};
+    ($direction:expr, $bytes:expr) => {
+        $crate::trace_frame!(@note $direction, $bytes, None)
+    };
+    ($direction:expr, $bytes:expr, $note:literal) => {
+        $crate::trace_frame!(@note $direction, $bytes, Some($note))
+    };
+}
+
+/// Writes a trace of the given communication packet or event if [`enable_tracing`] has been called. Raw frames should
+/// use [`crate::trace_frame!`] instead, so they can be filtered and written to the trace file.
 #[macro_export]
 macro_rules! trace_packet {
     ($($arg:tt)*) => {{
         if $crate::logging::TRACE_ENABLED.load(std::sync::atomic::Ordering::Relaxed) {
-            $crate::display::log($crate::display::LogLevel::Trace, &format!("{}", std::format!($($arg)*)));
+            $crate::display::log_from(
+                $crate::display::LogLevel::Trace,
+                std::module_path!(),
+                &std::format!($($arg)*),
+            );
         }
     }};
 }
@@ -23,20 +252,40 @@ macro_rules! trace_packet {
 macro_rules! trace_shutdown {
     ($arg:literal) => {{
         if $crate::logging::TRACE_ENABLED.load(std::sync::atomic::Ordering::Relaxed) {
-            $crate::display::log(
+            $crate::display::log_from(
                 $crate::display::LogLevel::Trace,
+                std::module_path!(),
                 &format!("[SHUTDOWN] {}", $arg),
             );
         }
     }};
 }
 
+/// Writes diagnostic text for the given event to stderr if [`enable_tracing`] has been called. Unlike
+/// [`crate::trace_frame!`], this is never filtered or written to the trace file.
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => {{
+        if $crate::logging::TRACE_ENABLED.load(std::sync::atomic::Ordering::Relaxed) {
+            $crate::display::log_from(
+                $crate::display::LogLevel::Debug,
+                std::module_path!(),
+                &std::format!($($arg)*),
+            );
+        }
+    }};
+}
+
 /// Writes a warning of the given event if [`enable_tracing`] has been called.
 #[macro_export]
 macro_rules! warning {
     ($($arg:tt)*) => {{
         if $crate::logging::TRACE_ENABLED.load(std::sync::atomic::Ordering::Relaxed) {
-            $crate::display::log($crate::display::LogLevel::Warning, &std::format!($($arg)*));
+            $crate::display::log_from(
+                $crate::display::LogLevel::Warning,
+                std::module_path!(),
+                &std::format!($($arg)*),
+            );
         }
     }};
 }
@@ -45,6 +294,84 @@ macro_rules! warning {
 #[macro_export]
 macro_rules! info {
     ($($arg:tt)*) => {{
-        $crate::display::log($crate::display::LogLevel::Info, &std::format!($($arg)*));
+        $crate::display::log_from(
+            $crate::display::LogLevel::Info,
+            std::module_path!(),
+            &std::format!($($arg)*),
+        );
     }};
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::*;
+
+    #[rstest]
+    #[case("host", TraceFilter { direction: Some(TraceDirection::HostToDevice), opcode: None })]
+    #[case("device,0x75", TraceFilter { direction: Some(TraceDirection::DeviceToHost), opcode: Some(0x75) })]
+    #[case("132", TraceFilter { direction: None, opcode: Some(132) })]
+    fn parse_filter(#[case] s: &str, #[case] expected: TraceFilter) {
+        assert_eq!(s.parse::<TraceFilter>(), Ok(expected));
+    }
+
+    #[rstest]
+    #[case("sideways")]
+    #[case("0x100")]
+    fn parse_filter_error(#[case] s: &str) {
+        assert!(s.parse::<TraceFilter>().is_err());
+    }
+
+    #[test]
+    fn trace_file_line() {
+        use chrono::TimeZone;
+        let time = chrono::Utc
+            .with_ymd_and_hms(2022, 11, 5, 14, 23, 1)
+            .unwrap()
+            + chrono::Duration::milliseconds(20);
+        assert_eq!(
+            format_trace_file_line(
+                time,
+                TraceDirection::DeviceToHost,
+                &[0xd0, 0x07, 0x84, 0xf0]
+            ),
+            "2022-11-05T14:23:01.020Z device->host d00784f0"
+        );
+    }
+
+    #[test]
+    fn direction_names() {
+        for direction in [TraceDirection::HostToDevice, TraceDirection::DeviceToHost] {
+            assert_eq!(TraceDirection::from_name(direction.name()), Some(direction));
+        }
+        assert_eq!(TraceDirection::from_name("sideways"), None);
+    }
+
+    #[test]
+    fn filter_frames() {
+        use TraceDirection::*;
+
+        // Everything is shown without a filter
+        let mut state = TraceFilterState::default();
+        assert!(state.allows(DeviceToHost, &[0x00, 0x00, 0x00]));
+
+        let mut state = TraceFilterState {
+            filter: "0x75".parse().unwrap(),
+            ..Default::default()
+        };
+        assert!(state.allows(HostToDevice, &[0x0d, 0x05, 0x75, 0x0f, 0xda, 0x25]));
+        assert!(!state.allows(
+            HostToDevice,
+            &[0x0d, 0x07, 0x84, 0x0f, 0x02, 0x01, 0x55, 0x12]
+        ));
+        // Continuations follow the frame they belong to
+        assert!(state.allows(DeviceToHost, &[0xd0, 0x12, 0x75, 0x0f, 0x01, 0x05]));
+        assert!(state.allows(DeviceToHost, &[0x00, 0x00, 0x00]));
+        assert!(!state.allows(DeviceToHost, &[0xd0, 0x07, 0x84, 0xf0]));
+        assert!(!state.allows(DeviceToHost, &[0x00, 0x00, 0x00]));
+
+        state.filter = "device".parse().unwrap();
+        assert!(!state.allows(HostToDevice, &[0x0d, 0x05, 0x75, 0x0f, 0xda, 0x25]));
+        assert!(state.allows(DeviceToHost, &[0xd0, 0x07, 0x84, 0xf0]));
+    }
+}
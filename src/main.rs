@@ -1,30 +1,201 @@
 #![warn(clippy::all)]
 use clap::builder::{PossibleValue, PossibleValuesParser};
-use clap::{arg, command, Arg, ArgMatches};
+use clap::{arg, command, Arg, ArgGroup, ArgMatches};
+use clap_complete::Shell;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
 
 mod app;
 
+use longshot::config::Config;
 use longshot::ecam::{
-    ecam_lookup, ecam_scan, get_ecam_simulator, pipe_stdin, Ecam, EcamBT, EcamError,
+    ecam_scan_all, get_ecam_driver, pipe_stdin, DeviceInfo, Ecam, EcamError, EcamStatus,
+    CHARACTERISTIC_UUID, DEFAULT_SCAN_DEADLINE, DEFAULT_SCAN_TIMEOUT, SERVICE_UUID,
 };
+#[cfg(unix)]
+use longshot::status_socket::StatusSocket;
 use longshot::{operations::*, protocol::*};
 
+/// State collected while running a command, printed at exit when `--dump-state-on-exit` is passed.
+#[derive(Default)]
+struct ExitDiagnostics {
+    enabled: bool,
+    device_name: Option<String>,
+    ecam: Option<Ecam>,
+    device_info: Option<DeviceInfo>,
+    last_error: Option<String>,
+}
+
+lazy_static! {
+    static ref EXIT_DIAGNOSTICS: Mutex<ExitDiagnostics> = Mutex::new(ExitDiagnostics::default());
+}
+
+/// Drop-guard that prints the [`ExitDiagnostics`] however `main` exits.
+struct DumpStateOnExit;
+
+impl Drop for DumpStateOnExit {
+    fn drop(&mut self) {
+        dump_state_on_exit();
+    }
+}
+
+/// Prints a compact diagnostic snapshot to stderr, if enabled.
+fn dump_state_on_exit() {
+    let diagnostics = match EXIT_DIAGNOSTICS.lock() {
+        Ok(diagnostics) => diagnostics,
+        Err(_) => return,
+    };
+    if !diagnostics.enabled {
+        return;
+    }
+    eprintln!(
+        "[STATE] device: {}",
+        diagnostics.device_name.as_deref().unwrap_or("(none)")
+    );
+    if let Some(info) = &diagnostics.device_info {
+        eprintln!(
+            "[STATE] name: {} id: {} rssi: {}",
+            info.name,
+            info.id,
+            info.rssi
+                .map(|rssi| rssi.to_string())
+                .unwrap_or_else(|| "(unknown)".to_owned())
+        );
+        eprintln!(
+            "[STATE] service: {} characteristic: {}",
+            info.service_uuid, info.characteristic_uuid
+        );
+    } else {
+        eprintln!(
+            "[STATE] service: {} characteristic: {}",
+            SERVICE_UUID, CHARACTERISTIC_UUID
+        );
+    }
+    if let Some(ecam) = &diagnostics.ecam {
+        eprintln!(
+            "[STATE] connection: {}",
+            if ecam.is_alive() { "alive" } else { "closed" }
+        );
+        if let Some(status) = ecam.last_monitor_response() {
+            eprintln!("[STATE] status: {:?}", EcamStatus::extract(&status));
+            eprintln!("[STATE] monitor: {:?}", status);
+            eprintln!("[STATE] monitor (raw): {}", hexdump(&status.encode()));
+        } else {
+            eprintln!("[STATE] monitor: (no status received)");
+        }
+    } else {
+        eprintln!("[STATE] connection: (not connected)");
+    }
+    eprintln!(
+        "[STATE] last error: {}",
+        diagnostics.last_error.as_deref().unwrap_or("(none)")
+    );
+}
+
+/// Errors raised by the command-line front-end itself.
+#[derive(Debug, Error)]
+enum CliError {
+    #[error("the configuration directory couldn't be found (set $HOME or $XDG_CONFIG_HOME)")]
+    NoConfigDir,
+}
+
+/// The exit code for any failure reported by [`run`].
+const ERROR_EXIT_CODE: i32 = 1;
+
+/// A top-level failure, printed as a single JSON object to stderr when `--json-errors` is passed. The `code` is stable
+/// and safe for scripts to match on, while the `message` is for humans.
+#[derive(Debug, Eq, PartialEq, Serialize)]
+struct ErrorReport {
+    code: &'static str,
+    message: String,
+}
+
+impl ErrorReport {
+    fn new(e: &(dyn std::error::Error + 'static)) -> Self {
+        ErrorReport {
+            code: Self::code(e),
+            message: e.to_string(),
+        }
+    }
+
+    fn code(e: &(dyn std::error::Error + 'static)) -> &'static str {
+        if let Some(e) = e.downcast_ref::<EcamError>() {
+            return match e {
+                EcamError::NotFound => "not_found",
+                EcamError::AccessoryDetached(_) => "accessory_detached",
+                EcamError::Timeout => "timeout",
+                EcamError::DeviceInUse => "device_in_use",
+                EcamError::Cancelled => "cancelled",
+                EcamError::NotSupported(_) => "not_supported",
+                EcamError::UnsupportedBeverage(_) => "unsupported_beverage",
+                EcamError::MissingRecipeVariant(..) => "missing_recipe_variant",
+                EcamError::NotApplied(_) => "not_applied",
+                EcamError::UnsafeParameter(_) => "unsafe_parameter",
+                EcamError::GroundsContainerFull => "grounds_container_full",
+                EcamError::Busy(_) => "busy",
+                EcamError::PacketTooLarge(_) => "packet_too_large",
+                EcamError::Disconnected => "disconnected",
+                EcamError::ProtocolError(_) => "protocol_error",
+                EcamError::NotReady(_) => "not_ready",
+                EcamError::BTError(_) => "bluetooth",
+                EcamError::IOError(_) => "io",
+                EcamError::Unknown => "unknown",
+            };
+        }
+        if let Some(e) = e.downcast_ref::<CliError>() {
+            return match e {
+                CliError::NoConfigDir => "no_config_dir",
+            };
+        }
+        if e.is::<IngredientParseError>() || e.is::<TimeParseError>() {
+            return "invalid_argument";
+        }
+        "unknown"
+    }
+
+    fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("Failed to serialize error")
+    }
+}
+
+/// Parses an ingredient amount the same way as recipe files, so that out-of-range amounts report the allowed range.
+fn amount_parser(
+    key: &'static str,
+) -> impl Fn(&str) -> Result<BrewIngredientInfo, IngredientParseError> + Clone {
+    move |s| BrewIngredientInfo::from_arg(key, s)
+}
+
 fn enum_value_parser<T: MachineEnumerable<T> + 'static>() -> PossibleValuesParser {
     PossibleValuesParser::new(T::all().map(|x| PossibleValue::new(x.to_arg_string())))
 }
 
+/// The environment variable providing the device name when `--device-name` isn't given.
+const DEVICE_NAME_ENV: &str = "LONGSHOT_DEVICE";
+
+/// The `--timeout` we suggest, long enough for a large beverage after a cold start and startup rinse.
+const RECOMMENDED_TIMEOUT: Duration = Duration::from_secs(600);
+
 struct DeviceCommon {
     device_name: String,
     dump_packets: bool,
     turn_on: bool,
     allow_off: bool,
+    scan_timeout: Duration,
+    record: Option<PathBuf>,
+    percentage_scale: Option<PercentageScale>,
 }
 
 impl DeviceCommon {
-    fn args() -> [Arg; 4] {
+    fn args() -> [Arg; 7] {
         [
             arg!(--"device-name" <name>)
                 .help("Provides the name of the device")
+                .env(DEVICE_NAME_ENV)
                 .required(true),
             arg!(--"dump-packets").help("Dumps decoded packets to the terminal for debugging"),
             arg!(--"turn-on")
@@ -34,71 +205,264 @@ impl DeviceCommon {
                 .hide(true)
                 .help("Allow brewing while machine is off")
                 .conflicts_with("turn-on"),
+            scan_timeout_arg().help(format!(
+                "How long to scan for the device, in seconds (default {})",
+                DEFAULT_SCAN_DEADLINE.as_secs()
+            )),
+            arg!(--"record" <file>)
+                .help("Record every packet sent to and received from the device to a file")
+                .value_parser(clap::value_parser!(PathBuf)),
+            arg!(--"percentage-scale" <scale>)
+                .help("The scale the machine reports progress percentages on, if it isn't the usual 0 to 100")
+                .value_parser(["percent", "byte"]),
         ]
     }
 
     fn parse(cmd: &ArgMatches) -> Self {
         Self {
-            device_name: cmd
-                .get_one::<String>("device-name")
-                .expect("Device name required")
-                .clone(),
+            device_name: resolve_device_alias(
+                cmd.get_one::<String>("device-name")
+                    .expect("Device name required"),
+            ),
             dump_packets: cmd.get_flag("dump-packets"),
             turn_on: cmd.get_flag("turn-on"),
             allow_off: cmd.get_flag("allow-off"),
+            scan_timeout: cmd
+                .get_one::<Duration>("scan-timeout")
+                .copied()
+                .unwrap_or(DEFAULT_SCAN_DEADLINE),
+            record: cmd.get_one::<PathBuf>("record").cloned(),
+            percentage_scale: cmd
+                .get_one::<String>("percentage-scale")
+                .map(|s| match s.as_str() {
+                    "byte" => PercentageScale::Byte,
+                    _ => PercentageScale::Percent,
+                }),
+        }
+    }
+
+    /// The options to [`connect`] with. Only [`ConnectOptions::allow_alarms`] is left for the caller to decide.
+    fn connect_options(self) -> ConnectOptions {
+        ConnectOptions {
+            device_name: self.device_name,
+            dump_packets: self.dump_packets,
+            scan_timeout: self.scan_timeout,
+            record: self.record,
+            turn_on: self.turn_on,
+            allow_off: self.allow_off,
+            allow_alarms: false,
+            skip_power_on: false,
+            percentage_scale: self.percentage_scale,
         }
     }
 }
 
-async fn ecam(cmd: &ArgMatches, allow_off_and_alarms: bool) -> Result<Ecam, EcamError> {
-    let device_common = DeviceCommon::parse(cmd);
-    let ecam = ecam_lookup(&device_common.device_name, device_common.dump_packets).await?;
-    if !power_on(
-        ecam.clone(),
-        device_common.allow_off | allow_off_and_alarms,
-        allow_off_and_alarms,
-        device_common.turn_on,
-    )
-    .await?
-    {
-        longshot::display::shutdown();
-        std::process::exit(1);
+/// Resolves a device alias from the configuration file (see [`Config`]), passing anything else through unchanged.
+fn resolve_device_alias(name: &str) -> String {
+    match Config::default_path().map(Config::load).transpose() {
+        Ok(Some(config)) => config.resolve_device(name).to_owned(),
+        Ok(None) => name.to_owned(),
+        Err(e) => {
+            longshot::info!(
+                "Ignoring the configuration file, as it couldn't be read: {}",
+                e
+            );
+            name.to_owned()
+        }
+    }
+}
+
+fn scan_timeout_arg() -> Arg {
+    arg!(--"scan-timeout" <seconds>).value_parser(parse_seconds)
+}
+
+/// Parses a (possibly fractional) number of seconds.
+fn parse_seconds(s: &str) -> Result<Duration, Box<dyn std::error::Error + Send + Sync>> {
+    Ok(Duration::try_from_secs_f64(s.parse()?)?)
+}
+
+fn timeout_arg() -> Arg {
+    arg!(--"timeout" <seconds>)
+        .help(format!(
+            "Give up if the operation takes longer than this, in seconds (default unlimited, {} is plenty for a brew)",
+            RECOMMENDED_TIMEOUT.as_secs()
+        ))
+        .value_parser(parse_seconds)
+}
+
+fn socket_arg() -> Arg {
+    arg!(--"socket" <path>)
+        .help("Write newline-delimited JSON status events to a Unix domain socket")
+}
+
+/// Parses a range of parameters (`<start>..<end>`, with an exclusive end), where each end is parsed as a parameter.
+fn parse_parameter_range(s: &str) -> Result<std::ops::Range<u16>, String> {
+    let (start, end) = s
+        .split_once("..")
+        .ok_or_else(|| "expected a range like 0x60..0x70".to_owned())?;
+    let start = parse_parameter_id(start).map_err(|e| e.to_string())?;
+    let end = parse_parameter_id(end).map_err(|e| e.to_string())?;
+    if start >= end {
+        return Err("the range is empty".to_owned());
+    }
+    Ok(start..end)
+}
+
+/// Parses a parameter, either by name (see [`ParameterId`]) or by raw ID.
+fn parse_parameter_id(s: &str) -> Result<u16, std::num::ParseIntError> {
+    if let Some(param) = ParameterId::lookup_by_name_case_insensitive(s) {
+        return Ok(param.id());
+    }
+    match s.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+}
+
+fn toggle_args() -> [Arg; 2] {
+    [
+        arg!([state])
+            .help("Whether to turn the setting on or off (shows the current setting if omitted)")
+            .value_parser(["on", "off"]),
+        arg!(--"force").help(
+            "Change the setting even though its parameter hasn't been confirmed on a real machine",
+        ),
+    ]
+}
+
+/// Binds the `--socket` status output if requested, routing all displayed statuses to it.
+#[cfg(unix)]
+fn bind_status_socket(cmd: &ArgMatches) -> Result<Option<Arc<StatusSocket>>, EcamError> {
+    if let Some(path) = cmd.get_one::<String>("socket") {
+        let socket = Arc::new(StatusSocket::bind(path)?);
+        let weak = Arc::downgrade(&socket);
+        longshot::display::add_status_sink(move |status| {
+            if let Some(socket) = weak.upgrade() {
+                socket.send(status);
+            }
+        });
+        Ok(Some(socket))
+    } else {
+        Ok(None)
+    }
+}
+
+#[cfg(not(unix))]
+fn bind_status_socket(cmd: &ArgMatches) -> Result<Option<Arc<()>>, EcamError> {
+    if cmd.get_one::<String>("socket").is_some() {
+        eprintln!("--socket is only supported on Unix platforms");
+        return Err(EcamError::Unknown);
+    }
+    Ok(None)
+}
+
+async fn ecam(
+    cmd: &ArgMatches,
+    allow_off_and_alarms: bool,
+) -> Result<Ecam, Box<dyn std::error::Error>> {
+    let mut options = DeviceCommon::parse(cmd).connect_options();
+    options.allow_alarms = allow_off_and_alarms;
+    ecam_with(options).await
+}
+
+/// Like [`ecam`], but with the given connection options.
+async fn ecam_with(options: ConnectOptions) -> Result<Ecam, Box<dyn std::error::Error>> {
+    if let Ok(mut diagnostics) = EXIT_DIAGNOSTICS.lock() {
+        diagnostics.device_name = Some(options.device_name.clone());
+    }
+    let (ecam, device_info) = connect(&options).await?;
+    if let Ok(mut diagnostics) = EXIT_DIAGNOSTICS.lock() {
+        if diagnostics.enabled {
+            diagnostics.ecam = Some(ecam.clone());
+            diagnostics.device_info = Some(device_info);
+        }
     }
     Ok(ecam)
 }
 
+/// Whether `--log-format json` was passed.
+fn json_logs(matches: &ArgMatches) -> bool {
+    matches.get_one::<String>("log-format").map(String::as_str) == Some("json")
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    println!("Hello, from longshot!");
-    pretty_env_logger::init();
-    longshot::display::initialize_display();
-
-    let matches = command!()
+    let mut command = command!()
         .arg(arg!(--"trace").help("Trace packets to/from device"))
+        .arg(
+            arg!(--"trace-file" <path>)
+                .help("Write each traced frame to a file, with a timestamp")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .group(
+            ArgGroup::new("tracing")
+                .args(["trace", "trace-file"])
+                .multiple(true),
+        )
+        .arg(
+            arg!(--"filter" <filter>)
+                .help("Only trace frames in one direction (host, device) and/or with a given opcode (ie: host,0x84)")
+                .requires("tracing")
+                .value_parser(|s: &str| s.parse::<longshot::logging::TraceFilter>()),
+        )
+        .arg(
+            arg!(--"log-format" <format>)
+                .help("Log as human-readable text, or as one JSON object per line on stderr")
+                .value_parser(["human", "json"])
+                .default_value("human"),
+        )
+        .arg(
+            arg!(--"json-errors")
+                .help("Print errors to stderr as a JSON object with a stable `code` and a `message`"),
+        )
+        .arg(
+            arg!(--"dump-state-on-exit")
+                .help("Print a diagnostic snapshot of the device state when the command exits"),
+        )
         .subcommand(
             command!("brew")
                 .about("Brew a coffee")
                 .args(&DeviceCommon::args())
                 .arg(
                     arg!(--"beverage" <name>)
-                        .required(true)
+                        .required_unless_present("recipe")
+                        .conflicts_with("recipe")
                         .help("The beverage to brew")
                         .value_parser(enum_value_parser::<EcamBeverageId>()),
                 )
+                .arg(
+                    arg!(--"recipe" <file>)
+                        .help("Brew the beverage and ingredients saved in a recipe file, overridden by any ingredients given")
+                        .value_parser(clap::value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--"save-recipe" <file>)
+                        .help("Save the recipe to a file once the beverage has been brewed")
+                        .value_parser(clap::value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--"variant" <variant>)
+                        .help("The stored variant of the recipe to use for unspecified ingredients")
+                        .default_value("1")
+                        .value_parser(
+                            clap::value_parser!(u8)
+                                .range(DEFAULT_RECIPE_VARIANT as i64..=MAX_RECIPE_VARIANT as i64),
+                        ),
+                )
                 .arg(
                     arg!(--"coffee" <amount>)
                         .help("Amount of coffee to brew")
-                        .value_parser(0..=2500),
+                        .value_parser(amount_parser("coffee")),
                 )
                 .arg(
                     arg!(--"milk" <amount>)
                         .help("Amount of milk to steam/pour")
-                        .value_parser(0..=2500),
+                        .value_parser(amount_parser("milk")),
                 )
                 .arg(
                     arg!(--"hotwater" <amount>)
                         .help("Amount of hot water to pour")
-                        .value_parser(0..=2500),
+                        .value_parser(amount_parser("hotwater")),
                 )
                 .arg(
                     arg!(--"taste" <taste>)
@@ -115,44 +479,305 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         .help("Allow brewing if some parameters are not specified"),
                 )
                 .arg(arg!(--"force").help("Allow brewing with parameters that do not validate"))
+                .arg(
+                    arg!(--"count" <n>)
+                        .help("Brew the beverage this many times, waiting for the machine to be ready between cups")
+                        .default_value("1")
+                        .value_parser(clap::value_parser!(u32).range(1..)),
+                )
                 .arg(
                     arg!(--"skip-brew")
                         .hide(true)
                         .help("Does everything except actually brew the beverage"),
-                ),
+                )
+                .arg(timeout_arg())
+                .arg(socket_arg()),
         )
         .subcommand(
             command!("monitor")
                 .about("Monitor the status of the device")
-                .args(&DeviceCommon::args()),
+                .args(&DeviceCommon::args())
+                .arg(timeout_arg())
+                .arg(socket_arg())
+                .arg(arg!(--"json").help("Print each status change to stdout as a line of JSON")),
         )
         .subcommand(
             command!("read-parameter")
                 .about("Read a parameter from the device")
                 .args(&DeviceCommon::args())
-                .arg(arg!(--"parameter" <parameter>).help("The parameter ID"))
-                .arg(arg!(--"length" <length>).help("The parameter length")),
+                .arg(
+                    arg!(--"parameter" <parameter>)
+                        .required_unless_present("range")
+                        .conflicts_with("range")
+                        .help("The parameter name, or ID (decimal, or hex with a 0x prefix)")
+                        .value_parser(parse_parameter_id),
+                )
+                .arg(
+                    arg!(--"range" <range>)
+                        .help("Read every parameter from <start>..<end> (end exclusive)")
+                        .value_parser(parse_parameter_range),
+                )
+                .arg(
+                    arg!(--"length" <length>)
+                        .required(true)
+                        .help("The parameter length")
+                        .value_parser(clap::value_parser!(u8)),
+                ),
+        )
+        .subcommand(
+            command!("set-parameter")
+                .about("Write a parameter to the device")
+                .args(&DeviceCommon::args())
+                .arg(
+                    arg!(--"parameter" <parameter>)
+                        .required(true)
+                        .help("The parameter name, or ID (decimal, or hex with a 0x prefix)")
+                        .value_parser(parse_parameter_id),
+                )
+                .arg(
+                    arg!(--"length" <length>)
+                        .required(true)
+                        .help("The parameter length, in two-byte blocks")
+                        .value_parser(clap::value_parser!(u8)),
+                )
+                .arg(
+                    arg!(--"value" <hex>)
+                        .required(true)
+                        .help("The value to write, as hex bytes")
+                        .value_parser(from_hex_str),
+                ),
+        )
+        .subcommand(
+            command!("send-raw")
+                .about("Send a hand-built payload to the device and print the responses")
+                .hide(true)
+                .args(&DeviceCommon::args())
+                .arg(
+                    arg!(--"hex" <bytes>)
+                        .required(true)
+                        .help("The payload to send as hex bytes, starting with the request ID")
+                        .value_parser(parse_raw_payload),
+                ),
+        )
+        .subcommand(
+            command!("probe-parameter")
+                .about("Read a parameter, optionally write a value, and read it back")
+                .hide(true)
+                .args(&DeviceCommon::args())
+                .arg(
+                    arg!(--"parameter" <parameter>)
+                        .required(true)
+                        .help("The parameter name, or ID (decimal, or hex with a 0x prefix)")
+                        .value_parser(parse_parameter_id),
+                )
+                .arg(
+                    arg!(--"length" <length>)
+                        .required(true)
+                        .help("The parameter length")
+                        .value_parser(clap::value_parser!(u8)),
+                )
+                .arg(
+                    arg!(--"value" <hex>)
+                        .help("The value to write, as hex bytes")
+                        .value_parser(from_hex_str),
+                )
+                .arg(arg!(--"force").help("Allow writes to parameters that are known to be unsafe")),
+        )
+        .subcommand(
+            command!("clock")
+                .about("Show or set the machine's clock (unconfirmed)")
+                .visible_alias("set-clock")
+                .args(&DeviceCommon::args())
+                .arg(
+                    arg!(--"show")
+                        .help("Show the machine's clock (the default)")
+                        .conflicts_with_all(["set", "now"]),
+                )
+                .arg(
+                    arg!(--"set" <time>)
+                        .help("Set the machine's clock to the given time (HH:MM), or `now` for the current time"),
+                )
+                .arg(
+                    arg!(--"now")
+                        .help("Set the machine's clock to the host's local time")
+                        .conflicts_with("set"),
+                )
+                .arg(
+                    arg!(--"force")
+                        .help("Set the clock even though its parameter hasn't been confirmed on a real machine"),
+                ),
+        )
+        .subcommand(
+            command!("energy-saving")
+                .about("Show or set the machine's energy saving mode (unconfirmed)")
+                .args(&DeviceCommon::args())
+                .args(toggle_args()),
+        )
+        .subcommand(
+            command!("cup-warmer")
+                .about("Show or set the machine's cup warmer (unconfirmed)")
+                .args(&DeviceCommon::args())
+                .args(toggle_args()),
+        )
+        .subcommand(
+            command!("startup-rinse")
+                .about("Show or set whether the machine rinses on startup (unconfirmed)")
+                .args(&DeviceCommon::args())
+                .args(toggle_args()),
+        )
+        .subcommand(
+            command!("http-server")
+                .about("Serve an HTTP API for brewing and checking the status of the device")
+                .args(DeviceCommon::args())
+                .arg(
+                    arg!(--"bind" <address>)
+                        .help("The address and port to listen on")
+                        .default_value("127.0.0.1:8080")
+                        .value_parser(clap::value_parser!(SocketAddr)),
+                )
+                .arg(
+                    arg!(--"metrics-addr" <address>)
+                        .help("Serve Prometheus metrics at /metrics on this address and port")
+                        .value_parser(clap::value_parser!(SocketAddr)),
+                ),
         )
         .subcommand(
             command!("list-recipes")
                 .about("List recipes stored in the device")
                 .args(&DeviceCommon::args())
                 .arg(arg!(--"detail").help("Show detailed ingredient information"))
-                .arg(arg!(--"raw").help("Show raw ingredient information")),
+                .arg(arg!(--"raw").help("Show raw ingredient information"))
+                .arg(
+                    arg!(--"json")
+                        .help("Output the recipes as a JSON array")
+                        .conflicts_with("detail"),
+                )
+                .arg(
+                    arg!(--"variants")
+                        .help("Also list the variants of each recipe stored in the device (slower)")
+                        .conflicts_with_all(["detail", "raw"]),
+                ),
+        )
+        .subcommand(
+            command!("supported-beverages")
+                .about("List the beverages this machine is able to make")
+                .visible_alias("available")
+                .args(&DeviceCommon::args())
+                .arg(arg!(--"json").help("Output the beverages as a JSON array")),
+        )
+        .subcommand(
+            command!("list")
+                .about("List all supported devices")
+                .arg(scan_timeout_arg().help(format!(
+                    "How long to scan for devices, in seconds (default {})",
+                    DEFAULT_SCAN_TIMEOUT.as_secs()
+                )))
+                .arg(
+                    arg!(--"save-aliases")
+                        .help("Save the devices found as aliases in the configuration file, for use with --device-name"),
+                ),
         )
-        .subcommand(command!("list").about("List all supported devices"))
         .subcommand(
             command!("x-internal-pipe")
                 .about("Used to communicate with the device")
                 .hide(true)
                 .args(&DeviceCommon::args()),
         )
-        .get_matches();
+        .subcommand(
+            command!("completions")
+                .about("Print a shell completion script to stdout")
+                .hide(true)
+                .arg(
+                    arg!(<shell>)
+                        .help("The shell to generate completions for")
+                        .value_parser(clap::value_parser!(Shell)),
+                ),
+        );
+    let matches = command.get_matches_mut();
+
+    // The completion script is written to stdout, so it must not be mixed with any other output
+    if let Some(("completions", cmd)) = matches.subcommand() {
+        let shell = *cmd.get_one::<Shell>("shell").unwrap();
+        let name = command.get_name().to_owned();
+        clap_complete::generate(shell, &mut command, name, &mut std::io::stdout());
+        return Ok(());
+    }
+
+    let json_logs = json_logs(&matches);
+    pretty_env_logger::init();
+    if json_logs {
+        longshot::display::initialize_json_display();
+    } else {
+        longshot::display::initialize_display();
+    }
 
     if matches.get_flag("trace") {
         longshot::logging::enable_tracing();
     }
+    if let Some(path) = matches.get_one::<PathBuf>("trace-file") {
+        longshot::logging::set_trace_file(path)?;
+    }
+    longshot::debug!("Hello, from longshot!");
+    if let Some(filter) = matches.get_one::<longshot::logging::TraceFilter>("filter") {
+        longshot::logging::set_trace_filter(filter.clone());
+    }
+
+    let _dump_state_on_exit = DumpStateOnExit;
+    if let Ok(mut diagnostics) = EXIT_DIAGNOSTICS.lock() {
+        diagnostics.enabled = matches.get_flag("dump-state-on-exit");
+    }
+
+    if let Err(e) = run_with_timeout(&matches).await {
+        if let Ok(mut diagnostics) = EXIT_DIAGNOSTICS.lock() {
+            diagnostics.last_error = Some(e.to_string());
+        }
+        longshot::display::shutdown();
+        if matches.get_flag("json-errors") {
+            eprintln!("{}", ErrorReport::new(&*e).to_json());
+        } else if json_logs {
+            longshot::display::log(longshot::display::LogLevel::Error, &e.to_string());
+        } else {
+            eprintln!("Error: {}", e);
+        }
+        drop(_dump_state_on_exit);
+        std::process::exit(ERROR_EXIT_CODE);
+    }
+    Ok(())
+}
+
+/// The `--timeout` given to `brew` or `monitor`, if any.
+fn command_timeout(cmd: &ArgMatches) -> Option<Duration> {
+    cmd.try_get_one::<Duration>("timeout")
+        .ok()
+        .flatten()
+        .copied()
+}
 
+/// Runs the command, failing with [`EcamError::Timeout`] if `monitor` takes longer than its `--timeout`. `brew`
+/// applies its own timeout, so that it can stop the machine before giving up. No other command takes a timeout, so
+/// servers and the internal pipe run for as long as they need to.
+async fn run_with_timeout(matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let timeout = match matches.subcommand() {
+        Some(("monitor", cmd)) => command_timeout(cmd),
+        _ => None,
+    };
+    match timeout {
+        None => run(matches).await,
+        Some(timeout) => match tokio::time::timeout(timeout, run(matches)).await {
+            Ok(res) => res,
+            Err(_) => {
+                longshot::display::clear_status();
+                longshot::info!(
+                    "The operation didn't finish within {} seconds",
+                    timeout.as_secs_f64()
+                );
+                Err(EcamError::Timeout.into())
+            }
+        },
+    }
+}
+
+async fn run(matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
     let subcommand = matches.subcommand();
     match subcommand {
         Some(("brew", cmd)) => {
@@ -160,21 +785,32 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             let allow_defaults = cmd.get_flag("allow-defaults");
             let force = cmd.get_flag("force");
 
-            let beverage: EcamBeverageId = EcamBeverageId::lookup_by_name_case_insensitive(
-                cmd.get_one::<String>("beverage").unwrap(),
-            )
-            .expect("Beverage required");
+            let (beverage, mut ingredients) = match cmd.get_one::<PathBuf>("recipe") {
+                Some(path) => {
+                    let recipe = SavedRecipe::load(path)?;
+                    (recipe.beverage, recipe.ingredients)
+                }
+                None => (
+                    EcamBeverageId::lookup_by_name_case_insensitive(
+                        cmd.get_one::<String>("beverage").unwrap(),
+                    )
+                    .expect("Beverage required"),
+                    vec![],
+                ),
+            };
+            let variant = *cmd.get_one::<u8>("variant").unwrap();
 
-            let mut ingredients = vec![];
             for arg in ["coffee", "milk", "hotwater", "taste", "temperature"] {
                 if let Some(value) = cmd.get_raw(arg) {
                     // Once clap has had a chance to validate the args, we go back to the underlying OsStr to parse it
                     let value = value.into_iter().next().unwrap().to_str().unwrap();
-                    if let Some(ingredient) = BrewIngredientInfo::from_arg(arg, value) {
-                        ingredients.push(ingredient);
-                    } else {
-                        eprintln!("Invalid value '{}' for argument '{}'", value, arg);
-                        return Ok(());
+                    match BrewIngredientInfo::from_arg(arg, value) {
+                        Ok(ingredient) => {
+                            // Ingredients given on the command line replace the ones from the recipe file
+                            ingredients.retain(|i| i.ingredient() != ingredient.ingredient());
+                            ingredients.push(ingredient);
+                        }
+                        Err(e) => return Err(e.into()),
                     }
                 }
             }
@@ -184,55 +820,333 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 (true, false) => IngredientCheckMode::AllowDefaults,
                 (false, false) => IngredientCheckMode::Strict,
             };
-            let ecam = ecam(cmd, false).await?;
-            let recipe = validate_brew(ecam.clone(), beverage, ingredients, mode).await?;
-            brew(ecam.clone(), skip_brew, beverage, recipe).await?;
+            let _socket = bind_status_socket(cmd)?;
+            let mut options = DeviceCommon::parse(cmd).connect_options();
+            // With --turn-on, we power on the machine ourselves and wait through its startup rinse
+            let turn_on = options.turn_on;
+            options.skip_power_on = turn_on;
+            let ecam = ecam_with(options).await?;
+            let count = *cmd.get_one::<u32>("count").unwrap();
+            let brewing = async {
+                if turn_on {
+                    turn_on_and_wait(ecam.clone(), BREW_WITH_TURN_ON_TIMEOUT).await?;
+                }
+                // The recipes are only fetched once the machine is ready
+                let recipe =
+                    validate_brew(ecam.clone(), beverage, variant, ingredients, mode).await?;
+                brew_repeatedly(
+                    ecam.clone(),
+                    skip_brew,
+                    beverage,
+                    recipe.clone(),
+                    count,
+                    None,
+                )
+                .await?;
+                Ok::<_, EcamError>(recipe)
+            };
+            let timeout = command_timeout(cmd);
+            let deadline = async {
+                match timeout {
+                    Some(timeout) => tokio::time::sleep(timeout).await,
+                    None => futures::future::pending().await,
+                }
+            };
+            // Ctrl-C is only intercepted while brewing, so that we can stop the machine before exiting
+            let recipe = tokio::select! {
+                res = brewing => res?,
+                _ = tokio::signal::ctrl_c() => {
+                    longshot::display::clear_status();
+                    if stop_brew(ecam.clone(), beverage).await? {
+                        longshot::info!("Interrupted, so we've asked the machine to stop the beverage");
+                    }
+                    ecam.close().await;
+                    return Err(EcamError::Cancelled.into());
+                }
+                _ = deadline => {
+                    longshot::display::clear_status();
+                    longshot::info!(
+                        "The brew didn't finish within {} seconds",
+                        timeout.unwrap_or_default().as_secs_f64()
+                    );
+                    if stop_brew(ecam.clone(), beverage).await? {
+                        longshot::info!("We've asked the machine to stop the beverage");
+                    }
+                    ecam.close().await;
+                    return Err(EcamError::Timeout.into());
+                }
+            };
+            if let Some(path) = cmd.get_one::<PathBuf>("save-recipe") {
+                SavedRecipe::from_recipe(beverage, &recipe).save(path)?;
+                longshot::info!("Saved the recipe to {}", path.display());
+            }
+            ecam.close().await;
         }
         Some(("monitor", cmd)) => {
+            let json = cmd.get_flag("json");
+            if json && !json_logs(matches) {
+                longshot::display::initialize_quiet_display();
+            }
+            let _socket = bind_status_socket(cmd)?;
+            let ecam = ecam(cmd, true).await?;
+            if json {
+                monitor_json(ecam.clone()).await?;
+            } else {
+                monitor(ecam.clone()).await?;
+            }
+            ecam.close().await;
+        }
+        Some(("http-server", cmd)) => {
             let ecam = ecam(cmd, true).await?;
-            monitor(ecam).await?;
+            app::serve(
+                ecam,
+                *cmd.get_one::<SocketAddr>("bind").unwrap(),
+                cmd.get_one::<SocketAddr>("metrics-addr").copied(),
+            )
+            .await?;
         }
-        Some(("list", _cmd)) => {
-            let (s, uuid) = ecam_scan().await?;
-            longshot::info!("{}  {}", s, uuid);
+        Some(("list", cmd)) => {
+            let scan_timeout = cmd
+                .get_one::<Duration>("scan-timeout")
+                .copied()
+                .unwrap_or(DEFAULT_SCAN_TIMEOUT);
+            let devices = ecam_scan_all(scan_timeout).await?;
+            if devices.is_empty() {
+                return Err(EcamError::NotFound.into());
+            }
+            for (s, uuid) in &devices {
+                longshot::info!("{}  {}", s, uuid);
+            }
+            if cmd.get_flag("save-aliases") {
+                let path = Config::default_path().ok_or(CliError::NoConfigDir)?;
+                let mut config = Config::load(&path)?;
+                let added = config.add_devices(&devices);
+                config.save(&path)?;
+                longshot::info!("Saved {} new device alias(es) to {}", added, path.display());
+            }
         }
         Some(("list-recipes", cmd)) => {
             let ecam = ecam(cmd, true).await?;
             let detailed = cmd.get_flag("detail");
             let raw = cmd.get_flag("raw");
+            let json = cmd.get_flag("json");
             if detailed {
                 list_recipes_detailed(ecam).await?;
             } else if raw {
-                list_recipes_raw(ecam).await?;
+                list_recipes_raw(ecam, json).await?;
             } else {
-                list_recipes(ecam).await?;
+                list_recipes(ecam, json, cmd.get_flag("variants")).await?;
             }
         }
+        Some((name @ ("energy-saving" | "cup-warmer" | "startup-rinse"), cmd)) => {
+            let toggle = match name {
+                "energy-saving" => Toggle::EnergySaving,
+                "cup-warmer" => Toggle::CupWarmer,
+                _ => Toggle::StartupRinse,
+            };
+            let state = cmd.get_one::<String>("state").map(String::as_str);
+            let ecam = ecam(cmd, true).await?;
+            if let Some(state) = state {
+                let enabled = state == "on";
+                set_toggle(ecam, toggle, enabled, cmd.get_flag("force")).await?;
+                longshot::info!("Turned {} {}", toggle.name(), state);
+            } else {
+                let enabled = read_toggle(ecam, toggle).await?;
+                longshot::info!(
+                    "The {} is {} (unconfirmed: its parameter hasn't been verified on a real machine)",
+                    toggle.name(),
+                    if enabled { "on" } else { "off" }
+                );
+            }
+        }
+        Some(("supported-beverages", cmd)) => {
+            let ecam = ecam(cmd, true).await?;
+            list_supported_beverages(ecam, cmd.get_flag("json")).await?;
+        }
         Some(("read-parameter", cmd)) => {
-            let parameter = cmd
-                .get_one::<String>("parameter")
-                .map(|s| s.parse::<u16>().expect("Invalid number"))
-                .expect("Required");
-            let length = cmd
-                .get_one::<String>("length")
-                .map(|s| s.parse::<u8>().expect("Invalid number"))
-                .expect("Required");
+            let length = *cmd.get_one::<u8>("length").expect("Required");
             let ecam = ecam(cmd, true).await?;
-            read_parameter(ecam, parameter, length).await?;
+            if let Some(range) = cmd.get_one::<std::ops::Range<u16>>("range") {
+                for (parameter, value) in read_parameter_range(ecam, range.clone(), length).await? {
+                    longshot::info!("{:#06x}  {}", parameter, hexdump(&value));
+                }
+            } else {
+                let parameter = *cmd.get_one::<u16>("parameter").expect("Required");
+                read_parameter(ecam, parameter, length).await?;
+            }
         }
-        Some(("x-internal-pipe", cmd)) => {
-            let device_name = DeviceCommon::parse(cmd).device_name;
-            if device_name.starts_with("sim") {
-                let ecam = get_ecam_simulator(&device_name).await?;
-                pipe_stdin(ecam).await?;
+        Some(("set-parameter", cmd)) => {
+            let parameter = *cmd.get_one::<u16>("parameter").expect("Required");
+            let length = *cmd.get_one::<u8>("length").expect("Required");
+            let value = cmd.get_one::<Vec<u8>>("value").expect("Required").clone();
+            let ecam = ecam(cmd, true).await?;
+            write_parameter(ecam, parameter, length, value).await?;
+        }
+        Some(("send-raw", cmd)) => {
+            let payload = cmd.get_one::<Vec<u8>>("hex").expect("Required").clone();
+            let ecam = ecam(cmd, true).await?;
+            send_raw(ecam, &payload, RAW_RESPONSE_WINDOW).await?;
+        }
+        Some(("probe-parameter", cmd)) => {
+            let parameter = *cmd.get_one::<u16>("parameter").expect("Required");
+            let length = *cmd.get_one::<u8>("length").expect("Required");
+            let value = cmd.get_one::<Vec<u8>>("value").cloned();
+            let ecam = ecam(cmd, true).await?;
+            probe_parameter(ecam, parameter, length, value, cmd.get_flag("force")).await?;
+        }
+        Some(("clock", cmd)) => {
+            let time = match cmd.get_one::<String>("set").map(String::as_str) {
+                None if cmd.get_flag("now") => Some(MachineTime::now()),
+                None => None,
+                Some("now") => Some(MachineTime::now()),
+                Some(s) => match s.parse::<MachineTime>() {
+                    Ok(time) => Some(time),
+                    Err(e) => return Err(e.into()),
+                },
+            };
+            let ecam = ecam(cmd, true).await?;
+            if let Some(time) = time {
+                let time = set_clock(ecam, time, cmd.get_flag("force")).await?;
+                longshot::info!("Machine clock set to {}", time);
             } else {
-                let ecam = EcamBT::get(device_name).await?;
-                pipe_stdin(ecam).await?;
+                longshot::info!(
+                    "Machine clock reads {} (unconfirmed: the clock parameter hasn't been verified on a real machine)",
+                    read_clock(ecam).await?
+                );
             }
         }
+        Some(("x-internal-pipe", cmd)) => {
+            let DeviceCommon {
+                device_name,
+                scan_timeout,
+                ..
+            } = DeviceCommon::parse(cmd);
+            longshot::logging::forward_frames();
+            pipe_stdin(get_ecam_driver(&device_name, scan_timeout).await?).await?;
+        }
         _ => {}
     }
 
     longshot::display::shutdown();
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::*;
+
+    #[rstest]
+    #[case(EcamError::Timeout.into(), "timeout", "timed out")]
+    #[case(EcamError::Disconnected.into(), "disconnected", "the device disconnected")]
+    #[case(
+        EcamError::ProtocolError("bad frame".to_owned()).into(),
+        "protocol_error",
+        "protocol error: bad frame"
+    )]
+    #[case(
+        EcamError::DeviceInUse.into(),
+        "device_in_use",
+        "device already in use by another longshot process"
+    )]
+    #[case(
+        EcamError::NotSupported("clock").into(),
+        "not_supported",
+        "clock is not supported by this machine"
+    )]
+    #[case(
+        "25:00".parse::<MachineTime>().unwrap_err().into(),
+        "invalid_argument",
+        "invalid time '25:00', expected HH:MM (24-hour)"
+    )]
+    #[case(EcamError::NotReady(EcamStatus::StandBy).into(), "not_ready", "the machine is not ready (StandBy)")]
+    #[case(
+        CliError::NoConfigDir.into(),
+        "no_config_dir",
+        "the configuration directory couldn't be found (set $HOME or $XDG_CONFIG_HOME)"
+    )]
+    #[case(hex::FromHexError::OddLength.into(), "unknown", "Odd number of digits")]
+    fn error_report(
+        #[case] error: Box<dyn std::error::Error>,
+        #[case] code: &str,
+        #[case] message: &str,
+    ) {
+        let report = ErrorReport::new(&*error);
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&report.to_json()).unwrap(),
+            serde_json::json!({ "code": code, "message": message })
+        );
+    }
+
+    #[rstest]
+    #[case("cupwarmer", Some(0x0063))]
+    #[case("StartupRinse", Some(0x0064))]
+    #[case("95", Some(95))]
+    #[case("0x1234", Some(0x1234))]
+    #[case("auto-off", None)]
+    fn parse_parameter(#[case] s: &str, #[case] id: Option<u16>) {
+        assert_eq!(parse_parameter_id(s).ok(), id);
+    }
+
+    #[rstest]
+    #[case("5", Some(Duration::from_secs(5)))]
+    #[case("0.5", Some(Duration::from_millis(500)))]
+    #[case("-1", None)]
+    #[case("soon", None)]
+    fn parse_scan_timeout(#[case] s: &str, #[case] timeout: Option<Duration>) {
+        assert_eq!(parse_seconds(s).ok(), timeout);
+    }
+
+    #[rstest]
+    #[case("0x60..0x70", Some(0x60..0x70))]
+    #[case("95..clock", None)]
+    #[case("clock..0x70", Some(0x5f..0x70))]
+    #[case("0x70..0x60", None)]
+    #[case("0x60", None)]
+    fn parse_range(#[case] s: &str, #[case] range: Option<std::ops::Range<u16>>) {
+        assert_eq!(parse_parameter_range(s).ok(), range);
+    }
+
+    #[test]
+    fn device_name_from_env() {
+        // clap reads the environment itself, so check the argument falls back to it rather than changing the
+        // environment under the other tests. Aliases are resolved by `Config::resolve_device`, tested with the config.
+        let args = DeviceCommon::args();
+        let device_name = args
+            .iter()
+            .find(|arg| arg.get_id() == "device-name")
+            .expect("Missing --device-name");
+        assert_eq!(
+            device_name.get_env(),
+            Some(std::ffi::OsStr::new(DEVICE_NAME_ENV))
+        );
+        let matches = clap::Command::new("longshot")
+            .args(args)
+            .try_get_matches_from(["longshot", "--device-name", "sim[on]"])
+            .expect("Invalid arguments");
+        assert_eq!(
+            matches.get_one::<String>("device-name").map(String::as_str),
+            Some("sim[on]")
+        );
+    }
+
+    #[test]
+    fn timeout_arg() {
+        let parse = |args: &[&str]| {
+            let matches = clap::Command::new("longshot")
+                .args(DeviceCommon::args())
+                .arg(super::timeout_arg())
+                .try_get_matches_from(args)
+                .expect("Invalid arguments");
+            command_timeout(&matches)
+        };
+        assert_eq!(parse(&["longshot", "--device-name", "sim"]), None);
+        assert_eq!(
+            parse(&["longshot", "--device-name", "sim", "--timeout", "1.5"]),
+            Some(Duration::from_millis(1500))
+        );
+        // Commands without the argument have no timeout
+        let matches = clap::Command::new("longshot").get_matches_from(["longshot"]);
+        assert_eq!(command_timeout(&matches), None);
+    }
+}
@@ -1,22 +1,50 @@
 use crate::{display, prelude::*};
 use crate::{
-    ecam::{Ecam, EcamError, EcamStatus},
+    ecam::{Ecam, EcamError, EcamEvent, EcamStatus},
     operations::{
-        check_ingredients, list_recipies_for, BrewIngredientInfo, IngredientCheckError,
-        IngredientCheckMode,
+        accumulate_recipies_for, check_ingredients, fetch_recipes, BrewIngredientInfo,
+        IngredientCheckError, IngredientCheckMode, RecipeAccumulator, DEFAULT_RECIPE_VARIANT,
     },
     protocol::*,
 };
 
-/// Checks the arguments for the given beverage against the machine's recipes and returns a computed recipe.
+/// Checks the arguments for the given beverage against the machine's recipes and returns a computed recipe. Ingredients
+/// that aren't specified default to the given stored variant of the recipe.
 pub async fn validate_brew(
     ecam: Ecam,
     beverage: EcamBeverageId,
+    variant: u8,
     ingredients: Vec<BrewIngredientInfo>,
     mode: IngredientCheckMode,
 ) -> Result<Vec<RecipeInfo<u16>>, EcamError> {
     info!("Fetching recipe for {:?}...", beverage);
-    let recipe_list = list_recipies_for(ecam.clone(), Some(vec![beverage])).await?;
+    let mut recipes = accumulate_recipies_for(ecam.clone(), Some(vec![beverage])).await?;
+    if recipes.is_empty(beverage) {
+        info!(
+            "This machine doesn't make {:?}. Use supported-beverages to see what it can make.",
+            beverage
+        );
+        return Err(EcamError::UnsupportedBeverage(beverage));
+    }
+    if variant != DEFAULT_RECIPE_VARIANT {
+        info!(
+            "Fetching variant {} of the recipe for {:?}...",
+            variant, beverage
+        );
+        recipes = fetch_recipes(
+            ecam.clone(),
+            RecipeAccumulator::variant_of(&recipes, variant),
+        )
+        .await?;
+        if recipes.is_empty(beverage) {
+            info!(
+                "This machine doesn't have a variant {} of {:?}. Use list-recipes to see the stored variants.",
+                variant, beverage
+            );
+            return Err(EcamError::MissingRecipeVariant(beverage, variant));
+        }
+    }
+    let recipe_list = recipes.take();
     let recipe = recipe_list.find(beverage);
     if let Some(recipe) = recipe {
         let ranges = recipe.fetch_ingredients();
@@ -56,7 +84,17 @@ pub async fn validate_brew(
             "I wasn't able to fetch the recipe for {:?}. Perhaps this machine can't make it?",
             beverage
         );
-        Err(EcamError::NotFound)
+        Err(EcamError::UnsupportedBeverage(beverage))
+    }
+}
+
+/// Maps an alarm that prevents brewing to the error we report for it. Most alarms are reported generically, but some
+/// are common enough to deserve their own error.
+fn alarm_error(alarm: MachineEnum<EcamMachineAlarm>) -> EcamError {
+    if alarm == EcamMachineAlarm::CoffeeWasteContainerFull {
+        EcamError::GroundsContainerFull
+    } else {
+        EcamError::Unknown
     }
 }
 
@@ -66,6 +104,31 @@ pub async fn brew(
     beverage: EcamBeverageId,
     recipe: Vec<RecipeInfo<u16>>,
 ) -> Result<(), EcamError> {
+    brew_with_progress(ecam, skip_brew, beverage, recipe, |status, _| {
+        display::display_status(status)
+    })
+    .await
+}
+
+/// Like [`brew`], but calls `on_progress` with the status and its progress from 0 to 100 (see
+/// [`EcamStatus::percentage`]) each time the status changes while brewing, rather than showing it on the display.
+pub async fn brew_with_progress(
+    ecam: Ecam,
+    skip_brew: bool,
+    beverage: EcamBeverageId,
+    recipe: Vec<RecipeInfo<u16>>,
+    mut on_progress: impl FnMut(EcamStatus, u8),
+) -> Result<(), EcamError> {
+    let mut last_status = None;
+    let mut monitor = |status: EcamStatus| {
+        if last_status != Some(status) {
+            last_status = Some(status);
+            on_progress(status, status.percentage());
+        }
+    };
+
+    // Milk drinks can't continue if the carafe is removed, so watch for it to be detached
+    let uses_milk = recipe.iter().any(|r| r.ingredient == EcamIngredients::Milk);
     let req = Request::BeverageDispensingMode(
         beverage.into(),
         EcamOperationTrigger::Start.into(),
@@ -73,27 +136,596 @@ pub async fn brew(
         EcamBeverageTasteType::Prepare.into(),
     );
 
+    let mut events = ecam.event_tap().await?;
+
     if skip_brew {
         info!("--skip-brew was passed, so we aren't going to brew anything");
     } else {
         ecam.write_request(req).await?;
     }
 
-    // Wait for not ready
-    ecam.wait_for_not_state(EcamStatus::Ready, display::display_status)
-        .await?;
+    let wait = async {
+        // Wait for not ready
+        ecam.wait_for_not_state(EcamStatus::Ready, &mut monitor)
+            .await?;
 
-    // Wait for not busy
-    ecam.wait_for(
-        |m| match EcamStatus::extract(m) {
-            EcamStatus::Busy(_) => false,
-            _ => true,
-        },
-        display::display_status,
-    )
-    .await?;
+        // Wait for not busy
+        ecam.wait_for(|m| !EcamStatus::extract(m).is_dispensing(), &mut monitor)
+            .await
+    };
+
+    let detached = async {
+        while let Some(event) = events.next().await {
+            if let EcamEvent::AccessoryChanged { from, .. } = event {
+                if uses_milk && from == EcamAccessory::Milk {
+                    return from;
+                }
+            }
+        }
+        futures::future::pending().await
+    };
+
+    tokio::select! {
+        res = wait => res?,
+        accessory = detached => {
+            display::clear_status();
+            if stop_brew(ecam.clone(), beverage).await? {
+                info!("The milk carafe was detached, so we've asked the machine to stop the beverage");
+            } else {
+                info!("The milk carafe was detached, so we've stopped waiting for the beverage");
+            }
+            return Err(EcamError::AccessoryDetached(accessory));
+        }
+    }
+
+    // The machine stops dispensing when the grounds container fills up
+    if let EcamStatus::Alarm(alarm) = ecam.current_state().await? {
+        if let EcamError::GroundsContainerFull = alarm_error(alarm) {
+            display::clear_status();
+            info!("The coffee grounds container is full, so the beverage was not completed. Empty it and try again.");
+            return Err(EcamError::GroundsContainerFull);
+        }
+    }
 
     display::log(display::LogLevel::Info, "Completed");
 
     Ok(())
 }
+
+/// Asks the machine to stop dispensing the given beverage, as if its stop button were pressed. This does nothing if the
+/// machine isn't dispensing anything, and returns whether the stop request was sent.
+pub async fn stop_brew(ecam: Ecam, beverage: EcamBeverageId) -> Result<bool, EcamError> {
+    if !ecam.current_state().await?.is_dispensing() {
+        return Ok(false);
+    }
+    ecam.write_request(Request::BeverageDispensingMode(
+        beverage.into(),
+        EcamOperationTrigger::StartProgramOrStopV2.into(),
+        vec![],
+        EcamBeverageTasteType::Prepare.into(),
+    ))
+    .await?;
+    Ok(true)
+}
+
+/// The default time budget for [`brew_with_turn_on`], long enough to cover a cold start, the startup rinse and a
+/// large beverage.
+pub const BREW_WITH_TURN_ON_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// How long the machine must report that it is ready before we trust it. Some machines briefly report ready after
+/// turning on, but before starting the startup rinse. The startup rinse setting hasn't been confirmed on a real
+/// machine (see [`PARAMETER_STARTUP_RINSE`]), so we always wait, even if the setting reads as off.
+const READY_SETTLE_TIME: Duration = Duration::from_secs(2);
+
+/// Runs one stage of [`brew_with_turn_on`], failing with [`EcamError::Timeout`] if the deadline passes first.
+async fn stage<T>(
+    deadline: tokio::time::Instant,
+    description: &str,
+    f: impl Future<Output = Result<T, EcamError>>,
+) -> Result<T, EcamError> {
+    match tokio::time::timeout_at(deadline, f).await {
+        Ok(res) => res,
+        Err(_) => {
+            display::clear_status();
+            info!("Timed out while {}", description);
+            Err(EcamError::Timeout)
+        }
+    }
+}
+
+/// Turns the machine on if it is off, then waits for it to finish starting up (including any startup rinse). The
+/// machine must be ready within `timeout`.
+pub async fn turn_on_and_wait(ecam: Ecam, timeout: Duration) -> Result<(), EcamError> {
+    ready_by(&ecam, tokio::time::Instant::now() + timeout).await
+}
+
+/// Turns the machine on if it is off, waits for it to finish starting up (including any startup rinse), then brews
+/// the beverage. The entire operation must complete within `timeout`.
+pub async fn brew_with_turn_on(
+    ecam: Ecam,
+    skip_brew: bool,
+    beverage: EcamBeverageId,
+    recipe: Vec<RecipeInfo<u16>>,
+    timeout: Duration,
+) -> Result<(), EcamError> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    ready_by(&ecam, deadline).await?;
+    stage(deadline, "brewing", brew(ecam, skip_brew, beverage, recipe)).await
+}
+
+/// The stages of [`turn_on_and_wait`], which must complete by `deadline`.
+async fn ready_by(ecam: &Ecam, deadline: tokio::time::Instant) -> Result<(), EcamError> {
+    match stage(deadline, "connecting to the machine", ecam.current_state()).await? {
+        EcamStatus::StandBy => {
+            info!("Turning on the machine...");
+            ecam.write_request(Request::AppControl(AppControl::TurnOn))
+                .await?;
+            stage(
+                deadline,
+                "waiting for the machine to start turning on",
+                ecam.wait_for_not_state(EcamStatus::StandBy, display::display_status),
+            )
+            .await?;
+        }
+        EcamStatus::Alarm(alarm) => {
+            info!(
+                "Machine has an alarm ({:?}), so we will cowardly refuse to brew coffee",
+                alarm
+            );
+            return Err(alarm_error(alarm));
+        }
+        _ => {}
+    }
+
+    stage(
+        deadline,
+        "waiting for the machine to be ready",
+        wait_until_ready(ecam),
+    )
+    .await
+}
+
+/// Waits for the machine to report that it is ready, failing if it reports an alarm first. The ready state must hold
+/// for [`READY_SETTLE_TIME`] before we trust it.
+async fn wait_until_ready(ecam: &Ecam) -> Result<(), EcamError> {
+    loop {
+        ecam.wait_for(
+            |m| {
+                matches!(
+                    EcamStatus::extract(m),
+                    EcamStatus::Ready | EcamStatus::Alarm(_)
+                )
+            },
+            display::display_status,
+        )
+        .await?;
+        if let EcamStatus::Alarm(alarm) = ecam.current_state().await? {
+            display::clear_status();
+            info!(
+                "Machine reported an alarm ({:?}) before it was ready, so we will cowardly refuse to brew coffee",
+                alarm
+            );
+            return Err(alarm_error(alarm));
+        }
+        // Only trust the ready state once it has held for a little while
+        let unsettled = tokio::time::timeout(
+            READY_SETTLE_TIME,
+            ecam.wait_for_not_state(EcamStatus::Ready, display::display_status),
+        );
+        if unsettled.await.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// Brews the same beverage `count` times in a row, waiting for the machine to be ready again between cups. The first
+/// cup is brewed with [`brew_with_turn_on`] if `turn_on_timeout` is given. The sequence stops at the first cup that
+/// fails.
+pub async fn brew_repeatedly(
+    ecam: Ecam,
+    skip_brew: bool,
+    beverage: EcamBeverageId,
+    recipe: Vec<RecipeInfo<u16>>,
+    count: u32,
+    turn_on_timeout: Option<Duration>,
+) -> Result<(), EcamError> {
+    for cup in 1..=count {
+        if count > 1 {
+            info!("Cup {} of {}", cup, count);
+        }
+        let res = match (cup, turn_on_timeout) {
+            (1, Some(timeout)) => {
+                brew_with_turn_on(ecam.clone(), skip_brew, beverage, recipe.clone(), timeout).await
+            }
+            (1, None) => brew(ecam.clone(), skip_brew, beverage, recipe.clone()).await,
+            _ => {
+                // The machine may rinse or heat up again between cups
+                async {
+                    wait_until_ready(&ecam).await?;
+                    brew(ecam.clone(), skip_brew, beverage, recipe.clone()).await
+                }
+                .await
+            }
+        };
+        if let Err(e) = res {
+            if cup < count {
+                info!(
+                    "Cup {} of {} failed, so the remaining cups won't be brewed",
+                    cup, count
+                );
+            }
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ecam::{
+        fault_script, get_ecam_simulator, get_ecam_simulator_scripted, SimulatorLog, SimulatorStep,
+    };
+    use crate::operations::write_parameter_value;
+
+    #[tokio::test(start_paused = true)]
+    async fn brew_aborts_when_milk_detached() -> Result<(), EcamError> {
+        let ecam = Ecam::new(
+            Box::new(get_ecam_simulator("sim[on][milk-detach]").await?),
+            false,
+        )
+        .await;
+        let recipe = vec![
+            RecipeInfo::new(EcamIngredients::Coffee, 65),
+            RecipeInfo::new(EcamIngredients::Milk, 190),
+        ];
+        match brew(ecam, false, EcamBeverageId::Cappuccino, recipe).await {
+            Err(EcamError::AccessoryDetached(accessory)) => {
+                assert_eq!(accessory, EcamAccessory::Milk)
+            }
+            x => panic!("Expected the brew to be aborted, got {:?}", x),
+        }
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn brew_stops_when_milk_detached() -> Result<(), EcamError> {
+        // Like SimulatorStep::status, but with an accessory attached
+        let status = |accessory: EcamAccessory, progress, count| {
+            SimulatorStep::Status(
+                MonitorV2Response {
+                    state: EcamMachineState::ReadyOrDispensing.into(),
+                    accessory: accessory.into(),
+                    switches: SwitchSet::of(&[EcamMachineSwitch::WaterSpout]),
+                    progress,
+                    percentage: progress * 50,
+                    ..Default::default()
+                },
+                count,
+            )
+        };
+        let (driver, log) = get_ecam_simulator_scripted(vec![
+            status(EcamAccessory::Milk, 0, 3),
+            SimulatorStep::WaitForRequest(EcamRequestId::BeverageDispensingMode),
+            status(EcamAccessory::Milk, 1, 2),
+            status(EcamAccessory::None, 1, 1),
+            SimulatorStep::WaitForRequest(EcamRequestId::BeverageDispensingMode),
+        ])
+        .await?;
+        let ecam = Ecam::new(Box::new(driver), false).await;
+        let recipe = vec![
+            RecipeInfo::new(EcamIngredients::Coffee, 65),
+            RecipeInfo::new(EcamIngredients::Milk, 190),
+        ];
+        match brew(ecam, false, EcamBeverageId::Cappuccino, recipe).await {
+            Err(EcamError::AccessoryDetached(accessory)) => {
+                assert_eq!(accessory, EcamAccessory::Milk)
+            }
+            x => panic!("Expected the brew to be aborted, got {:?}", x),
+        }
+        let stop = Request::BeverageDispensingMode(
+            EcamBeverageId::Cappuccino.into(),
+            EcamOperationTrigger::StartProgramOrStopV2.into(),
+            vec![],
+            EcamBeverageTasteType::Prepare.into(),
+        );
+        assert_eq!(log.requests().last(), Some(&stop.encode()));
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn brew_reports_grounds_container_full() -> Result<(), EcamError> {
+        let ecam = Ecam::new(
+            Box::new(get_ecam_simulator("sim[on][grounds-full]").await?),
+            false,
+        )
+        .await;
+        let recipe = vec![RecipeInfo::new(EcamIngredients::Coffee, 100)];
+        match brew(ecam, false, EcamBeverageId::RegularCoffee, recipe).await {
+            Err(EcamError::GroundsContainerFull) => {}
+            x => panic!("Expected the grounds container to be full, got {:?}", x),
+        }
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn brew_ignores_detach_without_milk() -> Result<(), EcamError> {
+        let ecam = Ecam::new(
+            Box::new(get_ecam_simulator("sim[on][milk-detach]").await?),
+            false,
+        )
+        .await;
+        let recipe = vec![RecipeInfo::new(EcamIngredients::Coffee, 100)];
+        brew(ecam, false, EcamBeverageId::RegularCoffee, recipe).await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn validate_unsupported_beverage() -> Result<(), EcamError> {
+        let ecam = Ecam::new(
+            Box::new(get_ecam_simulator("sim[on][restricted]").await?),
+            false,
+        )
+        .await;
+        match validate_brew(
+            ecam,
+            EcamBeverageId::Americano,
+            DEFAULT_RECIPE_VARIANT,
+            vec![],
+            IngredientCheckMode::AllowDefaults,
+        )
+        .await
+        {
+            Err(EcamError::UnsupportedBeverage(EcamBeverageId::Americano)) => {}
+            x => panic!("Expected the beverage to be unsupported, got {:?}", x),
+        }
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn validate_recipe_variant() -> Result<(), EcamError> {
+        let ecam = Ecam::new(
+            Box::new(get_ecam_simulator("sim[on][variants]").await?),
+            false,
+        )
+        .await;
+        let recipe = validate_brew(
+            ecam.clone(),
+            EcamBeverageId::Cappuccino,
+            2,
+            vec![],
+            IngredientCheckMode::AllowDefaults,
+        )
+        .await?;
+        let amount = |ingredient| {
+            recipe
+                .iter()
+                .find(|r| r.ingredient == ingredient)
+                .map(|r| r.value)
+        };
+        assert_eq!(amount(EcamIngredients::Coffee), Some(80));
+        assert_eq!(amount(EcamIngredients::Milk), Some(160));
+        brew(ecam.clone(), false, EcamBeverageId::Cappuccino, recipe).await?;
+
+        // Beverages without a stored variant can't be brewed with one
+        match validate_brew(
+            ecam,
+            EcamBeverageId::RegularCoffee,
+            2,
+            vec![],
+            IngredientCheckMode::AllowDefaults,
+        )
+        .await
+        {
+            Err(EcamError::MissingRecipeVariant(EcamBeverageId::RegularCoffee, 2)) => {}
+            x => panic!("Expected the variant to be missing, got {:?}", x),
+        }
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn brew_with_turn_on_cold_start() -> Result<(), EcamError> {
+        let ecam = Ecam::new(Box::new(get_ecam_simulator("sim[cold]").await?), false).await;
+        assert_eq!(ecam.current_state().await?, EcamStatus::StandBy);
+        let recipe = vec![RecipeInfo::new(EcamIngredients::Coffee, 100)];
+        brew_with_turn_on(
+            ecam,
+            false,
+            EcamBeverageId::RegularCoffee,
+            recipe,
+            BREW_WITH_TURN_ON_TIMEOUT,
+        )
+        .await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn turn_on_and_wait_then_validate() -> Result<(), EcamError> {
+        let ecam = Ecam::new(Box::new(get_ecam_simulator("sim[cold]").await?), false).await;
+        assert_eq!(ecam.current_state().await?, EcamStatus::StandBy);
+        turn_on_and_wait(ecam.clone(), BREW_WITH_TURN_ON_TIMEOUT).await?;
+        assert_eq!(ecam.current_state().await?, EcamStatus::Ready);
+        let recipe = validate_brew(
+            ecam,
+            EcamBeverageId::RegularCoffee,
+            DEFAULT_RECIPE_VARIANT,
+            vec![],
+            IngredientCheckMode::AllowDefaults,
+        )
+        .await?;
+        assert!(recipe
+            .iter()
+            .any(|r| r.ingredient == EcamIngredients::Coffee));
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn brew_with_turn_on_without_startup_rinse() -> Result<(), EcamError> {
+        let ecam = Ecam::new(Box::new(get_ecam_simulator("sim[cold]").await?), false).await;
+        write_parameter_value(ecam.clone(), PARAMETER_STARTUP_RINSE, vec![0, 0]).await?;
+        let mut tap = ecam.packet_tap().await?;
+        let statuses = tokio::spawn(async move {
+            let mut statuses = vec![];
+            while let Some(packet) = tap.next().await {
+                if let Some(Response::MonitorV2(response)) = packet.take_packet() {
+                    statuses.push((tokio::time::Instant::now(), EcamStatus::extract(&response)));
+                }
+            }
+            statuses
+        });
+        let recipe = vec![RecipeInfo::new(EcamIngredients::Coffee, 100)];
+        brew_with_turn_on(
+            ecam,
+            false,
+            EcamBeverageId::RegularCoffee,
+            recipe,
+            BREW_WITH_TURN_ON_TIMEOUT,
+        )
+        .await?;
+
+        // The machine doesn't rinse, but we still wait for the ready state to settle, since the setting is unconfirmed
+        let statuses = statuses.await.expect("Failed to collect statuses");
+        assert!(!statuses
+            .iter()
+            .any(|(_, status)| matches!(status, EcamStatus::Rinsing(_))));
+        let first = |f: fn(&EcamStatus) -> bool| {
+            statuses
+                .iter()
+                .find(|(_, status)| f(status))
+                .map(|(time, _)| *time)
+                .expect("Status not found")
+        };
+        let ready = first(|status| *status == EcamStatus::Ready);
+        let busy = first(|status| matches!(status, EcamStatus::Busy(_)));
+        assert!(busy - ready >= READY_SETTLE_TIME);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn brew_with_turn_on_times_out() -> Result<(), EcamError> {
+        let ecam = Ecam::new(Box::new(get_ecam_simulator("sim[cold]").await?), false).await;
+        let recipe = vec![RecipeInfo::new(EcamIngredients::Coffee, 100)];
+        match brew_with_turn_on(
+            ecam,
+            false,
+            EcamBeverageId::RegularCoffee,
+            recipe,
+            Duration::from_secs(2),
+        )
+        .await
+        {
+            Err(EcamError::Timeout) => {}
+            x => panic!("Expected the operation to time out, got {:?}", x),
+        }
+        Ok(())
+    }
+
+    /// Counts the brew requests written to a simulator.
+    fn brew_requests(log: &SimulatorLog) -> usize {
+        let id: u8 = EcamRequestId::BeverageDispensingMode.into();
+        log.requests()
+            .iter()
+            .filter(|r| r.first() == Some(&id))
+            .count()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn brew_repeatedly_waits_between_cups() -> Result<(), EcamError> {
+        use EcamMachineState::*;
+        let cup = vec![
+            SimulatorStep::status(ReadyOrDispensing, 0, 0, 3),
+            SimulatorStep::WaitForRequest(EcamRequestId::BeverageDispensingMode),
+            SimulatorStep::status(ReadyOrDispensing, 1, 50, 3),
+            // The machine rinses after each cup, and may briefly report that it is ready before rinsing
+            SimulatorStep::status(ReadyOrDispensing, 0, 0, 1),
+            SimulatorStep::status(Rinsing, 0, 0, 3),
+        ];
+        let script = [cup.clone(), cup.clone(), cup].concat();
+        let (driver, log) = get_ecam_simulator_scripted(script).await?;
+        let ecam = Ecam::new(Box::new(driver), false).await;
+        ecam.wait_for_state(EcamStatus::Ready, |_| {}).await?;
+        let recipe = vec![RecipeInfo::new(EcamIngredients::Coffee, 100)];
+        brew_repeatedly(ecam, false, EcamBeverageId::RegularCoffee, recipe, 3, None).await?;
+        assert_eq!(brew_requests(&log), 3);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn brew_repeatedly_stops_on_alarm() -> Result<(), EcamError> {
+        let script = fault_script("sim-nowater").expect("Missing fault script");
+        let (driver, log) = get_ecam_simulator_scripted(script).await?;
+        let ecam = Ecam::new(Box::new(driver), false).await;
+        ecam.wait_for_state(EcamStatus::Ready, |_| {}).await?;
+        let recipe = vec![RecipeInfo::new(EcamIngredients::Coffee, 100)];
+        match brew_repeatedly(ecam, false, EcamBeverageId::RegularCoffee, recipe, 2, None).await {
+            Err(EcamError::Unknown) => {}
+            x => panic!("Expected the alarm to stop the sequence, got {:?}", x),
+        }
+        assert_eq!(brew_requests(&log), 1);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn brew_with_progress_reports_changes() -> Result<(), EcamError> {
+        use EcamMachineState::*;
+        let (driver, _) = get_ecam_simulator_scripted(vec![
+            SimulatorStep::status(ReadyOrDispensing, 0, 0, 3),
+            SimulatorStep::WaitForRequest(EcamRequestId::BeverageDispensingMode),
+            SimulatorStep::status(ReadyOrDispensing, 1, 20, 2),
+            SimulatorStep::status(ReadyOrDispensing, 1, 60, 2),
+            SimulatorStep::status(ReadyOrDispensing, 0, 0, 3),
+        ])
+        .await?;
+        let ecam = Ecam::new(Box::new(driver), false).await;
+        ecam.wait_for_state(EcamStatus::Ready, |_| {}).await?;
+        let recipe = vec![RecipeInfo::new(EcamIngredients::Coffee, 100)];
+        let mut progress = vec![];
+        brew_with_progress(
+            ecam,
+            false,
+            EcamBeverageId::RegularCoffee,
+            recipe,
+            |status, percentage| progress.push((status, percentage)),
+        )
+        .await?;
+        assert_eq!(
+            progress,
+            vec![
+                (EcamStatus::Ready, 0),
+                (EcamStatus::Busy(20), 20),
+                (EcamStatus::Busy(60), 60),
+                (EcamStatus::Ready, 0),
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stop_brew_only_while_dispensing() -> Result<(), EcamError> {
+        let (driver, log) = get_ecam_simulator_scripted(vec![
+            SimulatorStep::status(EcamMachineState::ReadyOrDispensing, 0, 0, 3),
+            SimulatorStep::WaitForRequest(EcamRequestId::BeverageDispensingMode),
+            SimulatorStep::status(EcamMachineState::ReadyOrDispensing, 1, 50, 1),
+            SimulatorStep::WaitForRequest(EcamRequestId::BeverageDispensingMode),
+        ])
+        .await?;
+        let ecam = Ecam::new(Box::new(driver), false).await;
+        ecam.wait_for_state(EcamStatus::Ready, |_| {}).await?;
+        assert!(!stop_brew(ecam.clone(), EcamBeverageId::RegularCoffee).await?);
+        assert_eq!(brew_requests(&log), 0);
+
+        let recipe = vec![RecipeInfo::new(EcamIngredients::Coffee, 100)];
+        ecam.write_request(Request::BeverageDispensingMode(
+            EcamBeverageId::RegularCoffee.into(),
+            EcamOperationTrigger::Start.into(),
+            recipe,
+            EcamBeverageTasteType::Prepare.into(),
+        ))
+        .await?;
+        ecam.wait_for_state(EcamStatus::Busy(50), |_| {}).await?;
+        assert!(stop_brew(ecam.clone(), EcamBeverageId::RegularCoffee).await?);
+        assert_eq!(
+            log.requests().last(),
+            Some(&vec![0x83, 0xf0, 0x02, 0x02, 0x02])
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,172 @@
+use crate::{
+    ecam::{Ecam, EcamError},
+    operations::{read_parameter_value, write_parameter_value},
+    prelude::*,
+    protocol::PARAMETER_CLOCK,
+};
+
+use chrono::Timelike;
+use thiserror::Error;
+
+/// A time of day, as kept by the machine's clock.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct MachineTime {
+    pub hour: u8,
+    pub minute: u8,
+}
+
+/// The time passed to [`MachineTime::from_str`] wasn't in `HH:MM` format.
+#[derive(Clone, Debug, Eq, PartialEq, Error)]
+#[error("invalid time '{0}', expected HH:MM (24-hour)")]
+pub struct TimeParseError(pub String);
+
+impl MachineTime {
+    /// Creates a time, returning `None` if the hour or minute is out of range.
+    pub fn new(hour: u8, minute: u8) -> Option<Self> {
+        if hour < 24 && minute < 60 {
+            Some(MachineTime { hour, minute })
+        } else {
+            None
+        }
+    }
+
+    /// The host's current local time.
+    pub fn now() -> Self {
+        let now = chrono::Local::now();
+        MachineTime {
+            hour: now.hour() as u8,
+            minute: now.minute() as u8,
+        }
+    }
+
+    fn minutes_since_midnight(&self) -> u16 {
+        self.hour as u16 * 60 + self.minute as u16
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        vec![self.hour, self.minute]
+    }
+
+    fn decode(data: &[u8]) -> Option<Self> {
+        match data {
+            [hour, minute, ..] => Self::new(*hour, *minute),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for MachineTime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:02}:{:02}", self.hour, self.minute)
+    }
+}
+
+impl std::str::FromStr for MachineTime {
+    type Err = TimeParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || TimeParseError(s.to_owned());
+        let (hour, minute) = s.split_once(':').ok_or_else(err)?;
+        if minute.len() != 2 {
+            return Err(err());
+        }
+        let hour = hour.parse().map_err(|_| err())?;
+        let minute = minute.parse().map_err(|_| err())?;
+        Self::new(hour, minute).ok_or_else(err)
+    }
+}
+
+/// Reads the machine's clock, failing with [`EcamError::NotSupported`] if the machine doesn't have one.
+///
+/// The clock parameter hasn't been confirmed on a real machine (see [`PARAMETER_CLOCK`]), so any valid-looking time is
+/// returned even if the parameter turns out to be something else. Callers showing it to the user should say so.
+pub async fn read_clock(ecam: Ecam) -> Result<MachineTime, EcamError> {
+    let data = read_parameter_value(ecam, PARAMETER_CLOCK, 1).await?;
+    MachineTime::decode(&data).ok_or(EcamError::NotSupported("clock"))
+}
+
+/// Sets the machine's clock, and confirms that it took effect by reading it back. Returns the time read back from
+/// the machine.
+///
+/// The clock parameter hasn't been confirmed on a real machine (see [`PARAMETER_CLOCK`]), so the write is refused with
+/// [`EcamError::UnsafeParameter`] unless `force` is set.
+pub async fn set_clock(
+    ecam: Ecam,
+    time: MachineTime,
+    force: bool,
+) -> Result<MachineTime, EcamError> {
+    if !force {
+        info!("The clock parameter hasn't been confirmed on a real machine, pass --force to write it anyway");
+        return Err(EcamError::UnsafeParameter(PARAMETER_CLOCK));
+    }
+    // Make sure the machine actually has a clock before we write to it
+    read_clock(ecam.clone()).await?;
+    write_parameter_value(ecam.clone(), PARAMETER_CLOCK, time.encode()).await?;
+    let readback = read_clock(ecam).await?;
+
+    // The clock may have ticked over between the write and the read
+    let elapsed =
+        (readback.minutes_since_midnight() + 24 * 60 - time.minutes_since_midnight()) % (24 * 60);
+    if elapsed > 1 {
+        info!(
+            "Machine clock reads {} after setting it to {}",
+            readback, time
+        );
+        return Err(EcamError::NotApplied("clock"));
+    }
+    Ok(readback)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ecam::get_ecam_simulator;
+    use rstest::*;
+
+    #[rstest]
+    #[case("08:30", Ok(MachineTime { hour: 8, minute: 30 }))]
+    #[case("0:00", Ok(MachineTime { hour: 0, minute: 0 }))]
+    #[case("23:59", Ok(MachineTime { hour: 23, minute: 59 }))]
+    #[case("24:00", Err(TimeParseError("24:00".to_owned())))]
+    #[case("12:60", Err(TimeParseError("12:60".to_owned())))]
+    #[case("12:5", Err(TimeParseError("12:5".to_owned())))]
+    #[case("noon", Err(TimeParseError("noon".to_owned())))]
+    fn parse_time(#[case] s: &str, #[case] expected: Result<MachineTime, TimeParseError>) {
+        assert_eq!(s.parse::<MachineTime>(), expected);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn set_and_read_clock() -> Result<(), EcamError> {
+        let ecam = Ecam::new(Box::new(get_ecam_simulator("sim[on]").await?), false).await;
+        let time = MachineTime::new(6, 45).unwrap();
+        assert_eq!(set_clock(ecam.clone(), time, true).await?, time);
+        assert_eq!(read_clock(ecam).await?, time);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn set_clock_requires_force() -> Result<(), EcamError> {
+        let ecam = Ecam::new(Box::new(get_ecam_simulator("sim[on]").await?), false).await;
+        let before = read_clock(ecam.clone()).await?;
+        match set_clock(ecam.clone(), MachineTime::new(6, 45).unwrap(), false).await {
+            Err(EcamError::UnsafeParameter(PARAMETER_CLOCK)) => {}
+            x => panic!("Expected the write to be refused, got {:?}", x),
+        }
+        assert_eq!(read_clock(ecam).await?, before);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn clock_not_supported() -> Result<(), EcamError> {
+        let ecam = Ecam::new(
+            Box::new(get_ecam_simulator("sim[on][no-rtc]").await?),
+            false,
+        )
+        .await;
+        match read_clock(ecam).await {
+            Err(EcamError::NotSupported(_)) => {}
+            x => panic!("Expected the clock to be unsupported, got {:?}", x),
+        }
+        Ok(())
+    }
+}
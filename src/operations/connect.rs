@@ -0,0 +1,77 @@
+use std::path::PathBuf;
+
+use crate::ecam::{connect_with_info, DeviceInfo, Ecam, EcamError, DEFAULT_SCAN_DEADLINE};
+use crate::operations::power_on;
+use crate::prelude::*;
+use crate::protocol::PercentageScale;
+
+/// How [`connect`] finds the device, and what it accepts before handing the device over.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ConnectOptions {
+    /// The device id, `sim` (or `sim[...]`) for a simulator, or `replay:<file>` to replay a recording.
+    pub device_name: String,
+    /// Dump every packet exchanged with the device.
+    pub dump_packets: bool,
+    /// How long to scan for the device before giving up.
+    pub scan_timeout: Duration,
+    /// Record every packet exchanged with the device to this file.
+    pub record: Option<PathBuf>,
+    /// Turn the machine on if it's off, and wait for it to be ready.
+    pub turn_on: bool,
+    /// Accept a machine that is off.
+    pub allow_off: bool,
+    /// Accept a machine that is busy or reporting an alarm (this also accepts a machine that is off).
+    pub allow_alarms: bool,
+    /// Hand the device over without checking its state or turning it on, for callers that power it on themselves (ie:
+    /// with [`crate::operations::turn_on_and_wait`]).
+    pub skip_power_on: bool,
+    /// The scale the machine reports percentages on, overriding the one the driver reports.
+    pub percentage_scale: Option<PercentageScale>,
+}
+
+impl ConnectOptions {
+    /// Options to connect to the given device, which must be ready.
+    pub fn new(device_name: impl Into<String>) -> Self {
+        ConnectOptions {
+            device_name: device_name.into(),
+            dump_packets: false,
+            scan_timeout: DEFAULT_SCAN_DEADLINE,
+            record: None,
+            turn_on: false,
+            allow_off: false,
+            allow_alarms: false,
+            skip_power_on: false,
+            percentage_scale: None,
+        }
+    }
+}
+
+/// Connects to a device (see [`crate::ecam::get_ecam_driver`] for the devices that can be named), then checks that
+/// it's ready, turning it on first if [`ConnectOptions::turn_on`] is set. Fails with [`EcamError::NotReady`] if the
+/// machine is in a state the options don't accept. None of this is done if [`ConnectOptions::skip_power_on`] is set.
+pub async fn connect(options: &ConnectOptions) -> Result<(Ecam, DeviceInfo), EcamError> {
+    let (ecam, info) = connect_with_info(
+        &options.device_name,
+        options.dump_packets,
+        options.scan_timeout,
+        options.record.as_deref(),
+    )
+    .await?;
+    if let Some(scale) = options.percentage_scale {
+        ecam.set_percentage_scale(scale);
+    }
+    if options.skip_power_on {
+        return Ok((ecam, info));
+    }
+    if !power_on(
+        ecam.clone(),
+        options.allow_off || options.allow_alarms,
+        options.allow_alarms,
+        options.turn_on,
+    )
+    .await?
+    {
+        return Err(EcamError::NotReady(ecam.current_state().await?));
+    }
+    Ok((ecam, info))
+}
@@ -4,12 +4,31 @@
 //! There's a lot of code here for some apparently simple things, but it allows us to keep the messy protocol stuff
 //! separated from the semi-clean CLI interface. We also validate ingredients as much as we can to avoid sending anything
 //! bad to the machine that might have unintended consequences (spilled milk, too little coffee, spectacular fire, etc).
+use serde::ser::{Serialize, SerializeMap, Serializer};
 use std::collections::HashMap;
+use std::num::IntErrorKind;
 use std::vec;
+use thiserror::Error;
 
 use crate::prelude::*;
 use crate::protocol::*;
 
+/// Error result of [`BrewIngredientInfo::from_arg`].
+#[derive(Clone, Debug, Eq, PartialEq, Error)]
+pub enum IngredientParseError {
+    #[error("Unknown argument '{0}'")]
+    UnknownArgument(String),
+    #[error("Invalid value '{value}' for argument '{key}'")]
+    InvalidValue { key: String, value: String },
+    #[error("Value {value} for argument '{key}' is out of range ({min}-{max})")]
+    OutOfRange {
+        key: String,
+        value: String,
+        min: u16,
+        max: u16,
+    },
+}
+
 /// The requested ingredients to brew, generally provided by an API user or CLI input. A [`Vec<BrewIngredientInfo>`] will
 /// be combined with the [`IngredientCheckMode`] and a [`Vec<IngredientRangeInfo>`] to create the final brew recipe to send
 /// to the machine.
@@ -38,25 +57,40 @@ impl BrewIngredientInfo {
         }
     }
 
-    pub fn from_arg(key: &str, value: &str) -> Option<Self> {
-        if key == "coffee" {
-            return value.parse::<u16>().ok().map(BrewIngredientInfo::Coffee);
-        }
-        if key == "milk" {
-            return value.parse::<u16>().ok().map(BrewIngredientInfo::Milk);
-        }
-        if key == "hotwater" {
-            return value.parse::<u16>().ok().map(BrewIngredientInfo::HotWater);
-        }
-        if key == "taste" {
-            return EcamBeverageTaste::lookup_by_name_case_insensitive(value)
-                .map(BrewIngredientInfo::Taste);
-        }
-        if key == "temperature" {
-            return EcamTemperature::lookup_by_name_case_insensitive(value)
-                .map(BrewIngredientInfo::Temperature);
+    /// Parses a CLI-style argument name and value into a [`BrewIngredientInfo`].
+    pub fn from_arg(key: &str, value: &str) -> Result<Self, IngredientParseError> {
+        let invalid = || IngredientParseError::InvalidValue {
+            key: key.to_owned(),
+            value: value.to_owned(),
+        };
+        let amount = || {
+            let out_of_range = || IngredientParseError::OutOfRange {
+                key: key.to_owned(),
+                value: value.to_owned(),
+                min: u16::MIN,
+                max: u16::MAX,
+            };
+            // Numbers too large for an i64 are still numbers, so report them as out of range rather than invalid
+            let amount = value.parse::<i64>().map_err(|e| match e.kind() {
+                IntErrorKind::PosOverflow | IntErrorKind::NegOverflow => out_of_range(),
+                _ => invalid(),
+            })?;
+            // The machine's own per-recipe limits are checked against the recipe (see IngredientCheckMode), so only
+            // the range of the encoding is checked here
+            u16::try_from(amount).map_err(|_| out_of_range())
+        };
+        match key {
+            "coffee" => amount().map(BrewIngredientInfo::Coffee),
+            "milk" => amount().map(BrewIngredientInfo::Milk),
+            "hotwater" => amount().map(BrewIngredientInfo::HotWater),
+            "taste" => EcamBeverageTaste::lookup_by_name_case_insensitive(value)
+                .map(BrewIngredientInfo::Taste)
+                .ok_or_else(invalid),
+            "temperature" => EcamTemperature::lookup_by_name_case_insensitive(value)
+                .map(BrewIngredientInfo::Temperature)
+                .ok_or_else(invalid),
+            _ => Err(IngredientParseError::UnknownArgument(key.to_owned())),
         }
-        panic!("Unexpected argument {}", key);
     }
 
     pub fn ingredient(&self) -> EcamIngredients {
@@ -86,6 +120,31 @@ impl BrewIngredientInfo {
     pub fn to_recipe_info(&self) -> RecipeInfo<u16> {
         RecipeInfo::<u16>::new(self.ingredient(), self.value_u16())
     }
+
+    /// The inverse of [`BrewIngredientInfo::to_recipe_info`]. Returns `None` for ingredients that can't be requested,
+    /// or values that don't fit the ingredient.
+    pub fn from_recipe_info(recipe: &RecipeInfo<u16>) -> Option<Self> {
+        let value = recipe.value;
+        let flag = || match value {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        };
+        match Option::<EcamIngredients>::from(recipe.ingredient)? {
+            EcamIngredients::Coffee => Some(Self::Coffee(value)),
+            EcamIngredients::Milk => Some(Self::Milk(value)),
+            EcamIngredients::HotWater => Some(Self::HotWater(value)),
+            EcamIngredients::Taste => EcamBeverageTaste::try_from(u8::try_from(value).ok()?)
+                .ok()
+                .map(Self::Taste),
+            EcamIngredients::Temp => EcamTemperature::try_from(u8::try_from(value).ok()?)
+                .ok()
+                .map(Self::Temperature),
+            EcamIngredients::Inversion => flag().map(Self::Inversion),
+            EcamIngredients::DueXPer => flag().map(Self::Brew2),
+            _ => None,
+        }
+    }
 }
 
 /// The processed ingredients from the raw ECAM responses. Some ingredients are omitted as they are not useful for brewing.
@@ -254,6 +313,31 @@ impl IngredientRangeInfo {
     }
 }
 
+/// Serializes as an object naming the ingredient, along with its default value and (for amounts) its allowed range.
+impl Serialize for IngredientRangeInfo {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("ingredient", &self.ingredient().to_arg_string())?;
+        match self {
+            Self::Coffee(min, value, max)
+            | Self::Milk(min, value, max)
+            | Self::HotWater(min, value, max) => {
+                map.serialize_entry("min", min)?;
+                map.serialize_entry("value", value)?;
+                map.serialize_entry("max", max)?;
+            }
+            Self::Taste(value) => map.serialize_entry("value", &value.to_arg_string())?,
+            Self::Temperature(value) => map.serialize_entry("value", &value.to_arg_string())?,
+            Self::Accessory(value) => map.serialize_entry("value", &value.to_arg_string())?,
+            Self::Inversion(value, fixed) | Self::Brew2(value, fixed) => {
+                map.serialize_entry("value", value)?;
+                map.serialize_entry("fixed", fixed)?;
+            }
+        }
+        map.end()
+    }
+}
+
 /// Determines how ingredients are checked.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum IngredientCheckMode {
@@ -479,4 +563,44 @@ mod test {
     ) {
         test_mode(IngredientCheckMode::AllowDefaults, ranges, input, expected);
     }
+
+    #[rstest]
+    #[case("coffee", "100", Ok(BrewIngredientInfo::Coffee(100)))]
+    #[case("taste", "Strong", Ok(BrewIngredientInfo::Taste(EcamBeverageTaste::Strong)))]
+    #[case("sugar", "1", Err(IngredientParseError::UnknownArgument("sugar".to_owned())))]
+    #[case("milk", "lots", Err(IngredientParseError::InvalidValue { key: "milk".to_owned(), value: "lots".to_owned() }))]
+    #[case("temperature", "lava", Err(IngredientParseError::InvalidValue { key: "temperature".to_owned(), value: "lava".to_owned() }))]
+    #[case("hotwater", "2501", Ok(BrewIngredientInfo::HotWater(2501)))]
+    #[case("hotwater", "65536", Err(IngredientParseError::OutOfRange { key: "hotwater".to_owned(), value: "65536".to_owned(), min: 0, max: 65535 }))]
+    #[case("coffee", "-1", Err(IngredientParseError::OutOfRange { key: "coffee".to_owned(), value: "-1".to_owned(), min: 0, max: 65535 }))]
+    #[case("milk", "99999999999999999999", Err(IngredientParseError::OutOfRange { key: "milk".to_owned(), value: "99999999999999999999".to_owned(), min: 0, max: 65535 }))]
+    #[case("coffee", "-99999999999999999999", Err(IngredientParseError::OutOfRange { key: "coffee".to_owned(), value: "-99999999999999999999".to_owned(), min: 0, max: 65535 }))]
+    fn from_arg(
+        #[case] key: &str,
+        #[case] value: &str,
+        #[case] expected: Result<BrewIngredientInfo, IngredientParseError>,
+    ) {
+        assert_eq!(expected, BrewIngredientInfo::from_arg(key, value));
+    }
+
+    #[rstest]
+    #[case(BrewIngredientInfo::Coffee(100))]
+    #[case(BrewIngredientInfo::HotWater(250))]
+    #[case(BrewIngredientInfo::Taste(EcamBeverageTaste::ExtraStrong))]
+    #[case(BrewIngredientInfo::Temperature(EcamTemperature::High))]
+    #[case(BrewIngredientInfo::Brew2(true))]
+    fn recipe_info_round_trip(#[case] ingredient: BrewIngredientInfo) {
+        assert_eq!(
+            BrewIngredientInfo::from_recipe_info(&ingredient.to_recipe_info()),
+            Some(ingredient)
+        );
+    }
+
+    #[test]
+    fn recipe_info_invalid() {
+        let recipe = RecipeInfo::new(EcamIngredients::Inversion, 2);
+        assert_eq!(BrewIngredientInfo::from_recipe_info(&recipe), None);
+        let recipe = RecipeInfo::new(EcamIngredients::Accessorio, 1);
+        assert_eq!(BrewIngredientInfo::from_recipe_info(&recipe), None);
+    }
 }
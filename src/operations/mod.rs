@@ -1,15 +1,25 @@
 //! Coffee-related operations: brewing, monitoring, etc.
 
 mod brew;
+mod clock;
+mod connect;
 mod ingredients;
 mod monitor;
 mod parameter;
 mod power;
+mod raw;
+mod recipe_file;
 mod recipe_list;
+mod settings;
 
 pub use brew::*;
+pub use clock::*;
+pub use connect::*;
 pub use ingredients::*;
 pub use monitor::*;
 pub use parameter::*;
 pub use power::*;
+pub use raw::*;
+pub use recipe_file::*;
 pub use recipe_list::*;
+pub use settings::*;
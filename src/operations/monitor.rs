@@ -1,16 +1,103 @@
 use crate::prelude::*;
-use std::time::Instant;
+use serde::Serialize;
+use std::time::{Instant, SystemTime};
 
 use crate::display::*;
-use crate::ecam::{Ecam, EcamError};
+use crate::ecam::{Ecam, EcamError, EcamEvent, EcamStatusDetail};
+use crate::protocol::EcamWarning;
+
+/// A status change reported by [`monitor_json`], written as one line of JSON.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct MonitorEvent {
+    /// Milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    #[serde(flatten)]
+    pub detail: EcamStatusDetail,
+    pub warnings: Vec<EcamWarning>,
+}
+
+impl MonitorEvent {
+    pub fn new(detail: EcamStatusDetail, warnings: Vec<EcamWarning>) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        MonitorEvent {
+            timestamp,
+            detail,
+            warnings,
+        }
+    }
+
+    /// Serializes this event as a single line of JSON, without the trailing newline.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("Failed to serialize monitor event")
+    }
+}
+
+/// Monitors the device, printing a line of JSON to stdout (see [`MonitorEvent`]) each time the status, progress or
+/// warnings change.
+pub async fn monitor_json(ecam: Ecam) -> Result<(), EcamError> {
+    let mut last = None;
+    while ecam.is_alive() {
+        let detail = ecam.current_state_detailed().await?;
+        let warnings = ecam
+            .last_monitor_response()
+            .map(|response| response.warnings())
+            .unwrap_or_default();
+        let next = Some((detail, warnings));
+        if next != last {
+            if let Some((detail, warnings)) = next.clone() {
+                println!("{}", MonitorEvent::new(detail, warnings).to_json());
+            }
+            last = next;
+        }
+    }
+    Ok(())
+}
 
 pub async fn monitor(ecam: Ecam) -> Result<(), EcamError> {
+    let mut events = ecam.event_tap().await?;
+    let _events = tokio::spawn(async move {
+        while let Some(event) = events.next().await {
+            match event {
+                EcamEvent::WaterTankChanged { present: false } => {
+                    log(LogLevel::Warning, "The water tank was removed")
+                }
+                EcamEvent::WaterTankChanged { present: true } => {
+                    log(LogLevel::Info, "The water tank was put back")
+                }
+                EcamEvent::AccessoryChanged { from, to } => log(
+                    LogLevel::Info,
+                    &format!("The accessory changed from {:?} to {:?}", from, to),
+                ),
+            }
+        }
+    });
     let mut state = ecam.current_state().await?;
     display_status(state);
     let mut debounce = Instant::now();
+    let mut warnings = vec![];
     while ecam.is_alive() {
         // Poll for current state
         let next_state = ecam.current_state().await?;
+        if let Some(response) = ecam.last_monitor_response() {
+            let next_warnings = response.warnings();
+            if next_warnings != warnings {
+                if !next_warnings.is_empty() {
+                    log(
+                        LogLevel::Warning,
+                        &format!(
+                            "Warnings: {}",
+                            next_warnings
+                                .iter()
+                                .collect_map_join(", ", |w| format!("{:?}", w))
+                        ),
+                    );
+                }
+                warnings = next_warnings;
+            }
+        }
         if next_state != state || debounce.elapsed() > Duration::from_millis(250) {
             // println!("{:?}", next_state);
             display_status(next_state);
@@ -21,3 +108,30 @@ pub async fn monitor(ecam: Ecam) -> Result<(), EcamError> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ecam::EcamStatus;
+    use crate::protocol::{EcamMachineAlarm, MachineEnum};
+
+    #[test]
+    fn serialize_monitor_event() {
+        let event = MonitorEvent {
+            timestamp: 1,
+            detail: EcamStatusDetail {
+                status: EcamStatus::Busy(50),
+                progress: 3,
+                percentage: 50,
+            },
+            warnings: vec![
+                EcamWarning::WaterTankAbsent,
+                EcamWarning::Alarm(MachineEnum::Value(EcamMachineAlarm::EmptyWaterTank)),
+            ],
+        };
+        assert_eq!(
+            event.to_json(),
+            r#"{"timestamp":1,"status":{"Busy":50},"progress":3,"percentage":50,"warnings":["WaterTankAbsent",{"Alarm":"EmptyWaterTank"}]}"#
+        );
+    }
+}
@@ -1,9 +1,239 @@
 use crate::{
     ecam::{Ecam, EcamError, EcamOutput},
     prelude::*,
-    protocol::Request,
+    protocol::{
+        hexdump, EcamPacket, EcamRequestId, MultiFrameReassembler, Request, Response,
+        PARAMETER_BLOCKLIST,
+    },
 };
 
+/// The most blocks (of two bytes each) that [`read_parameter_value`] reads with a single request. This is a
+/// conservative limit of our own rather than one documented for the machine.
+const MAX_PARAMETER_READ_BLOCKS: u8 = 10;
+
+/// The size of each block of a parameter value, in bytes.
+const PARAMETER_BLOCK_SIZE: usize = 2;
+
+/// The delay between reads of a parameter range, as the machine drops requests that arrive too quickly.
+const PARAMETER_RANGE_READ_DELAY: Duration = Duration::from_millis(100);
+
+/// Reads a parameter from the device, returning its raw value. Reads longer than [`MAX_PARAMETER_READ_BLOCKS`] are
+/// split into several requests, one per run of blocks, and the responses are reassembled in order. Fails with
+/// [`EcamError::Timeout`] if the device doesn't respond, or [`EcamError::ProtocolError`] if it only responds with
+/// something that can't be decoded.
+///
+/// Splitting assumes that parameter IDs address consecutive two-byte blocks, so that block `n` of `param` can be read
+/// as parameter `param + n`. This is the same addressing [`read_parameter_range`] walks, but no capture from a real
+/// machine confirms it for values that span several IDs. Each response echoes the parameter ID it was asked for, and
+/// that ID is what orders the reassembled runs.
+pub async fn read_parameter_value(ecam: Ecam, param: u16, len: u8) -> Result<Vec<u8>, EcamError> {
+    let mut tap = ecam.packet_tap().await?;
+    let chunks: Vec<_> = (0..len)
+        .step_by(MAX_PARAMETER_READ_BLOCKS as usize)
+        .map(|offset| {
+            let len = (len - offset).min(MAX_PARAMETER_READ_BLOCKS);
+            (param.wrapping_add(offset as u16), len)
+        })
+        .collect();
+    let mut frames = MultiFrameReassembler::new(chunks.iter().map(|(param, _)| *param));
+    let response_ids = [
+        EcamRequestId::ParameterRead.into(),
+        EcamRequestId::ParameterReadExt.into(),
+    ];
+    let mut malformed = None;
+
+    // Requests are occasionally dropped, so try a few times
+    for _ in 0..3 {
+        let missing = frames.missing();
+        if missing.is_empty() {
+            break;
+        }
+        for (param, len) in chunks.iter().filter(|(param, _)| missing.contains(param)) {
+            let request = if *len > 4 {
+                Request::ParameterReadExt(*param, *len)
+            } else {
+                Request::ParameterRead(*param, *len)
+            };
+            ecam.write_request(request).await?;
+        }
+        let response = tokio::time::timeout(Duration::from_millis(500), async {
+            while let Some(packet) = tap.next().await {
+                let complete = match packet {
+                    EcamOutput::Packet(EcamPacket {
+                        representation:
+                            Some(
+                                Response::ParameterRead(p, data)
+                                | Response::ParameterReadExt(p, data),
+                            ),
+                        ..
+                    }) => frames.accept(p, data) && frames.is_complete(),
+                    EcamOutput::Packet(EcamPacket {
+                        representation: None,
+                        bytes,
+                    }) if bytes
+                        .bytes
+                        .first()
+                        .is_some_and(|id| response_ids.contains(id)) =>
+                    {
+                        malformed = Some(hexdump(&bytes.bytes));
+                        false
+                    }
+                    _ => false,
+                };
+                if complete {
+                    return true;
+                }
+            }
+            false
+        })
+        .await;
+        match response {
+            Ok(true) => break,
+            Ok(false) => return Err(EcamError::Disconnected),
+            Err(_) => {}
+        }
+    }
+
+    frames.take().map_err(|missing| {
+        trace_packet!("Failed to read parameter {:#06x}: {}", param, missing);
+        match malformed {
+            _ if !ecam.is_alive() => EcamError::Disconnected,
+            Some(bytes) => {
+                EcamError::ProtocolError(format!("malformed parameter response {}", bytes))
+            }
+            None => EcamError::Timeout,
+        }
+    })
+}
+
+/// Reads every parameter in `params`, each `len` blocks long, returning the id and raw value of each parameter that
+/// responded. Parameters that don't respond, or respond with something that can't be decoded, are skipped.
+pub async fn read_parameter_range(
+    ecam: Ecam,
+    params: std::ops::Range<u16>,
+    len: u8,
+) -> Result<Vec<(u16, Vec<u8>)>, EcamError> {
+    let mut values = vec![];
+    for param in params {
+        match read_parameter_value(ecam.clone(), param, len).await {
+            Ok(value) => values.push((param, value)),
+            Err(EcamError::Timeout | EcamError::ProtocolError(_)) => {}
+            Err(e) => return Err(e),
+        }
+        tokio::time::sleep(PARAMETER_RANGE_READ_DELAY).await;
+    }
+    Ok(values)
+}
+
+/// Writes the raw value of a parameter to the device. The device doesn't confirm writes, so callers should read the
+/// parameter back if they need to know that the write took effect.
+pub async fn write_parameter_value(
+    ecam: Ecam,
+    param: u16,
+    value: Vec<u8>,
+) -> Result<(), EcamError> {
+    ecam.write_request(Request::ParameterWrite(param, value))
+        .await
+}
+
+/// Writes a parameter that is `len` blocks long. Writes are refused with [`EcamError::UnsafeParameter`] for parameters
+/// in [`PARAMETER_BLOCKLIST`], or if the value is not exactly `len` blocks long.
+pub async fn write_parameter(
+    ecam: Ecam,
+    param: u16,
+    len: u8,
+    value: Vec<u8>,
+) -> Result<(), EcamError> {
+    if let Some((_, reason)) = PARAMETER_BLOCKLIST.iter().find(|(p, _)| *p == param) {
+        info!("Refusing to write parameter {:#06x}: {}", param, reason);
+        return Err(EcamError::UnsafeParameter(param));
+    }
+    if value.len() != len as usize * PARAMETER_BLOCK_SIZE {
+        info!(
+            "Refusing to write {} byte(s) to parameter {:#06x}, which is {} byte(s) long",
+            value.len(),
+            param,
+            len as usize * PARAMETER_BLOCK_SIZE
+        );
+        return Err(EcamError::UnsafeParameter(param));
+    }
+    info!("Writing {} to parameter {:#06x}", hexdump(&value), param);
+    write_parameter_value(ecam, param, value).await
+}
+
+/// The value of a parameter before and after [`probe_parameter`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ParameterProbe {
+    pub before: Vec<u8>,
+    /// The value read back after writing, if a value was written.
+    pub after: Option<Vec<u8>>,
+}
+
+/// Reads a parameter, optionally writes a new value and reads it back. Writes are refused with
+/// [`EcamError::UnsafeParameter`] for parameters in [`PARAMETER_BLOCKLIST`], or if the new value is not the same length
+/// as the current one, unless `force` is set.
+pub async fn probe_parameter(
+    ecam: Ecam,
+    param: u16,
+    len: u8,
+    value: Option<Vec<u8>>,
+    force: bool,
+) -> Result<ParameterProbe, EcamError> {
+    info!(
+        "Probing parameter {:#06x} (length {}, value {}, force {})",
+        param,
+        len,
+        value
+            .as_ref()
+            .map(hex::encode)
+            .unwrap_or_else(|| "(none)".to_owned()),
+        force
+    );
+    if let Some((_, reason)) = PARAMETER_BLOCKLIST.iter().find(|(p, _)| *p == param) {
+        if value.is_some() && !force {
+            info!("Refusing to write parameter {:#06x}: {}", param, reason);
+            return Err(EcamError::UnsafeParameter(param));
+        }
+        warning!("Parameter {:#06x} is blocklisted: {}", param, reason);
+    }
+
+    let before = read_parameter_value(ecam.clone(), param, len).await?;
+    info!("Before: {}", hexdump(&before));
+    let value = match value {
+        Some(value) => value,
+        None => {
+            return Ok(ParameterProbe {
+                before,
+                after: None,
+            })
+        }
+    };
+    if value.len() != before.len() {
+        if !force {
+            info!(
+                "Refusing to write {} byte(s) to parameter {:#06x}, which currently holds {} byte(s)",
+                value.len(),
+                param,
+                before.len()
+            );
+            return Err(EcamError::UnsafeParameter(param));
+        }
+        warning!(
+            "Writing a value with a different length to parameter {:#06x}",
+            param
+        );
+    }
+
+    info!("Writing: {}", hexdump(&value));
+    write_parameter_value(ecam.clone(), param, value).await?;
+    let after = read_parameter_value(ecam, param, len).await?;
+    info!("After: {}", hexdump(&after));
+    Ok(ParameterProbe {
+        before,
+        after: Some(after),
+    })
+}
+
 pub async fn read_parameter(ecam: Ecam, param: u16, len: u8) -> Result<(), EcamError> {
     let mut tap = ecam.packet_tap().await?;
     let ecam = ecam.clone();
@@ -32,3 +262,163 @@ pub async fn read_parameter(ecam: Ecam, param: u16, len: u8) -> Result<(), EcamE
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ecam::get_ecam_simulator;
+    use crate::protocol::PARAMETER_CUP_WARMER;
+
+    async fn simulator() -> Result<Ecam, EcamError> {
+        Ok(Ecam::new(Box::new(get_ecam_simulator("sim[on]").await?), false).await)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn read_long_parameter() -> Result<(), EcamError> {
+        // The simulator stores whatever is written, so write each run of blocks separately and read them back together
+        let ecam = simulator().await?;
+        let value: Vec<u8> = (0..48).collect();
+        for (i, chunk) in value.chunks(20).enumerate() {
+            write_parameter_value(ecam.clone(), 0x1000 + i as u16 * 10, chunk.to_vec()).await?;
+        }
+        assert_eq!(read_parameter_value(ecam, 0x1000, 24).await?, value);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn read_range() -> Result<(), EcamError> {
+        let ecam = simulator().await?;
+        write_parameter_value(ecam.clone(), 0x2000, vec![1, 2]).await?;
+        write_parameter_value(ecam.clone(), 0x2002, vec![3, 4]).await?;
+        assert_eq!(
+            read_parameter_range(ecam, 0x2000..0x2003, 1).await?,
+            vec![(0x2000, vec![1, 2]), (0x2001, vec![]), (0x2002, vec![3, 4])]
+        );
+        Ok(())
+    }
+
+    /// Replays a device that is ready immediately, then sends the given packets (as hex) at the given times.
+    async fn replay(name: &str, packets: &[(u64, &str)]) -> Result<Ecam, EcamError> {
+        use crate::ecam::{get_ecam_replay, RecordedEntry, RecordedEvent};
+        let path = std::env::temp_dir().join(format!(
+            "longshot-test-parameter-{}-{}.jsonl",
+            name,
+            std::process::id()
+        ));
+        let mut entries = vec![RecordedEntry {
+            elapsed_ms: 0,
+            event: RecordedEvent::Ready,
+        }];
+        for (elapsed_ms, packet) in packets {
+            entries.push(RecordedEntry {
+                elapsed_ms: *elapsed_ms,
+                event: RecordedEvent::Read {
+                    packet: packet.to_string(),
+                },
+            });
+        }
+        let lines: Vec<_> = entries
+            .iter()
+            .map(|e| serde_json::to_string(e).expect("Failed to serialize"))
+            .collect();
+        std::fs::write(&path, lines.join("\n"))?;
+        let replay = get_ecam_replay(path.clone()).await?;
+        let _ = std::fs::remove_file(path);
+        Ok(Ecam::new(Box::new(replay), false).await)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn read_malformed_response() -> Result<(), EcamError> {
+        // The parameter ID is cut short, and the device stays connected for longer than the read takes
+        let ecam = replay("malformed", &[(100, "95f000"), (5000, "95f000")]).await?;
+        match read_parameter_value(ecam, 0x5f, 1).await {
+            Err(EcamError::ProtocolError(_)) => {}
+            x => panic!("Expected a protocol error, got {:?}", x),
+        }
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn read_after_disconnect() -> Result<(), EcamError> {
+        let ecam = replay("disconnect", &[]).await?;
+        match read_parameter_value(ecam, 0x5f, 1).await {
+            Err(EcamError::Disconnected) => {}
+            x => panic!("Expected a disconnection, got {:?}", x),
+        }
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn write_parameter_checks_length() -> Result<(), EcamError> {
+        let ecam = simulator().await?;
+        for value in [vec![1], vec![0, 0, 1]] {
+            match write_parameter(ecam.clone(), PARAMETER_CUP_WARMER, 1, value).await {
+                Err(EcamError::UnsafeParameter(PARAMETER_CUP_WARMER)) => {}
+                x => panic!("Expected the write to be refused, got {:?}", x),
+            }
+        }
+        assert_eq!(
+            read_parameter_value(ecam.clone(), PARAMETER_CUP_WARMER, 1).await?,
+            vec![0, 0]
+        );
+        write_parameter(ecam.clone(), PARAMETER_CUP_WARMER, 1, vec![0, 1]).await?;
+        assert_eq!(
+            read_parameter_value(ecam, PARAMETER_CUP_WARMER, 1).await?,
+            vec![0, 1]
+        );
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn probe_read_only() -> Result<(), EcamError> {
+        let probe =
+            probe_parameter(simulator().await?, PARAMETER_CUP_WARMER, 2, None, false).await?;
+        assert_eq!(
+            probe,
+            ParameterProbe {
+                before: vec![0, 0],
+                after: None
+            }
+        );
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn probe_write() -> Result<(), EcamError> {
+        let ecam = simulator().await?;
+        let probe = probe_parameter(
+            ecam.clone(),
+            PARAMETER_CUP_WARMER,
+            2,
+            Some(vec![0, 1]),
+            false,
+        )
+        .await?;
+        assert_eq!(
+            probe,
+            ParameterProbe {
+                before: vec![0, 0],
+                after: Some(vec![0, 1])
+            }
+        );
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn probe_refuses_length_mismatch() -> Result<(), EcamError> {
+        let ecam = simulator().await?;
+        match probe_parameter(ecam.clone(), PARAMETER_CUP_WARMER, 2, Some(vec![1]), false).await {
+            Err(EcamError::UnsafeParameter(PARAMETER_CUP_WARMER)) => {}
+            x => panic!("Expected the write to be refused, got {:?}", x),
+        }
+        // The value is untouched, and --force allows the write
+        assert_eq!(
+            read_parameter_value(ecam.clone(), PARAMETER_CUP_WARMER, 2).await?,
+            vec![0, 0]
+        );
+        let probe =
+            probe_parameter(ecam.clone(), PARAMETER_CUP_WARMER, 2, Some(vec![1]), true).await?;
+        assert_eq!(probe.after, Some(vec![1]));
+        Ok(())
+    }
+}
@@ -0,0 +1,89 @@
+use crate::{
+    ecam::{Ecam, EcamError, EcamOutput},
+    prelude::*,
+    protocol::{from_hex_str, hexdump, EcamDriverPacket, MAX_PAYLOAD_LEN},
+};
+
+/// How long [`send_raw`] listens for responses by default.
+pub const RAW_RESPONSE_WINDOW: Duration = Duration::from_secs(3);
+
+/// Parses a raw payload from hex (spaces are ignored), rejecting payloads that are empty or too long to fit in a
+/// single packet.
+pub fn parse_raw_payload(s: &str) -> Result<Vec<u8>, String> {
+    let payload = from_hex_str(s).map_err(|e| format!("invalid hex: {}", e))?;
+    if payload.is_empty() {
+        return Err("the payload is empty".to_owned());
+    }
+    if payload.len() > MAX_PAYLOAD_LEN {
+        return Err(format!(
+            "the payload is {} bytes, but a single packet holds at most {}",
+            payload.len(),
+            MAX_PAYLOAD_LEN
+        ));
+    }
+    Ok(payload)
+}
+
+/// Sends a hand-built payload to the device, then collects (and logs) every packet the device sends back within
+/// `window`.
+pub async fn send_raw(
+    ecam: Ecam,
+    payload: &[u8],
+    window: Duration,
+) -> Result<Vec<EcamDriverPacket>, EcamError> {
+    let mut tap = ecam.packet_tap().await?;
+    info!("Sending {}", hexdump(payload));
+    ecam.write_raw(payload).await?;
+    let mut responses = vec![];
+    let _ = tokio::time::timeout(window, async {
+        while let Some(output) = tap.next().await {
+            match output {
+                EcamOutput::Packet(packet) => {
+                    info!("Received {}", hexdump(&packet.bytes.bytes));
+                    responses.push(packet.bytes);
+                }
+                EcamOutput::Done => break,
+                _ => {}
+            }
+        }
+    })
+    .await;
+    Ok(responses)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ecam::get_ecam_simulator;
+    use crate::protocol::{EcamRequestId, PARAMETER_CLOCK};
+    use rstest::*;
+
+    #[rstest]
+    #[case("95f0005f01", Ok(vec![0x95, 0xf0, 0x00, 0x5f, 0x01]))]
+    #[case("95 f0 00 5f 01", Ok(vec![0x95, 0xf0, 0x00, 0x5f, 0x01]))]
+    #[case("", Err(()))]
+    #[case("95f", Err(()))]
+    #[case("zz", Err(()))]
+    fn parse_payload(#[case] input: &str, #[case] expected: Result<Vec<u8>, ()>) {
+        assert_eq!(parse_raw_payload(input).map_err(|_| ()), expected);
+    }
+
+    #[test]
+    fn parse_payload_too_long() {
+        assert!(parse_raw_payload(&"00".repeat(MAX_PAYLOAD_LEN)).is_ok());
+        assert!(parse_raw_payload(&"00".repeat(MAX_PAYLOAD_LEN + 1)).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn send_raw_parameter_read() -> Result<(), EcamError> {
+        let ecam = Ecam::new(Box::new(get_ecam_simulator("sim[on]").await?), false).await;
+        ecam.wait_for_connection().await?;
+        let [hi, lo] = PARAMETER_CLOCK.to_be_bytes();
+        let payload = [EcamRequestId::ParameterRead.into(), 0xf0, hi, lo, 1];
+        let responses = send_raw(ecam, &payload, RAW_RESPONSE_WINDOW).await?;
+        assert!(responses
+            .iter()
+            .any(|packet| packet.bytes == [0x95, 0xf0, hi, lo, 8, 0]));
+        Ok(())
+    }
+}
@@ -0,0 +1,188 @@
+//! Recipe files: a beverage and its ingredients saved as JSON, so that a tuned recipe can be brewed again later. This
+//! is purely host-side, and the ingredients are validated against the machine's recipe like any others.
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::ecam::EcamError;
+use crate::operations::BrewIngredientInfo;
+use crate::protocol::*;
+
+/// A beverage and the ingredients to brew it with. Recipe files look like this, with the ingredients named as they are
+/// on the command line (see [`BrewIngredientInfo::from_arg`]):
+///
+/// ```json
+/// {"beverage": "cappuccino", "ingredients": {"coffee": 80, "milk": 160, "taste": "strong"}}
+/// ```
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SavedRecipe {
+    pub beverage: EcamBeverageId,
+    pub ingredients: Vec<BrewIngredientInfo>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RecipeFile {
+    beverage: String,
+    #[serde(default)]
+    ingredients: BTreeMap<String, serde_json::Value>,
+}
+
+fn invalid_data(message: String) -> EcamError {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message).into()
+}
+
+impl SavedRecipe {
+    /// Creates a recipe from the validated recipe returned by [`crate::operations::validate_brew`]. Ingredients that
+    /// can't be requested are left out.
+    pub fn from_recipe(beverage: EcamBeverageId, recipe: &[RecipeInfo<u16>]) -> Self {
+        SavedRecipe {
+            beverage,
+            ingredients: recipe
+                .iter()
+                .filter_map(BrewIngredientInfo::from_recipe_info)
+                .collect(),
+        }
+    }
+
+    /// Loads a recipe file, failing with [`EcamError::IOError`] if the file can't be read or doesn't describe a valid
+    /// recipe.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, EcamError> {
+        let file = std::fs::File::open(path)?;
+        let json: serde_json::Value =
+            serde_json::from_reader(std::io::BufReader::new(file)).map_err(std::io::Error::from)?;
+        Self::from_json(json)
+    }
+
+    /// Reads a recipe in the same shape as a recipe file, failing with [`EcamError::IOError`] if it doesn't describe
+    /// a valid recipe.
+    pub fn from_json(json: serde_json::Value) -> Result<Self, EcamError> {
+        let file: RecipeFile = serde_json::from_value(json).map_err(std::io::Error::from)?;
+        let beverage = EcamBeverageId::lookup_by_name_case_insensitive(&file.beverage)
+            .ok_or_else(|| invalid_data(format!("Unknown beverage '{}'", file.beverage)))?;
+        let mut ingredients = vec![];
+        for (key, value) in file.ingredients {
+            let value = match value {
+                serde_json::Value::String(s) => s,
+                value => value.to_string(),
+            };
+            ingredients.push(
+                BrewIngredientInfo::from_arg(&key, &value)
+                    .map_err(|e| invalid_data(e.to_string()))?,
+            );
+        }
+        ingredients.sort();
+        Ok(SavedRecipe {
+            beverage,
+            ingredients,
+        })
+    }
+
+    /// Writes this recipe to a file, replacing the file if it exists.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), EcamError> {
+        let json = serde_json::to_string_pretty(&self.to_json()).map_err(std::io::Error::from)?;
+        std::fs::write(path, json + "\n")?;
+        Ok(())
+    }
+
+    /// Describes this recipe in the same shape as a recipe file.
+    pub fn to_json(&self) -> serde_json::Value {
+        let ingredients = self
+            .ingredients
+            .iter()
+            .filter_map(|ingredient| {
+                let (key, value): (&str, serde_json::Value) = match ingredient {
+                    BrewIngredientInfo::Coffee(x) => ("coffee", (*x).into()),
+                    BrewIngredientInfo::Milk(x) => ("milk", (*x).into()),
+                    BrewIngredientInfo::HotWater(x) => ("hotwater", (*x).into()),
+                    BrewIngredientInfo::Taste(x) => ("taste", x.to_arg_string().into()),
+                    BrewIngredientInfo::Temperature(x) => ("temperature", x.to_arg_string().into()),
+                    // These can't be requested from the command line either
+                    BrewIngredientInfo::Inversion(..) | BrewIngredientInfo::Brew2(..) => {
+                        return None
+                    }
+                };
+                Some((key.to_owned(), value))
+            })
+            .collect();
+        let file = RecipeFile {
+            beverage: self.beverage.to_arg_string(),
+            ingredients,
+        };
+        serde_json::to_value(file).expect("Failed to serialize recipe")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn recipe_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!(
+            "longshot-test-recipe-{}-{}.json",
+            name,
+            std::process::id()
+        ))
+    }
+
+    #[test]
+    fn save_and_load() -> Result<(), EcamError> {
+        let path = recipe_path("round-trip");
+        let recipe = SavedRecipe::from_recipe(
+            EcamBeverageId::Cappuccino,
+            &[
+                RecipeInfo::new(EcamIngredients::Coffee, 80),
+                RecipeInfo::new(EcamIngredients::Milk, 160),
+                RecipeInfo::new(EcamIngredients::Taste, 4),
+                RecipeInfo::new(EcamIngredients::Inversion, 0),
+            ],
+        );
+        recipe.save(&path)?;
+        let json: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path)?).expect("Invalid JSON");
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "beverage": "cappuccino",
+                "ingredients": {"coffee": 80, "milk": 160, "taste": "strong"}
+            })
+        );
+
+        let loaded = SavedRecipe::load(&path)?;
+        assert_eq!(loaded.beverage, EcamBeverageId::Cappuccino);
+        assert_eq!(
+            loaded.ingredients,
+            vec![
+                BrewIngredientInfo::Coffee(80),
+                BrewIngredientInfo::Milk(160),
+                BrewIngredientInfo::Taste(EcamBeverageTaste::Strong),
+            ]
+        );
+        let _ = std::fs::remove_file(path);
+        Ok(())
+    }
+
+    #[test]
+    fn load_errors() {
+        for (name, contents) in [
+            ("beverage", r#"{"beverage": "mocha", "ingredients": {}}"#),
+            (
+                "ingredient",
+                r#"{"beverage": "espressocoffee", "ingredients": {"sugar": 2}}"#,
+            ),
+            (
+                "amount",
+                r#"{"beverage": "espressocoffee", "ingredients": {"coffee": 70000}}"#,
+            ),
+            ("json", r#"{"beverage": "espressocoffee""#),
+        ] {
+            let path = recipe_path(name);
+            std::fs::write(&path, contents).expect("Failed to write recipe");
+            assert!(
+                matches!(SavedRecipe::load(&path), Err(EcamError::IOError(_))),
+                "Expected {} to fail",
+                name
+            );
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
@@ -1,17 +1,27 @@
 use crate::{display, prelude::*};
 use crate::{
-    ecam::{Ecam, EcamError},
+    ecam::{Ecam, EcamError, EcamOutput},
     operations::IngredientRangeInfo,
     protocol::*,
 };
+use serde::ser::{Serialize, SerializeStruct, Serializer};
 use std::collections::HashMap;
 
+/// The default variant of a recipe. Machines may store further customized variants of the same beverage, numbered
+/// from 2 up to [`MAX_RECIPE_VARIANT`].
+pub const DEFAULT_RECIPE_VARIANT: u8 = 1;
+
+/// The highest recipe variant we look for.
+pub const MAX_RECIPE_VARIANT: u8 = 4;
+
 /// Accumulates recipe responses, allowing us to fetch them one-at-a-time and account for which ones went missing in transit.
-/// Note that this doesn't support profiles yet and currently requires the use of profile 1.
+/// Each accumulator fetches a single variant of the recipes (which the protocol refers to as the recipe profile).
 pub struct RecipeAccumulator {
     recipe: HashMap<EcamBeverageId, Vec<RecipeInfo<u16>>>,
     recipe_min_max: HashMap<EcamBeverageId, Vec<RecipeMinMaxInfo>>,
+    recipe_raw: HashMap<EcamBeverageId, Vec<u8>>,
     list: Vec<EcamBeverageId>,
+    variant: u8,
 }
 
 impl Default for RecipeAccumulator {
@@ -32,6 +42,25 @@ impl RecipeAccumulator {
             list: recipes,
             recipe: HashMap::new(),
             recipe_min_max: HashMap::new(),
+            recipe_raw: HashMap::new(),
+            variant: DEFAULT_RECIPE_VARIANT,
+        }
+    }
+
+    /// Creates a new accumulator for another variant of the beverages available in `base`. The min/max info doesn't
+    /// differ between variants, so it is reused rather than fetched again.
+    pub fn variant_of(base: &RecipeAccumulator, variant: u8) -> Self {
+        RecipeAccumulator {
+            list: base
+                .list
+                .iter()
+                .filter(|beverage| !base.is_empty(**beverage))
+                .copied()
+                .collect(),
+            recipe: HashMap::new(),
+            recipe_min_max: base.recipe_min_max.clone(),
+            recipe_raw: HashMap::new(),
+            variant,
         }
     }
 
@@ -50,12 +79,14 @@ impl RecipeAccumulator {
         remaining
     }
 
-    /// Returns the [`Request`] required to fetch this beverage.
+    /// Returns the [`Request`]s required to fetch this beverage, skipping the min/max info if we already have it.
     pub fn get_request_packets(&self, beverage: EcamBeverageId) -> Vec<Request> {
-        vec![
-            Request::RecipeMinMaxSync(beverage.into()),
-            Request::RecipeQuantityRead(1, beverage.into()),
-        ]
+        let mut requests = vec![];
+        if !self.recipe_min_max.contains_key(&beverage) {
+            requests.push(Request::RecipeMinMaxSync(beverage.into()));
+        }
+        requests.push(Request::RecipeQuantityRead(self.variant, beverage.into()));
+        requests
     }
 
     /// Is our fetch complete for this [`EcamBeverageId`].
@@ -102,8 +133,8 @@ impl RecipeAccumulator {
     /// Accumulate a [`Response`] for the given [`EcamBeverageId`].
     pub fn accumulate_packet(&mut self, expected_beverage: EcamBeverageId, packet: Response) {
         match packet {
-            Response::RecipeQuantityRead(_, beverage, ingredients) => {
-                if beverage == expected_beverage {
+            Response::RecipeQuantityRead(variant, beverage, ingredients) => {
+                if beverage == expected_beverage && variant == self.variant {
                     self.recipe.insert(expected_beverage, ingredients);
                 }
             }
@@ -118,6 +149,28 @@ impl RecipeAccumulator {
         }
     }
 
+    /// Accumulate the raw bytes of a [`Response::RecipeQuantityRead`] for the given [`EcamBeverageId`], keeping the
+    /// recipe payload exactly as the machine sent it.
+    pub fn accumulate_raw_packet(&mut self, expected_beverage: EcamBeverageId, bytes: &[u8]) {
+        if let [id, _, variant, beverage, payload @ ..] = bytes {
+            if *id == EcamRequestId::RecipeQuantityRead as u8
+                && *variant == self.variant
+                && *beverage == expected_beverage as u8
+            {
+                self.recipe_raw.insert(expected_beverage, payload.to_vec());
+            }
+        }
+    }
+
+    /// The raw recipe payloads received so far, in the order the beverages were requested. Each payload decodes as a
+    /// [`Vec<RecipeInfo<u16>>`].
+    pub fn raw_recipes(&self) -> Vec<(EcamBeverageId, Vec<u8>)> {
+        self.list
+            .iter()
+            .filter_map(|beverage| Some((*beverage, self.recipe_raw.get(beverage)?.clone())))
+            .collect()
+    }
+
     /// Take the contents of this instance as a [`RecipeList`].
     pub fn take(mut self) -> RecipeList {
         let mut list = RecipeList { recipes: vec![] };
@@ -130,6 +183,7 @@ impl RecipeAccumulator {
             if let (Some(recipe), Some(recipe_min_max)) = (recipe, recipe_min_max) {
                 list.recipes.push(RecipeDetails {
                     beverage: *beverage,
+                    variant: self.variant,
                     recipe,
                     recipe_min_max,
                 });
@@ -171,6 +225,8 @@ impl RecipeList {
 #[derive(Clone, Debug)]
 pub struct RecipeDetails {
     pub beverage: EcamBeverageId,
+    /// The stored variant of this recipe, where [`DEFAULT_RECIPE_VARIANT`] is the machine's default.
+    pub variant: u8,
     recipe: Vec<RecipeInfo<u16>>,
     recipe_min_max: Vec<RecipeMinMaxInfo>,
 }
@@ -182,7 +238,16 @@ impl RecipeDetails {
             .fetch_ingredients()
             .iter()
             .collect_filter_map_join(" ", IngredientRangeInfo::to_arg_string);
-        format!("--beverage {} {}", self.beverage.to_arg_string(), args)
+        if self.variant == DEFAULT_RECIPE_VARIANT {
+            format!("--beverage {} {}", self.beverage.to_arg_string(), args)
+        } else {
+            format!(
+                "--beverage {} --variant {} {}",
+                self.beverage.to_arg_string(),
+                self.variant,
+                args
+            )
+        }
     }
 
     /// Processes this [`RecipeDetails`] into a [`Vec<IngredientInfo>`], suitable for dispensing.
@@ -213,6 +278,18 @@ impl RecipeDetails {
     }
 }
 
+/// Serializes the beverage name and id, the variant, and the decoded ingredients of the recipe.
+impl Serialize for RecipeDetails {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("RecipeDetails", 4)?;
+        s.serialize_field("beverage", &self.beverage.to_arg_string())?;
+        s.serialize_field("id", &(self.beverage as u8))?;
+        s.serialize_field("variant", &self.variant)?;
+        s.serialize_field("ingredients", &self.fetch_ingredients())?;
+        s.end()
+    }
+}
+
 /// Lists recipes for either all recipes, or just the given ones.
 pub async fn list_recipies_for(
     ecam: Ecam,
@@ -221,19 +298,51 @@ pub async fn list_recipies_for(
     Ok(accumulate_recipies_for(ecam, recipes).await?.take())
 }
 
+/// Lists every stored variant of either all recipes, or just the given ones. Variants that are missing, or identical to
+/// the default recipe, are left out.
+pub async fn list_recipe_variants_for(
+    ecam: Ecam,
+    recipes: Option<Vec<EcamBeverageId>>,
+) -> Result<Vec<RecipeDetails>, EcamError> {
+    let base = accumulate_recipies_for(ecam.clone(), recipes).await?;
+    let mut variants = vec![];
+    for variant in DEFAULT_RECIPE_VARIANT + 1..=MAX_RECIPE_VARIANT {
+        let list = fetch_recipes(ecam.clone(), RecipeAccumulator::variant_of(&base, variant))
+            .await?
+            .take();
+        variants.extend(
+            list.recipes
+                .into_iter()
+                .filter(|details| base.get(details.beverage).0.as_ref() != Some(&details.recipe)),
+        );
+    }
+    let mut list = base.take().recipes;
+    list.extend(variants);
+    list.sort_by_key(|details| (details.beverage, details.variant));
+    Ok(list)
+}
+
 /// Accumulates recipe min/max and ingredient info for either all recipes, or just the given ones.
 pub async fn accumulate_recipies_for(
     ecam: Ecam,
     recipes: Option<Vec<EcamBeverageId>>,
 ) -> Result<RecipeAccumulator, EcamError> {
-    info!("Entering accumulate_recipies_for");
-    // Get the tap we'll use for reading responses
-    let mut tap = ecam.packet_tap().await?;
-    let mut recipes = if let Some(recipes) = recipes {
+    let recipes = if let Some(recipes) = recipes {
         RecipeAccumulator::limited_to(recipes)
     } else {
         RecipeAccumulator::new()
     };
+    fetch_recipes(ecam, recipes).await
+}
+
+/// Fetches everything the given [`RecipeAccumulator`] is still missing.
+pub async fn fetch_recipes(
+    ecam: Ecam,
+    mut recipes: RecipeAccumulator,
+) -> Result<RecipeAccumulator, EcamError> {
+    debug!("Entering fetch_recipes");
+    // Get the tap we'll use for reading responses
+    let mut tap = ecam.packet_tap().await?;
     let total = recipes.get_remaining_beverages().len();
     for i in 0..3 {
         if i == 0 {
@@ -251,12 +360,15 @@ pub async fn accumulate_recipies_for(
                 ));
                 let request_id = packet.ecam_request_id();
                 ecam.write_request(packet).await?;
-                let now = std::time::Instant::now();
+                let now = tokio::time::Instant::now();
                 while now.elapsed() < Duration::from_millis(500) {
                     match tokio::time::timeout(Duration::from_millis(50), tap.next()).await {
                         Err(_) => {}
                         Ok(None) => {}
                         Ok(Some(x)) => {
+                            if let EcamOutput::Packet(packet) = &x {
+                                recipes.accumulate_raw_packet(beverage, &packet.bytes.bytes);
+                            }
                             if let Some(packet) = x.take_packet() {
                                 let response_id = packet.ecam_request_id();
                                 recipes.accumulate_packet(beverage, packet);
@@ -280,18 +392,70 @@ pub async fn accumulate_recipies_for(
     Ok(recipes)
 }
 
-pub async fn list_recipes(ecam: Ecam) -> Result<(), EcamError> {
+/// Prints every recipe stored in the device, optionally as a JSON array of [`RecipeDetails`]. Stored variants are only
+/// listed if `variants` is set, as fetching them means asking for every beverage again for each variant.
+pub async fn list_recipes(ecam: Ecam, json: bool, variants: bool) -> Result<(), EcamError> {
     // Wait for device to settle
     ecam.wait_for_connection().await?;
-    let list = list_recipies_for(ecam, None).await?;
+    let list = if variants {
+        list_recipe_variants_for(ecam, None).await?
+    } else {
+        list_recipies_for(ecam, None).await?.recipes
+    };
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string(&list).expect("Failed to serialize recipes")
+        );
+        return Ok(());
+    }
     info!("Beverages supported:");
-    for recipe in list.recipes {
+    for recipe in list {
         info!("  {}", recipe.to_arg_string());
     }
 
     Ok(())
 }
 
+/// Fetches the raw recipe payloads for every beverage, exactly as the machine sent them. These can be stored and
+/// decoded later, or compared across firmware versions.
+pub async fn raw_recipes(ecam: Ecam) -> Result<Vec<(EcamBeverageId, Vec<u8>)>, EcamError> {
+    // Wait for device to settle
+    ecam.wait_for_connection().await?;
+    Ok(accumulate_recipies_for(ecam, None).await?.raw_recipes())
+}
+
+/// Lists the beverages that this machine is able to make. Machines that don't answer recipe requests are assumed to
+/// make every known beverage.
+pub async fn supported_beverages(ecam: Ecam) -> Result<Vec<EcamBeverageId>, EcamError> {
+    // Wait for device to settle
+    ecam.wait_for_connection().await?;
+    let list = list_recipies_for(ecam, None).await?;
+    if list.recipes.is_empty() {
+        warning!("The machine didn't report any recipes, assuming every beverage is available");
+        return Ok(EcamBeverageId::all_values().to_vec());
+    }
+    Ok(list.recipes.iter().map(|recipe| recipe.beverage).collect())
+}
+
+/// Prints the beverages that this machine is able to make, optionally as a JSON array of beverage names.
+pub async fn list_supported_beverages(ecam: Ecam, json: bool) -> Result<(), EcamError> {
+    let beverages = supported_beverages(ecam).await?;
+    if json {
+        let names: Vec<_> = beverages.iter().map(|b| b.to_arg_string()).collect();
+        println!(
+            "{}",
+            serde_json::to_string(&names).expect("Failed to serialize beverages")
+        );
+    } else {
+        info!("Beverages supported:");
+        for beverage in beverages {
+            info!("  {}", beverage.to_arg_string());
+        }
+    }
+    Ok(())
+}
+
 fn enspacen(b: &[u8]) -> String {
     let mut s = "".to_owned();
     let space = "·";
@@ -405,30 +569,33 @@ pub async fn list_recipes_detailed(ecam: Ecam) -> Result<(), EcamError> {
     Ok(())
 }
 
-pub async fn list_recipes_raw(ecam: Ecam) -> Result<(), EcamError> {
+/// Prints the raw recipe and min/max payloads for every beverage as hex, optionally as a JSON array.
+pub async fn list_recipes_raw(ecam: Ecam, json: bool) -> Result<(), EcamError> {
     // Wait for device to settle
     ecam.wait_for_connection().await?;
     let list = accumulate_recipies_for(ecam, None).await?;
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string(&raw_recipes_json(&list)).expect("Failed to serialize recipes")
+        );
+        return Ok(());
+    }
     let mut s = "".to_owned();
 
-    for beverage in EcamBeverageId::all() {
+    for (beverage, recipe) in list.raw_recipes() {
         if !list.is_complete(beverage) {
             continue;
         }
 
         s += &format!("# {:?} (id=0x{:02x})\n", beverage, beverage as u8);
-        let (recipe, minmax) = list.get(beverage);
 
         // Print the recipe
-        if let Some(recipe) = recipe {
-            for (_i, recipe_info) in recipe.iter().enumerate() {
-                s += &hex::encode(&recipe_info.encode());
-            }
-        }
+        s += &hex::encode(recipe);
         s += "\n";
 
         // Print the min/max info
-        if let Some(minmax) = minmax {
+        if let (_, Some(minmax)) = list.get(beverage) {
             for (_i, minmax_info) in minmax.iter().enumerate() {
                 s += &hex::encode(&minmax_info.encode());
             }
@@ -440,3 +607,138 @@ pub async fn list_recipes_raw(ecam: Ecam) -> Result<(), EcamError> {
 
     Ok(())
 }
+
+/// Builds the JSON form of [`list_recipes_raw`], with the raw payloads as hex strings.
+fn raw_recipes_json(list: &RecipeAccumulator) -> Vec<serde_json::Value> {
+    list.raw_recipes()
+        .into_iter()
+        .filter(|(beverage, _)| list.is_complete(*beverage))
+        .map(|(beverage, recipe)| {
+            let min_max = list
+                .get(beverage)
+                .1
+                .unwrap_or_default()
+                .iter()
+                .collect_map_join("", |minmax_info| hex::encode(minmax_info.encode()));
+            serde_json::json!({
+                "beverage": beverage.to_arg_string(),
+                "id": beverage as u8,
+                "recipe": hex::encode(recipe),
+                "min_max": min_max,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ecam::get_ecam_simulator;
+
+    #[tokio::test(start_paused = true)]
+    async fn supported_beverages_restricted() -> Result<(), EcamError> {
+        let ecam = Ecam::new(
+            Box::new(get_ecam_simulator("sim[on][restricted]").await?),
+            false,
+        )
+        .await;
+        assert_eq!(
+            supported_beverages(ecam).await?,
+            vec![
+                EcamBeverageId::EspressoCoffee,
+                EcamBeverageId::RegularCoffee,
+                EcamBeverageId::HotWater
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn supported_beverages_without_recipes() -> Result<(), EcamError> {
+        let ecam = Ecam::new(
+            Box::new(get_ecam_simulator("sim[on][no-recipes]").await?),
+            false,
+        )
+        .await;
+        assert_eq!(
+            supported_beverages(ecam).await?,
+            EcamBeverageId::all_values().to_vec()
+        );
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn list_recipe_variants() -> Result<(), EcamError> {
+        let ecam = Ecam::new(
+            Box::new(get_ecam_simulator("sim[on][variants]").await?),
+            false,
+        )
+        .await;
+        let list = list_recipe_variants_for(
+            ecam,
+            Some(vec![
+                EcamBeverageId::RegularCoffee,
+                EcamBeverageId::Cappuccino,
+            ]),
+        )
+        .await?;
+        let variants: Vec<_> = list.iter().map(|r| (r.beverage, r.variant)).collect();
+        assert_eq!(
+            variants,
+            vec![
+                (EcamBeverageId::RegularCoffee, 1),
+                (EcamBeverageId::Cappuccino, 1),
+                (EcamBeverageId::Cappuccino, 2),
+            ]
+        );
+        let args = list[2].to_arg_string();
+        assert!(
+            args.starts_with("--beverage cappuccino --variant 2 "),
+            "{}",
+            args
+        );
+        assert!(args.contains("--coffee <20-180, default 80>"), "{}", args);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn raw_recipes_round_trip() -> Result<(), EcamError> {
+        let ecam = Ecam::new(Box::new(get_ecam_simulator("sim[on]").await?), false).await;
+        let raw = raw_recipes(ecam).await?;
+        assert!(raw
+            .iter()
+            .any(|(beverage, _)| *beverage == EcamBeverageId::RegularCoffee));
+        for (beverage, bytes) in raw {
+            let recipe = <Vec<RecipeInfo<u16>>>::partial_decode(&mut bytes.as_slice())
+                .unwrap_or_else(|| panic!("Failed to decode {:?}", beverage));
+            let encoded: Vec<u8> = recipe.iter().flat_map(|r| r.encode()).collect();
+            assert_eq!(encoded, bytes, "{:?}", beverage);
+        }
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn recipes_json() -> Result<(), EcamError> {
+        let ecam = Ecam::new(Box::new(get_ecam_simulator("sim[on]").await?), false).await;
+        let list = list_recipies_for(ecam, Some(vec![EcamBeverageId::RegularCoffee])).await?;
+        let json = serde_json::to_value(&list.recipes).expect("Failed to serialize");
+        let recipe = &json[0];
+        assert_eq!(recipe["beverage"], "regularcoffee");
+        assert_eq!(recipe["id"], 2);
+        assert_eq!(recipe["variant"], 1);
+        let ingredients = recipe["ingredients"]
+            .as_array()
+            .expect("Expected ingredients");
+        let coffee = ingredients
+            .iter()
+            .find(|i| i["ingredient"] == "coffee")
+            .expect("Expected coffee");
+        assert!(coffee["min"].is_u64());
+        assert!(coffee["min"].as_u64() <= coffee["value"].as_u64());
+        assert!(coffee["value"].as_u64() <= coffee["max"].as_u64());
+        assert!(ingredients
+            .iter()
+            .any(|i| i["ingredient"] == "taste" && i["value"].is_string()));
+        Ok(())
+    }
+}
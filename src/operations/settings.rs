@@ -0,0 +1,125 @@
+use crate::{
+    ecam::{Ecam, EcamError},
+    info,
+    operations::{read_parameter_value, write_parameter_value},
+    protocol::{PARAMETER_CUP_WARMER, PARAMETER_ENERGY_SAVING, PARAMETER_STARTUP_RINSE},
+};
+
+/// A machine setting that is either on or off. Their parameters haven't been confirmed on a real machine, so writing
+/// them could change something else entirely: [`set_toggle`] refuses to unless forced.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Toggle {
+    EnergySaving,
+    CupWarmer,
+    StartupRinse,
+}
+
+impl Toggle {
+    fn parameter(&self) -> u16 {
+        match self {
+            Self::EnergySaving => PARAMETER_ENERGY_SAVING,
+            Self::CupWarmer => PARAMETER_CUP_WARMER,
+            Self::StartupRinse => PARAMETER_STARTUP_RINSE,
+        }
+    }
+
+    /// A human-readable name for this setting.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::EnergySaving => "energy saving",
+            Self::CupWarmer => "cup warmer",
+            Self::StartupRinse => "startup rinse",
+        }
+    }
+}
+
+/// Reads the setting from the machine, failing with [`EcamError::NotSupported`] if the machine doesn't have it.
+pub async fn read_toggle(ecam: Ecam, toggle: Toggle) -> Result<bool, EcamError> {
+    match read_parameter_value(ecam, toggle.parameter(), 1).await?[..] {
+        [0, 0, ..] => Ok(false),
+        [0, 1, ..] => Ok(true),
+        _ => Err(EcamError::NotSupported(toggle.name())),
+    }
+}
+
+/// Turns the setting on or off, and confirms that it took effect by reading it back.
+///
+/// The write is refused with [`EcamError::UnsafeParameter`] unless `force` is set, since the setting's parameter
+/// hasn't been confirmed on a real machine.
+pub async fn set_toggle(
+    ecam: Ecam,
+    toggle: Toggle,
+    enabled: bool,
+    force: bool,
+) -> Result<(), EcamError> {
+    if !force {
+        info!(
+            "The {} parameter hasn't been confirmed on a real machine, pass --force to write it anyway",
+            toggle.name()
+        );
+        return Err(EcamError::UnsafeParameter(toggle.parameter()));
+    }
+    // Make sure the machine actually has the setting before we write to it
+    read_toggle(ecam.clone(), toggle).await?;
+    write_parameter_value(ecam.clone(), toggle.parameter(), vec![0, enabled as u8]).await?;
+    if read_toggle(ecam, toggle).await? != enabled {
+        return Err(EcamError::NotApplied(toggle.name()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ecam::get_ecam_simulator;
+    use rstest::*;
+
+    #[rstest]
+    #[case(Toggle::EnergySaving, true)]
+    #[case(Toggle::CupWarmer, false)]
+    #[case(Toggle::StartupRinse, true)]
+    #[tokio::test(start_paused = true)]
+    async fn read_toggles(#[case] toggle: Toggle, #[case] enabled: bool) -> Result<(), EcamError> {
+        let ecam = Ecam::new(Box::new(get_ecam_simulator("sim[on]").await?), false).await;
+        assert_eq!(read_toggle(ecam, toggle).await?, enabled);
+        Ok(())
+    }
+
+    #[rstest]
+    #[tokio::test(start_paused = true)]
+    async fn set_and_read_toggle(
+        #[values(Toggle::EnergySaving, Toggle::CupWarmer, Toggle::StartupRinse)] toggle: Toggle,
+    ) -> Result<(), EcamError> {
+        let ecam = Ecam::new(Box::new(get_ecam_simulator("sim[on]").await?), false).await;
+        for enabled in [true, false] {
+            set_toggle(ecam.clone(), toggle, enabled, true).await?;
+            assert_eq!(read_toggle(ecam.clone(), toggle).await?, enabled);
+        }
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn set_toggle_requires_force() -> Result<(), EcamError> {
+        let ecam = Ecam::new(Box::new(get_ecam_simulator("sim[on]").await?), false).await;
+        match set_toggle(ecam.clone(), Toggle::EnergySaving, false, false).await {
+            Err(EcamError::UnsafeParameter(PARAMETER_ENERGY_SAVING)) => {}
+            x => panic!("Expected the write to be refused, got {:?}", x),
+        }
+        assert!(read_toggle(ecam, Toggle::EnergySaving).await?);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn toggle_not_supported() -> Result<(), EcamError> {
+        let ecam = Ecam::new(
+            Box::new(get_ecam_simulator("sim[on][no-cup-warmer]").await?),
+            false,
+        )
+        .await;
+        match read_toggle(ecam, Toggle::CupWarmer).await {
+            Err(EcamError::NotSupported(_)) => {}
+            x => panic!("Expected the cup warmer to be unsupported, got {:?}", x),
+        }
+        Ok(())
+    }
+}
@@ -5,6 +5,64 @@ use num_enum::{IntoPrimitive, TryFromPrimitive};
 ///! This file contains validated hardware enumerations and associated values.
 
 macro_rules! hardware_enum {
+    // Enumerations identified by a u16 (ie: parameters) can't be used in a [`super::MachineEnum`], so they get the
+    // same methods as [`MachineEnumerable`] without implementing it.
+    ($comment:literal, $name:ident: u16 { $($(# [ doc = $item_comment:literal ])? $x:ident = $v:literal,)* } ) => {
+        #[doc=$comment]
+        #[repr(u16)]
+        #[derive(
+            Copy,
+            Clone,
+            Debug,
+            PartialEq,
+            PartialOrd,
+            Ord,
+            IntoPrimitive,
+            TryFromPrimitive,
+            Eq,
+            Hash,
+        )]
+        pub enum $name { $($(#[doc=$item_comment])? $x = $v),* }
+
+        impl $name {
+            /// Return a static slice of all possible enumeration values, useful for iteration.
+            pub fn all_values() -> &'static[$name] {
+                &[$(Self::$x),*]
+            }
+
+            /// Iterates over all the values of the enumeration.
+            pub fn all() -> core::iter::Copied<std::slice::Iter<'static, $name>> {
+                Self::all_values().iter().copied()
+            }
+
+            pub fn lookup_by_name_case_insensitive(s: &str) -> Option<$name> {
+                Self::all().find(|e| format!("{:?}", e).eq_ignore_ascii_case(s))
+            }
+
+            pub fn lookup_by_name(s: &str) -> Option<$name> {
+                Self::all().find(|e| format!("{:?}", e) == s)
+            }
+
+            /// Generate the argument-style string for this enum.
+            pub fn to_arg_string(&self) -> String {
+                match *self {
+                    $(Self::$x => {
+                        stringify!($x).to_ascii_lowercase()
+                    })*
+                }
+            }
+
+            /// Looks up a value by the numeric id the machine uses for it, returning `None` for unknown ids.
+            pub fn lookup_by_id(id: u16) -> Option<$name> {
+                Self::try_from(id).ok()
+            }
+
+            /// The numeric id the machine uses for this value.
+            pub fn id(&self) -> u16 {
+                (*self).into()
+            }
+        }
+    };
     ($comment:literal, $name:ident { $($(# [ doc = $item_comment:literal ])? $x:ident = $v:literal,)* } ) => {
         #[doc=$comment]
         #[repr(u8)]
@@ -292,3 +350,48 @@ hardware_enum! {"The various switches that the machine reads.", EcamMachineSwitc
     DoorOpened = 13,
     PregroundDoorOpened = 14,
 }}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::*;
+
+    /// Pins the byte each machine state is reported with, as [`EcamMachineState`] is the only description of the
+    /// machine state used when decoding monitor responses.
+    #[rstest]
+    #[case(0, Some(EcamMachineState::StandBy))]
+    #[case(1, Some(EcamMachineState::TurningOn))]
+    #[case(2, Some(EcamMachineState::ShuttingDown))]
+    #[case(3, None)]
+    #[case(4, Some(EcamMachineState::Descaling))]
+    #[case(5, Some(EcamMachineState::SteamPreparation))]
+    #[case(6, Some(EcamMachineState::Recovery))]
+    #[case(7, Some(EcamMachineState::ReadyOrDispensing))]
+    #[case(8, Some(EcamMachineState::Rinsing))]
+    #[case(9, None)]
+    #[case(10, Some(EcamMachineState::MilkPreparation))]
+    #[case(11, Some(EcamMachineState::HotWaterDelivery))]
+    #[case(12, Some(EcamMachineState::MilkCleaning))]
+    #[case(16, Some(EcamMachineState::ChocolatePreparation))]
+    fn machine_state_bytes(#[case] byte: u8, #[case] state: Option<EcamMachineState>) {
+        assert_eq!(EcamMachineState::try_from(byte).ok(), state);
+        if let Some(state) = state {
+            assert_eq!(u8::from(state), byte);
+        }
+    }
+
+    #[test]
+    fn beverage_id_round_trip() {
+        for beverage in EcamBeverageId::all() {
+            assert_eq!(
+                EcamBeverageId::lookup_by_id(beverage.to_id()),
+                Some(beverage)
+            );
+        }
+        assert_eq!(
+            EcamBeverageId::lookup_by_id(7),
+            Some(EcamBeverageId::Cappuccino)
+        );
+        assert_eq!(EcamBeverageId::lookup_by_id(0), None);
+    }
+}
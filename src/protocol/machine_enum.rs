@@ -1,3 +1,4 @@
+use serde::{Serialize, Serializer};
 use std::{fmt::Debug, hash::Hash, marker::PhantomData};
 
 /// Helper trait that collects the requirements for a MachineEnum.
@@ -15,6 +16,16 @@ where
     fn all() -> core::iter::Copied<std::slice::Iter<'static, T>> {
         Self::all_values().iter().copied()
     }
+
+    /// Looks up a value by the numeric id the machine uses for it, returning `None` for unknown ids.
+    fn lookup_by_id(id: u8) -> Option<T> {
+        T::try_from(id).ok()
+    }
+
+    /// The numeric id the machine uses for this value.
+    fn to_id(&self) -> u8 {
+        (*self).into()
+    }
 }
 
 /// Wraps a machine enumeration that may have unknown values.
@@ -38,7 +49,7 @@ where
     T: MachineEnumerable<T>,
 {
     pub fn decode(value: u8) -> Self {
-        if let Ok(value) = T::try_from(value) {
+        if let Some(value) = T::lookup_by_id(value) {
             MachineEnum::Value(value)
         } else {
             MachineEnum::Unknown(value)
@@ -82,6 +93,13 @@ impl<T: MachineEnumerable<T>> Debug for MachineEnum<T> {
     }
 }
 
+/// Serializes as the name of the value, or `Unknown(n)` for unknown values.
+impl<T: MachineEnumerable<T>> Serialize for MachineEnum<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&format_args!("{:?}", self))
+    }
+}
+
 impl<T: MachineEnumerable<T>> PartialEq<T> for MachineEnum<T> {
     fn eq(&self, other: &T) -> bool {
         match self {
@@ -124,6 +142,11 @@ impl<T: MachineEnumerable<T>> SwitchSet<T> {
         }
     }
 
+    /// Is the given value in this set?
+    pub fn contains(&self, t: T) -> bool {
+        self.value & (1 << <T as Into<u8>>::into(t)) != 0
+    }
+
     pub fn set(&self) -> Vec<MachineEnum<T>> {
         // TODO: This should be an iterator
         let mut v = vec![];
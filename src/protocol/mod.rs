@@ -1,14 +1,21 @@
 //! Protocols for communication with ECAM-based devices.
 
+#[macro_use]
 mod hardware_enums;
 mod machine_enum;
+mod multi_frame;
 mod packet;
+mod parameter;
 mod request;
+mod util;
 
 pub use hardware_enums::*;
 pub use machine_enum::*;
+pub use multi_frame::*;
 pub use packet::*;
+pub use parameter::*;
 pub use request::*;
+pub use util::*;
 
 #[cfg(test)]
 pub mod test {
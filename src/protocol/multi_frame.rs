@@ -0,0 +1,98 @@
+//! Reassembly of responses that the device sends as several frames, each tagged with a sequence number.
+//!
+//! Long parameter reads are the only user (see [`crate::operations::read_parameter_value`]), where the sequence number
+//! is the parameter ID each response echoes. Recipe reads don't need this: every recipe request is answered by a
+//! single frame tagged with its beverage, and [`crate::operations::RecipeAccumulator`] already tracks which beverages
+//! are still missing and asks for them again.
+
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+/// Some frames never arrived, so the response couldn't be reassembled.
+#[derive(Clone, Debug, Eq, PartialEq, Error)]
+#[error("missing frames {0:?}")]
+pub struct MissingFrames(pub Vec<u16>);
+
+/// Collects the frames of a multi-frame response, which may arrive in any order, and joins them in sequence order
+/// once they have all arrived.
+#[derive(Clone, Debug, Default)]
+pub struct MultiFrameReassembler {
+    expected: Vec<u16>,
+    frames: BTreeMap<u16, Vec<u8>>,
+}
+
+impl MultiFrameReassembler {
+    /// Creates a reassembler expecting a frame for each of the given sequence numbers.
+    pub fn new(expected: impl IntoIterator<Item = u16>) -> Self {
+        let mut expected: Vec<_> = expected.into_iter().collect();
+        expected.sort_unstable();
+        expected.dedup();
+        MultiFrameReassembler {
+            expected,
+            frames: BTreeMap::new(),
+        }
+    }
+
+    /// Accepts a frame, returning `false` if it wasn't expected. Repeated frames replace the earlier copy.
+    pub fn accept(&mut self, sequence: u16, payload: Vec<u8>) -> bool {
+        if self.expected.binary_search(&sequence).is_err() {
+            return false;
+        }
+        self.frames.insert(sequence, payload);
+        true
+    }
+
+    /// The sequence numbers of the frames that haven't arrived yet, in order.
+    pub fn missing(&self) -> Vec<u16> {
+        self.expected
+            .iter()
+            .filter(|sequence| !self.frames.contains_key(sequence))
+            .copied()
+            .collect()
+    }
+
+    /// Have all of the expected frames arrived?
+    pub fn is_complete(&self) -> bool {
+        self.frames.len() == self.expected.len()
+    }
+
+    /// Joins the frames in sequence order, failing with [`MissingFrames`] if any haven't arrived.
+    pub fn take(self) -> Result<Vec<u8>, MissingFrames> {
+        let missing = self.missing();
+        if !missing.is_empty() {
+            return Err(MissingFrames(missing));
+        }
+        Ok(self.frames.into_values().flatten().collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::*;
+
+    #[rstest]
+    #[case::in_order(&[0, 10, 20])]
+    #[case::out_of_order(&[20, 0, 10])]
+    #[case::repeated(&[10, 0, 10, 20])]
+    fn reassemble(#[case] order: &[u16]) {
+        let mut reassembler = MultiFrameReassembler::new([0, 10, 20]);
+        for sequence in order {
+            assert!(!reassembler.is_complete());
+            assert!(reassembler.accept(*sequence, vec![*sequence as u8, 1]));
+        }
+        assert!(reassembler.is_complete());
+        assert_eq!(reassembler.take(), Ok(vec![0, 1, 10, 1, 20, 1]));
+    }
+
+    #[test]
+    fn truncated() {
+        let mut reassembler = MultiFrameReassembler::new([0, 10, 20]);
+        assert!(reassembler.accept(0, vec![1]));
+        assert!(!reassembler.accept(5, vec![2]));
+        assert!(reassembler.accept(20, vec![3]));
+        assert!(!reassembler.is_complete());
+        assert_eq!(reassembler.missing(), vec![10]);
+        assert_eq!(reassembler.take(), Err(MissingFrames(vec![10])));
+    }
+}
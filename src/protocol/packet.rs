@@ -1,4 +1,5 @@
 use crate::protocol::request::{PartialDecode, PartialEncode};
+use crate::protocol::util::{hexdump, stringify};
 use crc::Crc;
 use std::fmt::Debug;
 
@@ -42,7 +43,7 @@ pub struct EcamPacket<T> {
 }
 
 impl<T> EcamPacket<T> {
-    #[cfg(test)]
+    /// Creates a packet from a raw payload, without a representation.
     pub fn from_raw(input: &[u8]) -> EcamPacket<T> {
         let bytes = EcamDriverPacket::from_vec(input.to_vec());
         EcamPacket {
@@ -88,6 +89,10 @@ impl<T> From<EcamPacket<T>> for EcamDriverPacket {
 
 pub const CRC_ALGO: Crc<u16> = Crc::<u16>::new(&crc::CRC_16_SPI_FUJITSU);
 
+/// The longest payload that fits in a single packet. The length byte also counts the length byte itself and the
+/// checksum, so it can't describe anything longer.
+pub const MAX_PAYLOAD_LEN: usize = u8::MAX as usize - 3;
+
 /// Computes the checksum from a partial packet. Note that the checksum used here is
 /// equivalent to the `CRC_16_SPI_FUJITSU` definition (initial 0x1d0f, poly 0x1021).
 pub fn checksum(buffer: &[u8]) -> [u8; 2] {
@@ -95,16 +100,18 @@ pub fn checksum(buffer: &[u8]) -> [u8; 2] {
     [(i >> 8) as u8, (i & 0xff) as u8]
 }
 
-/// Returns the contents of the packet, minus header and checksum.
+/// Returns the contents of the packet, minus header and checksum. A buffer too short to hold both is returned as empty.
 pub fn unwrap_packet<T: ?Sized>(buffer: &T) -> &[u8]
 where
     T: AsRef<[u8]>,
 {
     let u: &[u8] = buffer.as_ref();
-    &u[2..u.len() - 2]
+    u.get(2..u.len().saturating_sub(2)).unwrap_or_default()
 }
 
-fn packetize(buffer: &[u8]) -> Vec<u8> {
+/// Wraps a payload in a packet, adding the host->device header, the length and the checksum. Panics if the payload is
+/// longer than [`MAX_PAYLOAD_LEN`].
+pub fn packetize(buffer: &[u8]) -> Vec<u8> {
     let mut out = [
         &[
             0x0d,
@@ -117,73 +124,52 @@ fn packetize(buffer: &[u8]) -> Vec<u8> {
     out
 }
 
-fn stringify(buffer: &[u8]) -> String {
-    buffer
-        .iter()
-        .map(|n| format!("{:02x}", n))
-        .collect::<String>()
-}
-
-/// Dumps a packet to a readable hex form.
-pub fn hexdump(buffer: &[u8]) -> String {
-    let maybe_space = |i| if i > 0 && i % 8 == 0 { " " } else { "" };
-    let s1: String = buffer
-        .iter()
-        .enumerate()
-        .map(|(i, b)| format!("{}{:02x}", maybe_space(i), b))
-        .collect::<String>();
-    let s2: String = buffer
-        .iter()
-        .enumerate()
-        .map(|(_i, b)| {
-            if *b >= 32 && *b < 127 {
-                *b as char
-            } else {
-                '.'
-            }
-        })
-        .collect::<String>();
-    format!("|{}| |{}|", s1, s2)
-}
-
 #[cfg(test)]
 pub mod test {
-    use super::{checksum, packetize};
+    use super::{checksum, packetize, unwrap_packet};
+    use crate::protocol::util::from_hex_str;
 
-    pub fn from_hex_str(s: &str) -> Vec<u8> {
-        hex::decode(s.replace(' ', "")).unwrap()
+    fn hex(s: &str) -> Vec<u8> {
+        from_hex_str(s).expect("Invalid hex")
     }
 
     #[test]
     pub fn test_checksum() {
         assert_eq!(
-            checksum(&from_hex_str("0d 0f 83 f0 02 01 01 00 67 02 02 00 00 06")),
+            checksum(&hex("0d 0f 83 f0 02 01 01 00 67 02 02 00 00 06")),
             [0x77, 0xff]
         );
         assert_eq!(
-            checksum(&from_hex_str("0d 0d 83 f0 05 01 01 00 78 00 00 06")),
+            checksum(&hex("0d 0d 83 f0 05 01 01 00 78 00 00 06")),
             [0xc4, 0x7e]
         );
-        assert_eq!(checksum(&from_hex_str("0d 07 84 0f 02 01")), [0x55, 0x12]);
+        assert_eq!(checksum(&hex("0d 07 84 0f 02 01")), [0x55, 0x12]);
     }
 
     #[test]
     pub fn test_packetize() {
         assert_eq!(
-            packetize(&from_hex_str("83 f0 02 01 01 00 67 02 02 00 00 06")),
-            from_hex_str("0d 0f 83 f0 02 01 01 00 67 02 02 00 00 06 77 ff")
+            packetize(&hex("83 f0 02 01 01 00 67 02 02 00 00 06")),
+            hex("0d 0f 83 f0 02 01 01 00 67 02 02 00 00 06 77 ff")
         );
         assert_eq!(
-            packetize(&from_hex_str("83 f0 05 01 01 00 78 00 00 06")),
-            from_hex_str("0d 0d 83 f0 05 01 01 00 78 00 00 06 c4 7e")
+            packetize(&hex("83 f0 05 01 01 00 78 00 00 06")),
+            hex("0d 0d 83 f0 05 01 01 00 78 00 00 06 c4 7e")
         );
         assert_eq!(
-            packetize(&from_hex_str("84 0f 02 01")),
-            from_hex_str("0d 07 84 0f 02 01 55 12")
+            packetize(&hex("84 0f 02 01")),
+            hex("0d 07 84 0f 02 01 55 12")
         );
+        assert_eq!(packetize(&hex("75 f0")), hex("0d 05 75 f0 c4 d5"));
+    }
+
+    #[test]
+    pub fn test_unwrap_short_packet() {
+        assert_eq!(unwrap_packet(&[]), &[] as &[u8]);
+        assert_eq!(unwrap_packet(&[0xd0, 0x05, 0x75]), &[] as &[u8]);
         assert_eq!(
-            packetize(&from_hex_str("75 f0")),
-            from_hex_str("0d 05 75 f0 c4 d5")
+            unwrap_packet(&[0xd0, 0x05, 0x75, 0x0f, 0xaa, 0xbb]),
+            &[0x75, 0x0f]
         );
     }
 }
@@ -0,0 +1,59 @@
+//! Identifiers for parameters that can be read and written with [`super::Request::ParameterRead`] and
+//! [`super::Request::ParameterWrite`].
+
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+
+/// The machine's clock, encoded as the hour followed by the minute. Machines without a clock return no data.
+///
+/// Neither the ID nor the encoding has been confirmed against a capture from a real machine, so
+/// [`crate::operations::set_clock`] only writes it when forced, and reads are reported as unconfirmed.
+/// [`super::EcamRequestId::SetTime`] may be how the app sets the clock, but its payload is unknown, so it isn't used.
+pub const PARAMETER_CLOCK: u16 = ParameterId::Clock as u16;
+
+/// Energy saving mode, encoded as a 16-bit value that is zero when off and one when on. Neither the ID nor the encoding
+/// has been confirmed against a capture from a real machine, so [`crate::operations::set_toggle`] only writes it when
+/// forced.
+pub const PARAMETER_ENERGY_SAVING: u16 = ParameterId::EnergySaving as u16;
+
+/// The cup warmer plate, encoded as a 16-bit value that is zero when off and one when on. Neither the ID nor the
+/// encoding has been confirmed against a capture from a real machine, so [`crate::operations::set_toggle`] only writes
+/// it when forced.
+pub const PARAMETER_CUP_WARMER: u16 = ParameterId::CupWarmer as u16;
+
+/// Whether the machine rinses when it turns on, encoded as a 16-bit value that is zero when off and one when on.
+/// Neither the ID nor the encoding has been confirmed against a capture from a real machine, so
+/// [`crate::operations::set_toggle`] only writes it when forced.
+pub const PARAMETER_STARTUP_RINSE: u16 = ParameterId::StartupRinse as u16;
+
+/// Parameters that are known to leave the machine's settings in a bad state when written, along with the reason. These
+/// are refused by [`crate::operations::probe_parameter`] unless forced. Add to this list as parameters are found to be
+/// unsafe to write.
+pub const PARAMETER_BLOCKLIST: &[(u16, &str)] = &[];
+
+hardware_enum! {"The parameters we know about, so they can be referred to by name rather than by their raw ID.", ParameterId: u16 {
+    Clock = 0x005f,
+    EnergySaving = 0x0062,
+    CupWarmer = 0x0063,
+    StartupRinse = 0x0064,
+}}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::*;
+
+    #[rstest]
+    #[case(ParameterId::Clock, 0x005f, "clock")]
+    #[case(ParameterId::EnergySaving, 0x0062, "energysaving")]
+    #[case(ParameterId::CupWarmer, 0x0063, "cupwarmer")]
+    #[case(ParameterId::StartupRinse, 0x0064, "startuprinse")]
+    fn parameter_ids(#[case] param: ParameterId, #[case] id: u16, #[case] name: &str) {
+        assert_eq!(param.id(), id);
+        assert_eq!(param.to_arg_string(), name);
+        assert_eq!(ParameterId::lookup_by_id(id), Some(param));
+        assert_eq!(
+            ParameterId::lookup_by_name_case_insensitive(&name.to_ascii_uppercase()),
+            Some(param)
+        );
+    }
+}
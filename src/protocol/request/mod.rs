@@ -197,9 +197,9 @@ packet_definition!(
         ingredients Vec<RecipeInfo<u16>>,
         mode MachineEnum<EcamBeverageTasteType>) => (unknown0 u8, unknown1 u8),
     AppControl(request AppControl) => (),
-    ParameterRead(parameter u16, len u8) => (),
-    ParameterWrite() => (),
-    ParameterReadExt(parameter u16, len u8) => (),
+    ParameterRead(parameter u16, len u8) => (parameter u16, data Vec<u8>),
+    ParameterWrite(parameter u16, data Vec<u8>) => (),
+    ParameterReadExt(parameter u16, len u8) => (parameter u16, data Vec<u8>),
     StatisticsRead(parameter u16, len u8) => (),
     Checksum() => (),
     ProfileNameRead(start u8, end u8) => (names Vec<WideStringWithIcon>),
@@ -250,11 +250,24 @@ mod test {
     fn real_packets_decode_as_expected(#[case] bytes: &[u8]) {
         let (packet, remainder) = Response::decode(unwrap_packet(bytes));
         let packet = packet.expect("Expected to decode something");
-        assert_eq!(remainder, &[]);
+        assert_eq!(remainder, &[] as &[u8]);
         // Not actually testing the decoding of these packets, but at least we can print it
         println!("{:?}", packet);
     }
 
+    #[rstest]
+    #[case(&[])]
+    #[case(&[117])]
+    #[case(&[117, 15, 1])]
+    #[case(&[149, 240, 0])]
+    fn short_packets_do_not_decode(#[case] bytes: &[u8]) {
+        assert_eq!(Response::decode(bytes).0, None);
+        assert_eq!(
+            EcamPacket::<Response>::from_bytes(bytes).representation,
+            None
+        );
+    }
+
     #[test]
     fn test_decode_monitor_packet() {
         let buf = [117_u8, 15, 1, 5, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0];
@@ -316,6 +329,24 @@ mod test {
         )
     }
 
+    #[test]
+    fn test_decode_parameter_packet() {
+        let buf = [149_u8, 240, 0, 95, 8, 30];
+        let input = &mut buf.as_slice();
+        assert_eq!(
+            <Response>::partial_decode(input).expect("Failed to decode"),
+            Response::ParameterRead(95, vec![8, 30])
+        );
+    }
+
+    #[rstest]
+    #[case(Request::ParameterRead(PARAMETER_CLOCK, 1), &[149, 240, 0, 95, 1])]
+    #[case(Request::ParameterRead(PARAMETER_STARTUP_RINSE, 2), &[149, 240, 0, 100, 2])]
+    #[case(Request::ParameterReadExt(0x1234, 10), &[161, 240, 0x12, 0x34, 10])]
+    fn test_encode_parameter_read(#[case] request: Request, #[case] bytes: &[u8]) {
+        assert_eq!(request.encode(), bytes);
+    }
+
     #[test]
     fn test_brew_coffee() {
         let recipe = vec![
@@ -334,4 +365,38 @@ mod test {
             vec![0x83, 0xf0, 0x02, 0x01, 0x01, 0x00, 0x67, 0x02, 0x02, 0x00, 0x00, 0x06]
         );
     }
+
+    #[rstest]
+    #[case(
+        EcamBeverageId::EspressoCoffee,
+        vec![
+            RecipeInfo::new(EcamIngredients::Coffee, 40),
+            RecipeInfo::new(EcamIngredients::Taste, 3),
+            RecipeInfo::new(EcamIngredients::Temp, 1),
+        ],
+        &[0x83, 0xf0, 0x01, 0x01, 0x01, 0x00, 0x28, 0x02, 0x03, 0x00, 0x01, 0x02]
+    )]
+    #[case(
+        EcamBeverageId::Cappuccino,
+        vec![
+            RecipeInfo::new(EcamIngredients::Coffee, 65),
+            RecipeInfo::new(EcamIngredients::Milk, 190),
+            RecipeInfo::new(EcamIngredients::Taste, 4),
+            RecipeInfo::new(EcamIngredients::Temp, 2),
+        ],
+        &[0x83, 0xf0, 0x07, 0x01, 0x01, 0x00, 0x41, 0x09, 0x00, 0xbe, 0x02, 0x04, 0x00, 0x02, 0x02]
+    )]
+    fn test_encode_beverage(
+        #[case] beverage: EcamBeverageId,
+        #[case] recipe: Vec<RecipeInfo<u16>>,
+        #[case] bytes: &[u8],
+    ) {
+        let request = Request::BeverageDispensingMode(
+            beverage.into(),
+            EcamOperationTrigger::Start.into(),
+            recipe,
+            EcamBeverageTasteType::Prepare.into(),
+        );
+        assert_eq!(request.encode(), bytes);
+    }
 }
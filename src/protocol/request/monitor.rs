@@ -17,6 +17,88 @@ pub struct MonitorV2Response {
     pub unknown2: u8,
     pub unknown3: u8,
     pub unknown4: u8,
+    /// The scale of [`MonitorV2Response::percentage`] for the connected model. This isn't sent by the device.
+    pub scale: PercentageScale,
+}
+
+/// Something the user needs to take care of, as reported by [`MonitorV2Response::warnings`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq, serde::Serialize)]
+pub enum EcamWarning {
+    /// The water tank has been removed.
+    WaterTankAbsent,
+    /// The coffee grounds container has been removed.
+    CoffeeWasteContainerAbsent,
+    /// The machine raised an alarm.
+    Alarm(MachineEnum<EcamMachineAlarm>),
+}
+
+impl MonitorV2Response {
+    /// The progress of the current operation from 0 to 100, regardless of the scale this model reports it on. The raw
+    /// value is available in [`MonitorV2Response::percentage`].
+    pub fn progress_percent(&self) -> u8 {
+        self.scale.to_percent(self.percentage)
+    }
+
+    /// Is the water tank in place?
+    pub fn water_tank_present(&self) -> bool {
+        !self.switches.contains(EcamMachineSwitch::WaterTankAbsent)
+    }
+
+    /// Is the hot water spout attached?
+    pub fn water_spout_present(&self) -> bool {
+        self.switches.contains(EcamMachineSwitch::WaterSpout)
+    }
+
+    /// All of the warnings that are currently active: missing parts first, then any alarms.
+    pub fn warnings(&self) -> Vec<EcamWarning> {
+        let mut warnings = vec![];
+        if !self.water_tank_present() {
+            warnings.push(EcamWarning::WaterTankAbsent);
+        }
+        if !self.coffee_waste_container_present() {
+            warnings.push(EcamWarning::CoffeeWasteContainerAbsent);
+        }
+        warnings.extend(self.alarms.set().into_iter().map(EcamWarning::Alarm));
+        warnings
+    }
+
+    /// Is the coffee grounds container in place? The machine reports the container's switch when it is removed.
+    pub fn coffee_waste_container_present(&self) -> bool {
+        !self
+            .switches
+            .contains(EcamMachineSwitch::CoffeeWasteContainer)
+    }
+}
+
+/// The scale a model uses to report the percentage byte in [`MonitorV2Response`]. No model is known to use anything
+/// but [`PercentageScale::Percent`], so the byte scale is only used when a driver reports it (see
+/// [`crate::ecam::DeviceInfo::percentage_scale`]) or it is asked for explicitly.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum PercentageScale {
+    /// The percentage is reported from 0 to 100.
+    #[default]
+    Percent,
+    /// The percentage is reported from 0 to 255.
+    Byte,
+}
+
+impl PercentageScale {
+    /// Converts a raw percentage byte on this scale to a value from 0 to 100.
+    pub fn to_percent(&self, raw: u8) -> u8 {
+        match self {
+            PercentageScale::Percent => raw.min(100),
+            PercentageScale::Byte => ((raw as u16 * 100 + 127) / 255) as u8,
+        }
+    }
+
+    /// Converts a value from 0 to 100 to a raw percentage byte on this scale.
+    pub fn from_percent(&self, percent: u8) -> u8 {
+        let percent = percent.min(100);
+        match self {
+            PercentageScale::Percent => percent,
+            PercentageScale::Byte => ((percent as u16 * 255 + 50) / 100) as u8,
+        }
+    }
 }
 
 impl<T: MachineEnumerable<T>> PartialDecode<SwitchSet<T>> for SwitchSet<T> {
@@ -30,7 +112,9 @@ impl<T: MachineEnumerable<T>> PartialDecode<SwitchSet<T>> for SwitchSet<T> {
 
 impl<T: MachineEnumerable<T>> PartialEncode for SwitchSet<T> {
     fn partial_encode(&self, out: &mut Vec<u8>) {
-        self.value.partial_encode(out)
+        // Note that this is inverted from <u16>::partial_encode, to match the decoder
+        out.push(self.value as u8);
+        out.push((self.value >> 8) as u8);
     }
 }
 
@@ -48,6 +132,7 @@ impl PartialDecode<MonitorV2Response> for MonitorV2Response {
             unknown2: <u8>::partial_decode(input)?,
             unknown3: <u8>::partial_decode(input)?,
             unknown4: <u8>::partial_decode(input)?,
+            scale: PercentageScale::default(),
         })
     }
 }
@@ -70,9 +155,13 @@ impl PartialEncode for MonitorV2Response {
 
 #[cfg(test)]
 mod test {
-    use crate::protocol::EcamMachineSwitch;
+    use crate::protocol::{
+        unwrap_packet, EcamAccessory, EcamMachineAlarm, EcamMachineSwitch, PartialDecode,
+        PartialEncode,
+    };
 
-    use super::SwitchSet;
+    use super::{EcamWarning, MonitorV2Response, PercentageScale, SwitchSet};
+    use rstest::*;
 
     #[test]
     fn switch_set_test() {
@@ -82,4 +171,104 @@ mod test {
             SwitchSet::of(&[EcamMachineSwitch::MotorDown, EcamMachineSwitch::WaterSpout]);
         assert_eq!("WaterSpout | MotorDown", format!("{:?}", switches));
     }
+
+    #[rstest]
+    #[case(&crate::protocol::test::RESPONSE_STATUS_STANDBY_NO_ALARMS, EcamAccessory::None, true, false, true)]
+    #[case(&crate::protocol::test::RESPONSE_STATUS_STANDBY_NO_WATER_TANK, EcamAccessory::None, false, false, true)]
+    #[case(&crate::protocol::test::RESPONSE_STATUS_STANDBY_WATER_SPOUT, EcamAccessory::Water, true, true, true)]
+    #[case(&crate::protocol::test::RESPONSE_STATUS_STANDBY_NO_COFFEE_CONTAINER, EcamAccessory::Water, true, true, false)]
+    #[case(&crate::protocol::test::RESPONSE_STATUS_CAPPUCCINO_MILK, EcamAccessory::Milk, true, false, true)]
+    fn decode_switches(
+        #[case] bytes: &[u8],
+        #[case] accessory: EcamAccessory,
+        #[case] water_tank: bool,
+        #[case] water_spout: bool,
+        #[case] coffee_waste_container: bool,
+    ) {
+        let response = MonitorV2Response::partial_decode(&mut &unwrap_packet(bytes)[2..])
+            .expect("Failed to decode");
+        assert_eq!(response.accessory, accessory);
+        assert_eq!(response.water_tank_present(), water_tank);
+        assert_eq!(response.water_spout_present(), water_spout);
+        assert_eq!(
+            response.coffee_waste_container_present(),
+            coffee_waste_container
+        );
+    }
+
+    #[test]
+    fn decode_warnings() {
+        let response = MonitorV2Response::partial_decode(
+            &mut &unwrap_packet(
+                &crate::protocol::test::RESPONSE_STATUS_STANDBY_NO_COFFEE_CONTAINER,
+            )[2..],
+        )
+        .expect("Failed to decode");
+        assert_eq!(
+            response.warnings(),
+            vec![EcamWarning::CoffeeWasteContainerAbsent]
+        );
+
+        // Several warnings can be active at once
+        let response = MonitorV2Response {
+            switches: SwitchSet::of(&[
+                EcamMachineSwitch::WaterTankAbsent,
+                EcamMachineSwitch::CoffeeWasteContainer,
+            ]),
+            alarms: SwitchSet::of(&[
+                EcamMachineAlarm::EmptyWaterTank,
+                EcamMachineAlarm::CoffeeBeansEmpty,
+            ]),
+            ..Default::default()
+        };
+        assert_eq!(
+            response.warnings(),
+            vec![
+                EcamWarning::WaterTankAbsent,
+                EcamWarning::CoffeeWasteContainerAbsent,
+                EcamWarning::Alarm(EcamMachineAlarm::EmptyWaterTank.into()),
+                EcamWarning::Alarm(EcamMachineAlarm::CoffeeBeansEmpty.into()),
+            ]
+        );
+        assert_eq!(MonitorV2Response::default().warnings(), vec![]);
+    }
+
+    #[rstest]
+    #[case(&[])]
+    #[case(&[EcamMachineAlarm::CoffeeWasteContainerFull])]
+    #[case(&[EcamMachineAlarm::EmptyWaterTank, EcamMachineAlarm::CleanKnob])]
+    fn switch_set_round_trip(#[case] alarms: &[EcamMachineAlarm]) {
+        let mut out = vec![];
+        SwitchSet::of(alarms).partial_encode(&mut out);
+        let decoded = <SwitchSet<EcamMachineAlarm>>::partial_decode(&mut out.as_slice());
+        assert_eq!(decoded, Some(SwitchSet::of(alarms)));
+    }
+
+    #[rstest]
+    #[case(PercentageScale::Percent, 0, 0)]
+    #[case(PercentageScale::Percent, 50, 50)]
+    #[case(PercentageScale::Percent, 100, 100)]
+    #[case(PercentageScale::Percent, 150, 100)]
+    #[case(PercentageScale::Byte, 0, 0)]
+    #[case(PercentageScale::Byte, 128, 50)]
+    #[case(PercentageScale::Byte, 191, 75)]
+    #[case(PercentageScale::Byte, 255, 100)]
+    fn progress_percent(#[case] scale: PercentageScale, #[case] raw: u8, #[case] percent: u8) {
+        let response = MonitorV2Response {
+            percentage: raw,
+            scale,
+            ..Default::default()
+        };
+        assert_eq!(response.progress_percent(), percent);
+        assert_eq!(response.percentage, raw);
+    }
+
+    #[rstest]
+    fn percentage_round_trip(
+        #[values(PercentageScale::Percent, PercentageScale::Byte)] scale: PercentageScale,
+    ) {
+        for percent in 0..=100 {
+            assert_eq!(scale.to_percent(scale.from_percent(percent)), percent);
+        }
+    }
 }
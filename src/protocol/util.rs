@@ -0,0 +1,52 @@
+//! Helpers for displaying and parsing raw packet bytes, for tools that work with the protocol directly.
+
+/// Formats bytes as contiguous lowercase hex, ie: `0d0575f0c4d5`. This is the format used by recordings and
+/// `--trace-file`, and is the inverse of [`from_hex_str`].
+pub fn stringify(buffer: &[u8]) -> String {
+    buffer
+        .iter()
+        .map(|n| format!("{:02x}", n))
+        .collect::<String>()
+}
+
+/// Dumps a packet to a readable hex form.
+pub fn hexdump(buffer: &[u8]) -> String {
+    let maybe_space = |i| if i > 0 && i % 8 == 0 { " " } else { "" };
+    let s1: String = buffer
+        .iter()
+        .enumerate()
+        .map(|(i, b)| format!("{}{:02x}", maybe_space(i), b))
+        .collect::<String>();
+    let s2: String = buffer
+        .iter()
+        .enumerate()
+        .map(|(_i, b)| {
+            if *b >= 32 && *b < 127 {
+                *b as char
+            } else {
+                '.'
+            }
+        })
+        .collect::<String>();
+    format!("|{}| |{}|", s1, s2)
+}
+
+/// Parses hex bytes, ignoring any whitespace between them (ie: `0d 05 75 f0`).
+pub fn from_hex_str(s: &str) -> Result<Vec<u8>, hex::FromHexError> {
+    hex::decode(s.split_whitespace().collect::<String>())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let bytes = from_hex_str("0d 05 75f0\tc4 d5").expect("Invalid hex");
+        assert_eq!(bytes, [0x0d, 0x05, 0x75, 0xf0, 0xc4, 0xd5]);
+        assert_eq!(stringify(&bytes), "0d0575f0c4d5");
+        assert_eq!(hexdump(&bytes), "|0d0575f0c4d5| |..u...|");
+        assert!(from_hex_str("0d 5").is_err());
+        assert!(from_hex_str("zz").is_err());
+    }
+}
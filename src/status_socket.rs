@@ -0,0 +1,165 @@
+//! Newline-delimited JSON status output over a Unix domain socket, for lightweight local integrations.
+
+use crate::ecam::{EcamError, EcamStatus};
+use crate::prelude::*;
+
+use serde::Serialize;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tokio::io::AsyncWriteExt;
+use tokio::net::UnixListener;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// A single status event, written as one line of JSON.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct StatusEvent {
+    /// Milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    pub status: EcamStatus,
+}
+
+impl StatusEvent {
+    pub fn new(status: EcamStatus) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        StatusEvent { timestamp, status }
+    }
+
+    /// Serializes this event as a single line of JSON, including the trailing newline.
+    pub fn to_json_line(&self) -> String {
+        serde_json::to_string(self).expect("Failed to serialize status") + "\n"
+    }
+}
+
+/// Broadcasts [`StatusEvent`]s to every reader connected to a Unix domain socket. Readers may connect and disconnect
+/// at any time, and newly-connected readers immediately receive the last status sent.
+pub struct StatusSocket {
+    path: PathBuf,
+    tx: broadcast::Sender<String>,
+    last: Arc<std::sync::Mutex<Option<(EcamStatus, String)>>>,
+    accept: tokio::task::JoinHandle<()>,
+}
+
+impl StatusSocket {
+    /// Binds a socket at the given path, replacing a stale socket if one exists.
+    pub fn bind<P: AsRef<Path>>(path: P) -> Result<Self, EcamError> {
+        let path = path.as_ref().to_owned();
+        if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+            if metadata.file_type().is_socket() {
+                std::fs::remove_file(&path)?;
+            }
+        }
+        let listener = UnixListener::bind(&path)?;
+        let (tx, _) = broadcast::channel::<String>(100);
+        let last: Arc<std::sync::Mutex<Option<(EcamStatus, String)>>> = Default::default();
+
+        let (tx2, last2) = (tx.clone(), last.clone());
+        let accept = tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut rx = tx2.subscribe();
+                let initial = last2.lock().ok().and_then(|x| x.clone());
+                tokio::spawn(async move {
+                    if let Some((_, line)) = initial {
+                        if stream.write_all(line.as_bytes()).await.is_err() {
+                            return;
+                        }
+                    }
+                    loop {
+                        match rx.recv().await {
+                            Ok(line) => {
+                                // The reader went away, so just drop it
+                                if stream.write_all(line.as_bytes()).await.is_err() {
+                                    break;
+                                }
+                            }
+                            Err(RecvError::Lagged(n)) => {
+                                warning!("Status socket reader lagged by {} events", n);
+                            }
+                            Err(RecvError::Closed) => break,
+                        }
+                    }
+                });
+            }
+        });
+
+        Ok(StatusSocket {
+            path,
+            tx,
+            last,
+            accept,
+        })
+    }
+
+    /// Sends the status to all connected readers, if it differs from the last status sent.
+    pub fn send(&self, status: EcamStatus) {
+        if let Ok(mut last) = self.last.lock() {
+            if matches!(&*last, Some((s, _)) if *s == status) {
+                return;
+            }
+            let line = StatusEvent::new(status).to_json_line();
+            *last = Some((status, line.clone()));
+            let _ = self.tx.send(line);
+        }
+    }
+}
+
+impl Drop for StatusSocket {
+    fn drop(&mut self) {
+        self.accept.abort();
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+    use tokio::net::UnixStream;
+
+    fn read_status(line: &str) -> serde_json::Value {
+        let value: serde_json::Value = serde_json::from_str(line).expect("Invalid JSON");
+        value["status"].clone()
+    }
+
+    #[test]
+    fn serialize_status_event() {
+        let event = StatusEvent {
+            timestamp: 1,
+            status: EcamStatus::Busy(50),
+        };
+        assert_eq!(
+            event.to_json_line(),
+            "{\"timestamp\":1,\"status\":{\"Busy\":50}}\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn multiple_readers() -> Result<(), EcamError> {
+        let path = std::env::temp_dir().join(format!("longshot-test-{}.sock", std::process::id()));
+        let socket = StatusSocket::bind(&path)?;
+        socket.send(EcamStatus::StandBy);
+
+        let mut a = BufReader::new(UnixStream::connect(&path).await?).lines();
+        let b = UnixStream::connect(&path).await?;
+        assert_eq!(read_status(&a.next_line().await?.unwrap()), "StandBy");
+
+        // Disconnecting one reader doesn't affect the other
+        drop(b);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        socket.send(EcamStatus::Ready);
+        socket.send(EcamStatus::Ready);
+        socket.send(EcamStatus::Busy(10));
+        assert_eq!(read_status(&a.next_line().await?.unwrap()), "Ready");
+        assert_eq!(
+            read_status(&a.next_line().await?.unwrap()),
+            serde_json::json!({"Busy": 10})
+        );
+
+        drop(socket);
+        assert!(!path.exists());
+        Ok(())
+    }
+}
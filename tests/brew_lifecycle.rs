@@ -0,0 +1,88 @@
+//! Replays a scripted brew against the simulator and checks the exact conversation with the machine.
+
+use futures::StreamExt;
+use longshot::ecam::{get_ecam_simulator_scripted, Ecam, EcamOutput, EcamStatus, SimulatorStep};
+use longshot::operations::{brew, validate_brew, IngredientCheckMode, DEFAULT_RECIPE_VARIANT};
+use longshot::protocol::*;
+
+#[tokio::test(start_paused = true)]
+async fn brew_lifecycle() -> Result<(), Box<dyn std::error::Error>> {
+    use EcamMachineState::*;
+    let (driver, log) = get_ecam_simulator_scripted(vec![
+        SimulatorStep::status(ReadyOrDispensing, 0, 0, 1),
+        SimulatorStep::WaitForRequest(EcamRequestId::BeverageDispensingMode),
+        SimulatorStep::status(ReadyOrDispensing, 1, 25, 2),
+        SimulatorStep::status(ReadyOrDispensing, 1, 50, 2),
+        SimulatorStep::status(ReadyOrDispensing, 1, 75, 2),
+        SimulatorStep::status(ReadyOrDispensing, 0, 0, 2),
+    ])
+    .await?;
+    let ecam = Ecam::new(Box::new(driver), false).await;
+
+    // Collect every status the machine reports until the script completes
+    let mut tap = ecam.packet_tap().await?;
+    let statuses = tokio::spawn(async move {
+        let mut statuses = vec![];
+        while let Some(output) = tap.next().await {
+            match output {
+                EcamOutput::Packet(EcamPacket {
+                    representation: Some(Response::MonitorV2(response)),
+                    ..
+                }) => {
+                    let status = EcamStatus::extract(&response);
+                    if statuses.last() != Some(&status) {
+                        statuses.push(status);
+                    }
+                }
+                EcamOutput::Done => break,
+                _ => {}
+            }
+        }
+        statuses
+    });
+
+    let beverage = EcamBeverageId::EspressoCoffee;
+    let recipe = validate_brew(
+        ecam.clone(),
+        beverage,
+        DEFAULT_RECIPE_VARIANT,
+        vec![],
+        IngredientCheckMode::AllowDefaults,
+    )
+    .await?;
+    brew(ecam.clone(), false, beverage, recipe).await?;
+
+    let requests: Vec<_> = log
+        .requests()
+        .into_iter()
+        .filter(|r| r[0] != EcamRequestId::MonitorV2 as u8)
+        .collect();
+    assert_eq!(
+        requests,
+        vec![
+            Request::RecipeMinMaxSync(beverage.into()).encode(),
+            Request::RecipeQuantityRead(1, beverage.into()).encode(),
+            Request::BeverageDispensingMode(
+                beverage.into(),
+                EcamOperationTrigger::Start.into(),
+                vec![
+                    RecipeInfo::new(EcamIngredients::Coffee, 40),
+                    RecipeInfo::new(EcamIngredients::Taste, EcamBeverageTaste::Normal as u16),
+                ],
+                EcamBeverageTasteType::Prepare.into(),
+            )
+            .encode(),
+        ]
+    );
+    assert_eq!(
+        statuses.await?,
+        vec![
+            EcamStatus::Ready,
+            EcamStatus::Busy(25),
+            EcamStatus::Busy(50),
+            EcamStatus::Busy(75),
+            EcamStatus::Ready,
+        ]
+    );
+    Ok(())
+}